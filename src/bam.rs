@@ -0,0 +1,634 @@
+use crate::{
+    error::CrussmapError,
+    fasta::revcomp,
+    interval::{find_in_lapper, get_lapper_hashmap_with_sizes, LapperHashMap, Region},
+    parser::Strand,
+    sam::{CigarOp, SamHeader, SamReader, SamRecord, SamWriter},
+    utils::{get_file_reader, get_output_writer},
+};
+use log::{info, warn};
+use std::{collections::HashMap, fmt, io};
+
+/// Why an alignment could not be lifted to the new assembly, written in the `XU:Z` tag.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BamUnmapReason {
+    /// aligned part of the read does not overlap any chain block
+    Unmap,
+    /// blocks hit by the read are on different chromosomes/strands or out of order
+    MultipleChains,
+    /// no aligned base of the read survives in the new assembly
+    NoAlignedBase,
+}
+
+impl fmt::Display for BamUnmapReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            BamUnmapReason::Unmap => "Unmap",
+            BamUnmapReason::MultipleChains => "MultipleChains",
+            BamUnmapReason::NoAlignedBase => "NoAlignedBase",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// Gapless piece of chain alignment: `len` old reference bases from `old_start`
+/// map to `new_start..` in new reference coordinates oriented along the read,
+/// i.e. counted from the end of the sequence for negative strand blocks.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    old_start: usize,
+    new_start: usize,
+    len: usize,
+}
+
+/// Split the old reference range `[start, end)` into pieces with their oriented
+/// new position, `None` for bases which fall in a chain gap.
+fn split_range(segments: &[Segment], start: usize, end: usize) -> Vec<(usize, Option<usize>)> {
+    let mut pieces = Vec::new();
+    let mut cursor = start;
+    for seg in segments {
+        let seg_end = seg.old_start + seg.len;
+        if seg_end <= cursor || seg.old_start >= end {
+            continue;
+        }
+        if seg.old_start > cursor {
+            pieces.push((seg.old_start - cursor, None));
+            cursor = seg.old_start;
+        }
+        let piece_end = seg_end.min(end);
+        pieces.push((
+            piece_end - cursor,
+            Some(seg.new_start + cursor - seg.old_start),
+        ));
+        cursor = piece_end;
+    }
+    if cursor < end {
+        pieces.push((end - cursor, None));
+    }
+    pieces
+}
+
+fn push_op(ops: &mut Vec<CigarOp>, op: u8, len: usize) {
+    if len == 0 {
+        return;
+    }
+    match ops.last_mut() {
+        Some(last) if last.op == op => last.len += len as u32,
+        _ => ops.push(CigarOp {
+            len: len as u32,
+            op,
+        }),
+    }
+}
+
+/// Compose the read-to-old CIGAR with the chain segments, returning the
+/// read-to-new CIGAR (still oriented along the old read) and its oriented start.
+fn compose_cigar(
+    cigar: &[CigarOp],
+    old_pos: usize,
+    segments: &[Segment],
+) -> Option<(Vec<CigarOp>, usize)> {
+    let mut ops: Vec<CigarOp> = Vec::new();
+    let mut ref_cursor = old_pos;
+    let mut first_new: Option<usize> = None;
+    let mut next_new: Option<usize> = None;
+    for op in cigar {
+        let len = op.len as usize;
+        match op.op {
+            b'S' | b'H' | b'I' => push_op(&mut ops, op.op, len),
+            b'M' | b'=' | b'X' | b'D' | b'N' => {
+                for (piece_len, new_start) in split_range(segments, ref_cursor, ref_cursor + len) {
+                    match new_start {
+                        Some(p) => {
+                            match next_new {
+                                // bases only present in the new assembly
+                                Some(e) if p > e => push_op(&mut ops, b'D', p - e),
+                                None if !op.consumes_read() => continue,
+                                None => first_new = Some(p),
+                                _ => {}
+                            }
+                            push_op(&mut ops, op.op, piece_len);
+                            next_new = Some(p + piece_len);
+                        }
+                        None => {
+                            // bases deleted in the new assembly
+                            if op.consumes_read() {
+                                push_op(&mut ops, b'I', piece_len);
+                            }
+                        }
+                    }
+                }
+                ref_cursor += len;
+            }
+            _ => {}
+        }
+    }
+    let first_new = first_new?;
+    // trailing deletions and insertions next to the clips become clipped bases
+    while matches!(ops.last(), Some(x) if x.op == b'D' || x.op == b'N') {
+        ops.pop();
+    }
+    for op in ops.iter_mut() {
+        match op.op {
+            b'I' => op.op = b'S',
+            b'S' | b'H' => {}
+            _ => break,
+        }
+    }
+    for op in ops.iter_mut().rev() {
+        match op.op {
+            b'I' => op.op = b'S',
+            b'S' | b'H' => {}
+            _ => break,
+        }
+    }
+    let mut merged: Vec<CigarOp> = Vec::with_capacity(ops.len());
+    for op in ops {
+        push_op(&mut merged, op.op, op.len as usize);
+    }
+    Some((merged, first_new))
+}
+
+/// Lift a single base, returning the new chromosome, 0-based position and block strand.
+fn lift_point(
+    lapper_hashmap: &LapperHashMap,
+    chrom: &String,
+    pos: usize,
+) -> Option<(String, usize, Strand)> {
+    let q_region = Region {
+        chrom,
+        start: pos,
+        end: pos + 1,
+        strand: Strand::Positive,
+    };
+    let matches = find_in_lapper(lapper_hashmap, &q_region)?;
+    let hit = matches.get(1)?;
+    Some((hit.chrom.to_string(), hit.start, hit.strand))
+}
+
+/// Read name and first/last segment bits of a primary alignment of a pair, its
+/// mate has the same name and the other segment bit.
+type MateKey = (String, u16);
+
+fn mate_key(record: &SamRecord) -> Option<MateKey> {
+    match record.flag & 0x1 != 0 && record.flag & 0x900 == 0 {
+        true => Some((record.qname.clone(), record.flag & 0xc0)),
+        false => None,
+    }
+}
+
+/// Key of the primary alignment of the mate of `record`, which may be secondary.
+fn mate_of(record: &SamRecord) -> Option<MateKey> {
+    match record.flag & 0x1 != 0 {
+        true => Some((record.qname.clone(), (record.flag & 0xc0) ^ 0xc0)),
+        false => None,
+    }
+}
+
+/// Where a primary alignment was lifted to, as seen by its mate.
+#[derive(Debug, Clone, PartialEq)]
+struct MatePlacement {
+    rname: String,
+    /// 1-based
+    pos: usize,
+    /// 0-based exclusive end, `None` for a placed unmapped read
+    end: Option<usize>,
+    reverse: bool,
+}
+
+impl MatePlacement {
+    fn of(record: &SamRecord) -> Option<Self> {
+        if record.rname == "*" || record.pos == 0 {
+            return None;
+        }
+        Some(MatePlacement {
+            rname: record.rname.clone(),
+            pos: record.pos,
+            end: match record.is_unmapped() {
+                true => None,
+                false => Some(record.pos - 1 + record.ref_len().max(1)),
+            },
+            reverse: record.flag & 0x10 != 0,
+        })
+    }
+}
+
+/// Point RNEXT/PNEXT, the mate reverse flag and TLEN of `record` at `mate`, the
+/// lifted mate alignment, `None` if the mate could not be lifted.
+fn set_mate(record: &mut SamRecord, mate: Option<&MatePlacement>) {
+    let mate = match mate {
+        Some(mate) => mate,
+        None => {
+            record.rnext = "*".to_string();
+            record.pnext = 0;
+            record.tlen = 0;
+            return;
+        }
+    };
+    let same_chrom = mate.rname == record.rname;
+    record.rnext = match same_chrom {
+        true => "=".to_string(),
+        false => mate.rname.clone(),
+    };
+    record.pnext = mate.pos;
+    match mate.reverse {
+        true => record.flag |= 0x20,
+        false => record.flag &= !0x20,
+    }
+    record.tlen = match (MatePlacement::of(record), same_chrom) {
+        (Some(this), true) => match (this.end, mate.end) {
+            (Some(this_end), Some(mate_end)) => {
+                let len = (this_end.max(mate_end) - this.pos.min(mate.pos) + 1) as i64;
+                // the leftmost segment gets the positive sign
+                match this.pos < mate.pos || (this.pos == mate.pos && record.flag & 0x40 != 0) {
+                    true => len,
+                    false => -len,
+                }
+            }
+            _ => 0,
+        },
+        _ => 0,
+    };
+}
+
+/// Mate position of a record whose mate is not in the input, lifted as a single base.
+fn lift_mate_point(record: &mut SamRecord, old_rname: &str, lapper_hashmap: &LapperHashMap) {
+    if record.rnext == "*" || record.pnext == 0 {
+        return;
+    }
+    let mate_chrom = match record.rnext.as_str() {
+        "=" => old_rname.to_string(),
+        x => x.to_string(),
+    };
+    let mate =
+        lift_point(lapper_hashmap, &mate_chrom, record.pnext - 1).map(|(rname, pos, strand)| {
+            MatePlacement {
+                rname,
+                pos: pos + 1,
+                end: None,
+                reverse: (record.flag & 0x20 != 0) != (strand == Strand::Negative),
+            }
+        });
+    set_mate(record, mate.as_ref());
+}
+
+/// Lift one alignment, RNEXT/PNEXT/TLEN are left to [`set_mate`].
+fn lift_sam_record(
+    record: &SamRecord,
+    lapper_hashmap: &LapperHashMap,
+    query_sizes: &HashMap<String, usize>,
+) -> Result<SamRecord, BamUnmapReason> {
+    let mut lifted = record.clone();
+    if record.is_unmapped() || record.cigar.is_empty() || record.pos == 0 {
+        // placed unmapped reads follow their mate, a RNAME without POS is dropped
+        if record.rname != "*" {
+            let placed = match record.pos {
+                0 => None,
+                pos => lift_point(lapper_hashmap, &record.rname, pos - 1),
+            };
+            match placed {
+                Some((chrom, pos, _)) => {
+                    lifted.rname = chrom;
+                    lifted.pos = pos + 1;
+                }
+                None => {
+                    lifted.rname = "*".to_string();
+                    lifted.pos = 0;
+                }
+            }
+        }
+        return Ok(lifted);
+    }
+    let old_start = record.pos - 1;
+    let old_end = old_start + record.ref_len();
+    let q_region = Region {
+        chrom: &record.rname,
+        start: old_start,
+        end: old_end,
+        strand: Strand::Positive,
+    };
+    let matches = match find_in_lapper(lapper_hashmap, &q_region) {
+        Some(matches) => matches,
+        None => return Err(BamUnmapReason::Unmap),
+    };
+    let new_chrom = matches[1].chrom;
+    let strand = matches[1].strand;
+    let new_size = *query_sizes.get(new_chrom).unwrap();
+    let mut segments: Vec<Segment> = Vec::new();
+    for pair in matches.chunks(2) {
+        let (old, new) = (&pair[0], &pair[1]);
+        if new.chrom != new_chrom || new.strand != strand {
+            return Err(BamUnmapReason::MultipleChains);
+        }
+        segments.push(Segment {
+            old_start: old.start,
+            new_start: match strand {
+                Strand::Positive => new.start,
+                Strand::Negative => new_size - new.end,
+            },
+            len: old.end - old.start,
+        });
+    }
+    segments.sort_by_key(|x| x.old_start);
+    for w in segments.windows(2) {
+        if w[0].old_start + w[0].len > w[1].old_start || w[0].new_start + w[0].len > w[1].new_start
+        {
+            return Err(BamUnmapReason::MultipleChains);
+        }
+    }
+    let (mut cigar, oriented_start) = match compose_cigar(&record.cigar, old_start, &segments) {
+        Some(x) => x,
+        None => return Err(BamUnmapReason::NoAlignedBase),
+    };
+    let new_len: usize = cigar
+        .iter()
+        .filter(|x| x.consumes_ref())
+        .map(|x| x.len as usize)
+        .sum();
+    let new_start = match strand {
+        Strand::Positive => oriented_start,
+        Strand::Negative => {
+            cigar.reverse();
+            lifted.flag ^= 0x10;
+            if lifted.seq != "*" {
+                lifted.seq = revcomp(&lifted.seq);
+            }
+            if lifted.qual != "*" {
+                lifted.qual = lifted.qual.chars().rev().collect();
+            }
+            new_size - oriented_start - new_len
+        }
+    };
+    lifted.rname = new_chrom.to_string();
+    lifted.pos = new_start + 1;
+    lifted.cigar = cigar;
+    // both depend on the old reference sequence
+    lifted.remove_tag("MD");
+    lifted.remove_tag("NM");
+    Ok(lifted)
+}
+
+fn lifted_header(header: &SamHeader, query_sizes: &[(String, usize)]) -> SamHeader {
+    let mut lines: Vec<String> = header
+        .lines
+        .iter()
+        .map(|x| match x.starts_with("@HD") {
+            true => x.replace("SO:coordinate", "SO:unsorted"),
+            false => x.to_string(),
+        })
+        .collect();
+    if !lines
+        .iter()
+        .any(|x| x.starts_with("@PG") && x.contains("\tID:crussmap"))
+    {
+        lines.push(format!(
+            "@PG\tID:crussmap\tPN:crussmap\tVN:{}",
+            env!("CARGO_PKG_VERSION")
+        ));
+    }
+    SamHeader {
+        lines,
+        refs: query_sizes.to_vec(),
+    }
+}
+
+fn is_bam_path(path: &Option<String>) -> bool {
+    matches!(path, Some(x) if x.ends_with(".bam"))
+}
+
+pub fn cross_bam(
+    bam_file: &String,
+    input_chain: &Option<String>,
+    output_bam: &Option<String>,
+    unmaped_bam: &Option<String>,
+    rewrite: bool,
+) -> Result<(), CrussmapError> {
+    let (lapper_hashmap, query_sizes) = get_lapper_hashmap_with_sizes(input_chain)?;
+    info!("get lapper hashmap done!");
+    let query_size_map: HashMap<String, usize> = query_sizes.iter().cloned().collect();
+    // first pass: where every primary alignment of a pair goes, for its mate
+    let mut mates: HashMap<MateKey, Option<MatePlacement>> = HashMap::new();
+    let (reader, _) = SamReader::new(get_file_reader(bam_file)?)?;
+    for sam_rcd in reader.flatten() {
+        if let Some(key) = mate_key(&sam_rcd) {
+            let lifted = lift_sam_record(&sam_rcd, &lapper_hashmap, &query_size_map);
+            mates.insert(key, lifted.ok().and_then(|x| MatePlacement::of(&x)));
+        }
+    }
+    info!("lift {} paired alignments for their mates", mates.len());
+    let (mut reader, header) = SamReader::new(get_file_reader(bam_file)?)?;
+    let (output_file, stdout_mode) = get_output_writer(output_bam, rewrite)?;
+    let mut output_file = SamWriter::new(output_file, is_bam_path(output_bam));
+    let mut unmaped_file = match stdout_mode {
+        true => SamWriter::new(Box::new(io::stdout()), false),
+        false => SamWriter::new(
+            get_output_writer(unmaped_bam, rewrite)?.0,
+            is_bam_path(unmaped_bam),
+        ),
+    };
+    output_file.write_header(&lifted_header(&header, &query_sizes))?;
+    if !stdout_mode {
+        unmaped_file.write_header(&header)?;
+    }
+
+    for sam_line in reader.by_ref() {
+        let sam_rcd = match sam_line {
+            Ok(sam_rcd) => sam_rcd,
+            Err(e) => {
+                warn!("SKIP: Error parsing SAM/BAM record: {}", e);
+                continue;
+            }
+        };
+        match lift_sam_record(&sam_rcd, &lapper_hashmap, &query_size_map) {
+            Ok(mut lifted) => {
+                match mate_of(&sam_rcd).and_then(|key| mates.get(&key)) {
+                    Some(mate) => set_mate(&mut lifted, mate.as_ref()),
+                    None => lift_mate_point(&mut lifted, &sam_rcd.rname, &lapper_hashmap),
+                }
+                output_file.write_record(&lifted)?
+            }
+            Err(reason) => {
+                let mut unmaped = sam_rcd;
+                unmaped.set_tag("XU", 'Z', &reason.to_string());
+                unmaped_file.write_record(&unmaped)?;
+            }
+        }
+    }
+    output_file.finish()?;
+    unmaped_file.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        interval::lapper_hashmap_from_str,
+        sam::{cigar_to_string, parse_cigar},
+    };
+
+    /// chrT [0, 310) to chrQ with the 10 bases after chrT:100 deleted.
+    const GAP_CHAIN: &str = "chain 100 chrT 1000 + 0 310 chrQ 1000 + 0 300 1\n100\t10\t0\n200\n\n";
+
+    fn lift_pair(lines: [&str; 2]) -> Vec<String> {
+        let (lapper_hashmap, query_sizes) = lapper_hashmap_from_str(GAP_CHAIN).unwrap();
+        let query_sizes: HashMap<String, usize> = query_sizes.into_iter().collect();
+        let records: Vec<SamRecord> = lines
+            .iter()
+            .map(|x| SamRecord::from_line(x).unwrap())
+            .collect();
+        let lifted: Vec<SamRecord> = records
+            .iter()
+            .map(|x| lift_sam_record(x, &lapper_hashmap, &query_sizes).unwrap())
+            .collect();
+        let mates: HashMap<MateKey, Option<MatePlacement>> = lifted
+            .iter()
+            .map(|x| (mate_key(x).unwrap(), MatePlacement::of(x)))
+            .collect();
+        lifted
+            .into_iter()
+            .map(|mut x| {
+                let mate = mates.get(&mate_of(&x).unwrap()).unwrap();
+                set_mate(&mut x, mate.as_ref());
+                x.to_string()
+            })
+            .collect()
+    }
+
+    /// chrT [0, 300) to the negative strand of chrR with one base inserted after 150.
+    const NEGATIVE_CHAIN: &str =
+        "chain 90 chrT 1000 + 0 300 chrR 900 - 0 301 2\n150\t0\t1\n150\n\n";
+
+    fn lift_line(chain: &str, line: &str) -> Result<String, BamUnmapReason> {
+        let (lapper_hashmap, query_sizes) = lapper_hashmap_from_str(chain).unwrap();
+        let query_sizes: HashMap<String, usize> = query_sizes.into_iter().collect();
+        let record = SamRecord::from_line(line).unwrap();
+        lift_sam_record(&record, &lapper_hashmap, &query_sizes).map(|x| x.to_string())
+    }
+
+    #[test]
+    fn split_range_over_a_gap() {
+        let segments = [
+            Segment {
+                old_start: 0,
+                new_start: 0,
+                len: 100,
+            },
+            Segment {
+                old_start: 110,
+                new_start: 100,
+                len: 200,
+            },
+        ];
+        assert_eq!(
+            split_range(&segments, 90, 120),
+            [(10, Some(90)), (10, None), (10, Some(100))]
+        );
+        assert_eq!(
+            split_range(&segments, 300, 320),
+            [(10, Some(290)), (10, None)]
+        );
+        let cigar = parse_cigar("2S10M5D15M").unwrap();
+        let (cigar, start) = compose_cigar(&cigar, 90, &segments).unwrap();
+        // the deletion falls in the chain gap, the next 5 aligned bases too
+        assert_eq!(
+            (cigar_to_string(&cigar).as_str(), start),
+            ("2S10M5I10M", 90)
+        );
+        assert!(compose_cigar(&parse_cigar("5M").unwrap(), 102, &segments).is_none());
+    }
+
+    #[test]
+    fn positive_strand_gaps() {
+        let seq = "ACGTACGTACGTACGTACGTACGTACGTAC";
+        let qual = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcd";
+        // bases missing from the new assembly become an insertion
+        assert_eq!(
+            lift_line(
+                GAP_CHAIN,
+                &format!(
+                    "r\t0\tchrT\t91\t60\t30M\t*\t0\t0\t{}\t{}\tMD:Z:30\tNM:i:0",
+                    seq, qual
+                )
+            ),
+            Ok(format!(
+                "r\t0\tchrQ\t91\t60\t10M10I10M\t*\t0\t0\t{}\t{}",
+                seq, qual
+            ))
+        );
+        // and a clip at the end of the read
+        assert_eq!(
+            lift_line(GAP_CHAIN, "r\t0\tchrT\t91\t60\t20M\t*\t0\t0\t*\t*"),
+            Ok("r\t0\tchrQ\t91\t60\t10M10S\t*\t0\t0\t*\t*".to_string())
+        );
+        assert_eq!(
+            lift_line(GAP_CHAIN, "r\t0\tchrT\t96\t60\t5N5M\t*\t0\t0\t*\t*"),
+            Err(BamUnmapReason::NoAlignedBase)
+        );
+        assert_eq!(
+            lift_line(GAP_CHAIN, "r\t0\tchrT\t103\t60\t5M\t*\t0\t0\t*\t*"),
+            Err(BamUnmapReason::Unmap)
+        );
+    }
+
+    #[test]
+    fn negative_strand_gaps() {
+        // bases only in the new assembly become a deletion, the read is turned around
+        assert_eq!(
+            lift_line(
+                NEGATIVE_CHAIN,
+                "r\t0\tchrT\t141\t60\t3S20M\t*\t0\t0\tGGGACGTACGTAAAAACCCCCTT\tABCDEFGHIJKLMNOPQRSTUVW"
+            ),
+            Ok(
+                "r\t16\tchrR\t740\t60\t10M1D10M3S\t*\t0\t0\tAAGGGGGTTTTTACGTACGTCCC\tWVUTSRQPONMLKJIHGFEDCBA"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            lift_line(NEGATIVE_CHAIN, "r\t16\tchrT\t1\t60\t4M\t*\t0\t0\tACGG\t*"),
+            Ok("r\t0\tchrR\t897\t60\t4M\t*\t0\t0\tCCGT\t*".to_string())
+        );
+    }
+
+    #[test]
+    fn unmapped_reads_without_position() {
+        assert_eq!(
+            lift_line(GAP_CHAIN, "r\t4\tchrT\t0\t0\t*\t*\t0\t0\t*\t*"),
+            Ok("r\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*".to_string())
+        );
+        assert_eq!(
+            lift_line(GAP_CHAIN, "r\t0\tchrT\t0\t0\t5M\t*\t0\t0\t*\t*"),
+            Ok("r\t0\t*\t0\t0\t5M\t*\t0\t0\t*\t*".to_string())
+        );
+    }
+
+    #[test]
+    fn mate_starting_in_a_gap() {
+        let lifted = lift_pair([
+            "p1\t99\tchrT\t51\t60\t20M\t=\t101\t80\t*\t*",
+            "p1\t147\tchrT\t101\t60\t30M\t=\t51\t-80\t*\t*",
+        ]);
+        assert_eq!(
+            lifted,
+            [
+                "p1\t99\tchrQ\t51\t60\t20M\t=\t101\t70\t*\t*",
+                "p1\t147\tchrQ\t101\t60\t10S20M\t=\t51\t-70\t*\t*",
+            ]
+        );
+    }
+
+    #[test]
+    fn unmapped_mate_follows_its_pair() {
+        let lifted = lift_pair([
+            "p1\t73\tchrT\t201\t60\t10M\t=\t201\t0\t*\t*",
+            "p1\t133\tchrT\t201\t0\t*\t=\t201\t0\t*\t*",
+        ]);
+        assert_eq!(
+            lifted,
+            [
+                "p1\t73\tchrQ\t191\t60\t10M\t=\t191\t0\t*\t*",
+                "p1\t133\tchrQ\t191\t0\t*\t=\t191\t0\t*\t*",
+            ]
+        );
+    }
+}
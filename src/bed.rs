@@ -1,15 +1,163 @@
 use crate::{
-    interval::{find_in_lapper, get_lapper_hashmap, Region},
+    error::{CrussmapError, Result},
+    interval::{get_chain_spans, get_lapper_hashmap, LapperHashMap, Region},
+    liftover::{map_pieces, merge_pieces, MappedPiece, MultiMapPolicy},
     parser::Strand,
-    utils::{get_file_reader, get_output_writer},
+    tabix::{OutputOptions, TabixConf},
+    utils::{get_compressed_output_writer, get_file_reader, get_output_writer},
 };
-use csv::{DeserializeRecordsIter, ReaderBuilder};
+use csv::{ByteRecordsIter, ReaderBuilder};
 use log::{info, warn};
+use rust_lapper::Lapper;
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
     fmt,
     io::{self, Write},
 };
 
+/// Why a BED record was not converted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BedUnmapReason {
+    /// chromosome of the record is not in the chain file
+    ChromAbsent,
+    /// record does not overlap any chain
+    NoOverlap,
+    /// record falls between two blocks of a chain, deleted in the new assembly
+    ChainGap,
+    /// fraction of the record bases covered by chain blocks, below `--min-match`
+    MinMatch(f64),
+    /// mapped and total BED12 blocks, ratio below `--min-blocks`
+    MinBlocks(usize, usize),
+    /// number of chains the pieces come from, with `--multi-map unique-only`
+    MultiChain(usize),
+    /// start larger than end, or a line that is not a BED record
+    Malformed,
+}
+
+impl BedUnmapReason {
+    /// Reason without its details, used as key of [`BedLiftSummary`].
+    pub fn code(&self) -> &'static str {
+        match self {
+            BedUnmapReason::ChromAbsent => "ChromAbsent",
+            BedUnmapReason::NoOverlap => "NoOverlap",
+            BedUnmapReason::ChainGap => "ChainGap",
+            BedUnmapReason::MinMatch(_) => "MinMatch",
+            BedUnmapReason::MinBlocks(_, _) => "MinBlocks",
+            BedUnmapReason::MultiChain(_) => "MultiChain",
+            BedUnmapReason::Malformed => "Malformed",
+        }
+    }
+}
+
+impl fmt::Display for BedUnmapReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BedUnmapReason::MinMatch(ratio) => write!(f, "MinMatch:{:.3}", ratio),
+            BedUnmapReason::MinBlocks(mapped, total) => {
+                write!(f, "MinBlocks:{}/{}", mapped, total)
+            }
+            BedUnmapReason::MultiChain(chains) => write!(f, "MultiChain:{}", chains),
+            reason => write!(f, "{}", reason.code()),
+        }
+    }
+}
+
+/// Counts of converted and unmapped BED records, logged at the end of [`cross_bed`]
+/// and written to the `--report` file as JSON.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BedLiftSummary {
+    pub records: usize,
+    pub mapped: usize,
+    /// unmapped records per [`BedUnmapReason::code`]
+    pub unmapped: BTreeMap<&'static str, usize>,
+}
+
+impl BedLiftSummary {
+    fn add_unmapped(&mut self, reason: BedUnmapReason) {
+        *self.unmapped.entry(reason.code()).or_insert(0) += 1;
+    }
+}
+
+/// When a BED record counts as converted, after the options of UCSC liftOver. The
+/// default accepts any overlap with a chain block.
+#[derive(Debug, Clone, Default)]
+pub struct BedLiftOptions {
+    /// smallest fraction of the record bases covered by chain blocks
+    pub min_match: f64,
+    /// smallest fraction of BED12 blocks (exons) overlapping a chain block, records
+    /// without blocks count as one
+    pub min_blocks: f64,
+    /// which chain to keep when a record maps through several
+    pub multi_map: MultiMapPolicy,
+    /// write pieces from the same chain, chromosome and strand as one interval
+    pub merge_split: bool,
+    /// largest gap between two pieces that are still merged, unlimited if `None`
+    pub max_gap: Option<usize>,
+    /// JSON file for the [`BedLiftSummary`]
+    pub report: Option<String>,
+}
+
+impl BedLiftOptions {
+    /// Pieces to write for `record`, at least one, or why it is left unmapped.
+    fn apply(
+        &self,
+        record: &BedRecord,
+        pieces: Vec<MappedPiece>,
+    ) -> std::result::Result<Vec<MappedPiece>, BedUnmapReason> {
+        let chains = pieces.iter().map(|x| x.chain_id).collect::<HashSet<_>>();
+        let pieces = match self.multi_map.select(pieces) {
+            Some(pieces) => pieces,
+            None => return Err(BedUnmapReason::MultiChain(chains.len())),
+        };
+        if let Some(reason) = self.check(record, &pieces) {
+            return Err(reason);
+        }
+        Ok(match self.merge_split {
+            true => merge_pieces(pieces, self.max_gap),
+            false => pieces,
+        })
+    }
+
+    fn check(&self, record: &BedRecord, pieces: &[MappedPiece]) -> Option<BedUnmapReason> {
+        let ratio = match record.end - record.start {
+            0 => 1.0,
+            len => covered_bases(pieces) as f64 / len as f64,
+        };
+        if ratio < self.min_match {
+            return Some(BedUnmapReason::MinMatch(ratio));
+        }
+        if let Some(blocks) = record.blocks() {
+            let mapped = blocks
+                .iter()
+                .filter(|(start, end)| {
+                    pieces
+                        .iter()
+                        .any(|x| x.source.start < *end && x.source.end > *start)
+                })
+                .count();
+            if (mapped as f64) < self.min_blocks * blocks.len() as f64 {
+                return Some(BedUnmapReason::MinBlocks(mapped, blocks.len()));
+            }
+        }
+        None
+    }
+}
+
+/// Number of source bases covered by `pieces`, sorted by start; pieces from several
+/// chains may overlap.
+fn covered_bases(pieces: &[MappedPiece]) -> usize {
+    let mut covered = 0;
+    let mut cursor = 0;
+    for piece in pieces {
+        let start = piece.source.start.max(cursor);
+        if piece.source.end > start {
+            covered += piece.source.end - start;
+        }
+        cursor = cursor.max(piece.source.end);
+    }
+    covered
+}
+
 #[derive(Debug)]
 pub struct BedReader<R: io::Read> {
     inner: csv::Reader<R>,
@@ -23,6 +171,8 @@ impl<R: io::Read> BedReader<R> {
                 .delimiter(b'\t')
                 .has_headers(false)
                 .comment(Some(b'#'))
+                // column counts are checked by `BedLines`, which keeps the bad lines
+                .flexible(true)
                 .from_reader(reader),
         }
     }
@@ -30,21 +180,84 @@ impl<R: io::Read> BedReader<R> {
     /// Iterate over all records.
     pub fn bedrecords(&mut self) -> BedRecords<'_, R> {
         BedRecords {
-            inner: self.inner.deserialize(),
+            inner: self.lines(),
+        }
+    }
+
+    /// Iterate over all lines, keeping the text of those which are not valid records.
+    pub fn lines(&mut self) -> BedLines<'_, R> {
+        BedLines {
+            inner: self.inner.byte_records(),
+            fields: None,
         }
     }
 }
 
+/// A line of a BED file.
+#[derive(Debug)]
+pub enum BedLine {
+    Record(BedRecord),
+    /// line which is not a valid record, with its fields joined by tabs
+    Malformed {
+        text: String,
+        error: CrussmapError,
+    },
+}
+
+/// An iterator over the lines of a BED file, only I/O errors are returned as errors.
+pub struct BedLines<'a, R: io::Read> {
+    inner: ByteRecordsIter<'a, R>,
+    /// number of fields of the first record, every record must have as many
+    fields: Option<usize>,
+}
+
+impl<'a, R: io::Read> Iterator for BedLines<'a, R> {
+    type Item = Result<BedLine>;
+
+    fn next(&mut self) -> Option<Result<BedLine>> {
+        let record = match self.inner.next()? {
+            Ok(record) => record,
+            Err(e) => return Some(Err(CrussmapError::from(e))),
+        };
+        let line = record.position().map_or(0, |x| x.line() as usize);
+        let fields = *self.fields.get_or_insert(record.len());
+        let parsed = match record.len() == fields {
+            true => record
+                .deserialize::<BedRecord>(None)
+                .map_err(|e| e.to_string()),
+            false => Err(format!(
+                "found record with {} fields, but the previous record has {} fields",
+                record.len(),
+                fields
+            )),
+        };
+        Some(Ok(match parsed {
+            Ok(bed_rcd) => BedLine::Record(bed_rcd),
+            Err(message) => BedLine::Malformed {
+                text: record
+                    .iter()
+                    .map(String::from_utf8_lossy)
+                    .collect::<Vec<_>>()
+                    .join("\t"),
+                error: CrussmapError::BedParse { line, message },
+            },
+        }))
+    }
+}
+
 /// An iterator over the records of a BED file.
 pub struct BedRecords<'a, R: io::Read> {
-    inner: DeserializeRecordsIter<'a, R, BedRecord>,
+    inner: BedLines<'a, R>,
 }
 
 impl<'a, R: io::Read> Iterator for BedRecords<'a, R> {
-    type Item = csv::Result<BedRecord>;
+    type Item = Result<BedRecord>;
 
-    fn next(&mut self) -> Option<csv::Result<BedRecord>> {
-        self.inner.next()
+    fn next(&mut self) -> Option<Result<BedRecord>> {
+        self.inner.next().map(|x| match x? {
+            BedLine::Record(bed_rcd) => Ok(bed_rcd),
+            BedLine::Malformed { error, .. } => Err(error),
+        })
     }
 }
 
@@ -83,7 +296,31 @@ impl BedRecord {
             None
         }
     }
-    pub fn into_region(&self) -> Region {
+    /// Blocks (exons) of a BED12 record in chromosome coordinates, `None` if the
+    /// record has no valid block columns.
+    pub fn blocks(&self) -> Option<Vec<(usize, usize)>> {
+        let count: usize = self.aux(9)?.parse().ok()?;
+        let numbers = |i: usize| -> Option<Vec<usize>> {
+            self.aux(i)?
+                .trim_end_matches(',')
+                .split(',')
+                .map(|x| x.parse().ok())
+                .collect()
+        };
+        let (sizes, starts) = (numbers(10)?, numbers(11)?);
+        if count == 0 || sizes.len() != count || starts.len() != count {
+            return None;
+        }
+        Some(
+            starts
+                .iter()
+                .zip(sizes)
+                .map(|(start, size)| (self.start + start, self.start + start + size))
+                .collect(),
+        )
+    }
+
+    pub fn into_region(&self) -> Region<'_> {
         Region {
             chrom: &self.chrom,
             start: self.start,
@@ -109,72 +346,225 @@ impl fmt::Display for BedRecord {
     }
 }
 
+/// Pieces of `bed_rcd` to write, or why it is left unmapped. `chain_spans` are the
+/// spans of [`get_chain_spans`], telling chain gaps from regions outside any chain.
+fn lift_bed_record(
+    bed_rcd: &BedRecord,
+    lapper_hashmap: &LapperHashMap,
+    chain_spans: &HashMap<String, Lapper<usize, usize>>,
+    lift_options: &BedLiftOptions,
+) -> std::result::Result<Vec<MappedPiece>, BedUnmapReason> {
+    let bed_region = bed_rcd.into_region();
+    if bed_region.start > bed_region.end {
+        return Err(BedUnmapReason::Malformed);
+    }
+    let pieces = map_pieces(
+        lapper_hashmap,
+        &bed_rcd.chrom,
+        bed_region.start,
+        bed_region.end,
+        bed_region.strand,
+    );
+    if !pieces.is_empty() {
+        return lift_options.apply(bed_rcd, pieces);
+    }
+    match chain_spans.get(&bed_rcd.chrom) {
+        None => {
+            warn!("chrom:{} not found in chain file", bed_rcd.chrom);
+            Err(BedUnmapReason::ChromAbsent)
+        }
+        Some(spans) => match spans.find(bed_region.start, bed_region.end).next() {
+            Some(_) => Err(BedUnmapReason::ChainGap),
+            None => Err(BedUnmapReason::NoOverlap),
+        },
+    }
+}
+
 pub fn cross_bed(
     bed_file: &String,
     input_chain: &Option<String>,
     output_bed: &Option<String>,
     unmaped_bed: &Option<String>,
+    lift_options: &BedLiftOptions,
+    output_options: &OutputOptions,
     rewrite: bool,
-) {
-    let lapper_hashmap = get_lapper_hashmap(input_chain);
+) -> Result<()> {
+    let lapper_hashmap = get_lapper_hashmap(input_chain)?;
+    let chain_spans = get_chain_spans(&lapper_hashmap);
     info!("get lapper hashmap done!");
-    let bed_file = get_file_reader(bed_file).unwrap();
-    let (mut output_file, stdout_mode) = get_output_writer(output_bed, rewrite);
+    let bed_file = get_file_reader(bed_file)?;
+    let compression = output_options.compression(output_bed)?;
+    let (mut output_file, stdout_mode) =
+        get_compressed_output_writer(output_bed, compression, rewrite)?;
     let mut unmaped_file = match stdout_mode {
         true => Box::new(io::stdout()),
-        false => get_output_writer(unmaped_bed, rewrite).0,
+        false => get_compressed_output_writer(unmaped_bed, output_options.compress, rewrite)?.0,
+    };
+    // opened first so that an existing report fails before the conversion
+    let mut report_file = match lift_options.report {
+        Some(_) => Some(get_output_writer(&lift_options.report, rewrite)?.0),
+        None => None,
     };
 
+    let mut summary = BedLiftSummary::default();
     let mut bed_reder = BedReader::new(bed_file);
-    for bed_line in bed_reder.bedrecords() {
-        let bed_rcd = match bed_line {
-            Ok(bed_rcd) => {
+    for bed_line in bed_reder.lines() {
+        summary.records += 1;
+        let bed_rcd = match bed_line? {
+            BedLine::Record(bed_rcd) => {
                 // info!("capture a bed record!");
                 bed_rcd
             }
-            Err(e) => {
-                warn!("SKIP: Error parsing BED record: {}", e);
+            BedLine::Malformed { text, error } => {
+                warn!("SKIP: Error parsing BED record: {}", error);
+                let reason = BedUnmapReason::Malformed;
+                summary.add_unmapped(reason);
+                unmaped_file.write_all(format!("{}\tUNMAP\t{}\n", text, reason).as_bytes())?;
                 continue;
             }
         };
-        let bed_region = bed_rcd.into_region();
-        if bed_region.start > bed_region.end {
-            warn!("SKIP: Start larger end in BED record: {}", bed_rcd);
-            continue;
-        }
-        let matches = find_in_lapper(&lapper_hashmap, &bed_region);
-        match matches {
-            None => {
-                unmaped_file
-                    .write_all(format!("{}\tUNMAP\n", bed_rcd).as_bytes())
-                    .unwrap();
+        let pieces = lift_bed_record(&bed_rcd, &lapper_hashmap, &chain_spans, lift_options);
+        let pieces = match pieces {
+            Ok(pieces) => pieces,
+            Err(reason) => {
+                summary.add_unmapped(reason);
+                unmaped_file.write_all(format!("{}\tUNMAP\t{}\n", bed_rcd, reason).as_bytes())?;
                 continue;
             }
-            Some(matches) => {
-                let match_len = matches.len();
-                let mut count = 0;
-                for j in (1..match_len).step_by(2) {
-                    count += 1;
-                    let current_match_region = &matches[j];
-                    let hit_multi = match_len > 2;
-                    let hit_info = match hit_multi {
-                        true => format!("(split.{}:{})", count, &matches[j - 1]).replace('\t', "@"),
-                        false => "->".to_string(),
-                    };
-                    if stdout_mode {
-                        output_file
-                            .write_all(
-                                format!("{}\t{}\t{}\n", bed_rcd, hit_info, current_match_region)
-                                    .as_bytes(),
-                            )
-                            .unwrap();
-                    } else {
-                        output_file
-                            .write_all(format!("{}\n", current_match_region).as_bytes())
-                            .unwrap();
-                    }
-                }
+        };
+        summary.mapped += 1;
+        let hit_multi = pieces.len() > 1;
+        for (i, piece) in pieces.iter().enumerate() {
+            let hit_info = match hit_multi {
+                true => format!("(split.{}:{})", i + 1, piece.source).replace('\t', "@"),
+                false => "->".to_string(),
+            };
+            if stdout_mode {
+                output_file.write_all(
+                    format!("{}\t{}\t{}\n", bed_rcd, hit_info, piece.lifted).as_bytes(),
+                )?;
+            } else {
+                output_file.write_all(format!("{}\n", piece.lifted).as_bytes())?;
             }
         }
     }
+    // finish the compressed stream before it is read back
+    output_file.flush()?;
+    drop(output_file);
+    output_options.finish(output_bed, &TabixConf::BED, rewrite)?;
+
+    info!(
+        "converted {} of {} BED records",
+        summary.mapped, summary.records
+    );
+    for (code, count) in &summary.unmapped {
+        info!("unmapped {}: {}", code, count);
+    }
+    if let Some(report_file) = report_file.as_mut() {
+        serde_json::to_writer_pretty(&mut *report_file, &summary).map_err(io::Error::from)?;
+        writeln!(report_file)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interval::lapper_hashmap_from_str;
+
+    // chrT 0-100 and 110-300 on chain 1, 400-500 on chain 2
+    const CHAIN: &str = "chain 100 chrT 1000 + 0 300 chrQ 1000 + 0 300 1\n100 10 10\n190\n\n\
+                         chain 50 chrT 1000 + 400 500 chrZ 500 - 0 100 2\n100\n\n";
+
+    fn record(line: &str) -> BedRecord {
+        BedReader::new(line.as_bytes())
+            .bedrecords()
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn lift(
+        line: &str,
+        lift_options: &BedLiftOptions,
+    ) -> std::result::Result<usize, BedUnmapReason> {
+        let (lapper_hashmap, _) = lapper_hashmap_from_str(CHAIN).unwrap();
+        let chain_spans = get_chain_spans(&lapper_hashmap);
+        lift_bed_record(&record(line), &lapper_hashmap, &chain_spans, lift_options).map(|x| x.len())
+    }
+
+    #[test]
+    fn unmap_reasons() {
+        let default = BedLiftOptions::default();
+        assert_eq!(lift("chrT\t10\t50", &default), Ok(1));
+        assert_eq!(
+            lift("chrX\t10\t50", &default),
+            Err(BedUnmapReason::ChromAbsent)
+        );
+        assert_eq!(
+            lift("chrT\t350\t380", &default),
+            Err(BedUnmapReason::NoOverlap)
+        );
+        assert_eq!(
+            lift("chrT\t600\t700", &default),
+            Err(BedUnmapReason::NoOverlap)
+        );
+        assert_eq!(
+            lift("chrT\t100\t110", &default),
+            Err(BedUnmapReason::ChainGap)
+        );
+        assert_eq!(
+            lift("chrT\t60\t20", &default),
+            Err(BedUnmapReason::Malformed)
+        );
+    }
+
+    #[test]
+    fn threshold_reasons() {
+        let min_match = BedLiftOptions {
+            min_match: 0.95,
+            ..Default::default()
+        };
+        assert_eq!(lift("chrT\t90\t210", &BedLiftOptions::default()), Ok(2));
+        match lift("chrT\t90\t210", &min_match) {
+            Err(BedUnmapReason::MinMatch(ratio)) => assert!((ratio - 110.0 / 120.0).abs() < 1e-9),
+            x => panic!("expected MinMatch, got {:?}", x),
+        }
+        // exons 250-270, 340-360 and 430-450, the second is not covered
+        let bed12 = "chrT\t250\t450\tx\t0\t+\t250\t450\t0\t3\t20,20,20,\t0,90,180,";
+        let min_blocks = BedLiftOptions {
+            min_blocks: 0.9,
+            ..Default::default()
+        };
+        assert_eq!(
+            lift(bed12, &min_blocks),
+            Err(BedUnmapReason::MinBlocks(2, 3))
+        );
+        let unique_only = BedLiftOptions {
+            multi_map: MultiMapPolicy::UniqueOnly,
+            ..Default::default()
+        };
+        assert_eq!(
+            lift(bed12, &unique_only),
+            Err(BedUnmapReason::MultiChain(2))
+        );
+        assert_eq!(
+            BedUnmapReason::MinMatch(110.0 / 120.0).to_string(),
+            "MinMatch:0.917"
+        );
+    }
+
+    #[test]
+    fn malformed_lines_keep_their_text() {
+        let text = "chrT\t10\t50\nchrT\tab\t50\nchrT\t1\t2\t3\nchrT\t60\t70\n";
+        let mut reader = BedReader::new(text.as_bytes());
+        let lines: Vec<String> = reader
+            .lines()
+            .map(|x| match x.unwrap() {
+                BedLine::Record(bed_rcd) => bed_rcd.chrom,
+                BedLine::Malformed { text, .. } => text,
+            })
+            .collect();
+        assert_eq!(lines, ["chrT", "chrT\tab\t50", "chrT\t1\t2\t3", "chrT"]);
+    }
 }
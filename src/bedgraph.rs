@@ -0,0 +1,427 @@
+use crate::{
+    error::Result,
+    interval::{find_in_lapper, get_lapper_hashmap, LapperHashMap, Region},
+    parser::Strand,
+    utils::{get_file_reader, get_output_writer},
+};
+use clap::ValueEnum;
+use log::{info, warn};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+};
+
+/// How to combine values of source intervals landing on the same target bases.
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum MergePolicy {
+    Mean,
+    Max,
+    Sum,
+    /// drop bases covered by more than one source interval
+    Drop,
+}
+
+impl MergePolicy {
+    fn merge(&self, values: &[f64]) -> Option<f64> {
+        match (self, values.len()) {
+            (_, 0) => None,
+            (_, 1) => Some(values[0]),
+            (MergePolicy::Mean, n) => Some(values.iter().sum::<f64>() / n as f64),
+            (MergePolicy::Max, _) => values.iter().copied().reduce(f64::max),
+            (MergePolicy::Sum, _) => Some(values.iter().sum()),
+            (MergePolicy::Drop, _) => None,
+        }
+    }
+}
+
+/// One interval of a signal track, 0-based and half-open like bedGraph.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignalRecord {
+    pub chrom: String,
+    pub start: usize,
+    pub end: usize,
+    pub value: f64,
+}
+
+impl fmt::Display for SignalRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}",
+            self.chrom, self.start, self.end, self.value
+        )
+    }
+}
+
+fn parse_bedgraph_line(line: &str) -> Option<SignalRecord> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 4 {
+        return None;
+    }
+    Some(SignalRecord {
+        chrom: fields[0].to_string(),
+        start: fields[1].parse().ok()?,
+        end: fields[2].parse().ok()?,
+        value: fields[3].parse().ok()?,
+    })
+}
+
+fn is_track_line(line: &str) -> bool {
+    line.is_empty()
+        || line.starts_with('#')
+        || line.starts_with("track")
+        || line.starts_with("browser")
+}
+
+/// Current `fixedStep`/`variableStep` declaration of a WIG file.
+enum WigStep {
+    Fixed {
+        chrom: String,
+        next_start: usize,
+        step: usize,
+        span: usize,
+    },
+    Variable {
+        chrom: String,
+        span: usize,
+    },
+}
+
+fn parse_wig_declaration(line: &str) -> Option<WigStep> {
+    let mut fields = line.split_whitespace();
+    let kind = fields.next()?;
+    let mut chrom = None;
+    let mut start = 1;
+    let mut step = 1;
+    let mut span = 1;
+    for field in fields {
+        let (k, v) = field.split_once('=')?;
+        match k {
+            "chrom" => chrom = Some(v.to_string()),
+            "start" => start = v.parse().ok()?,
+            "step" => step = v.parse().ok()?,
+            "span" => span = v.parse().ok()?,
+            _ => {}
+        }
+    }
+    let chrom = chrom?;
+    match kind {
+        "fixedStep" => Some(WigStep::Fixed {
+            chrom,
+            next_start: start - 1,
+            step,
+            span,
+        }),
+        "variableStep" => Some(WigStep::Variable { chrom, span }),
+        _ => None,
+    }
+}
+
+/// Read fixedStep/variableStep WIG data lines as bedGraph intervals.
+fn read_wig<R: BufRead>(reader: R) -> Result<Vec<SignalRecord>> {
+    let mut records = Vec::new();
+    let mut step: Option<WigStep> = None;
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if is_track_line(line) {
+            continue;
+        }
+        if line.starts_with("fixedStep") || line.starts_with("variableStep") {
+            step = parse_wig_declaration(line);
+            if step.is_none() {
+                warn!("SKIP: Error parsing WIG declaration: {}", line);
+            }
+            continue;
+        }
+        let record = match step.as_mut() {
+            Some(WigStep::Fixed {
+                chrom,
+                next_start,
+                step,
+                span,
+            }) => {
+                let start = *next_start;
+                *next_start += *step;
+                line.parse().ok().map(|value| SignalRecord {
+                    chrom: chrom.clone(),
+                    start,
+                    end: start + *span,
+                    value,
+                })
+            }
+            Some(WigStep::Variable { chrom, span }) => {
+                let mut fields = line.split_whitespace();
+                let pos = fields.next().and_then(|x| x.parse::<usize>().ok());
+                let value = fields.next().and_then(|x| x.parse::<f64>().ok());
+                match (pos, value) {
+                    (Some(pos), Some(value)) if pos > 0 => Some(SignalRecord {
+                        chrom: chrom.clone(),
+                        start: pos - 1,
+                        end: pos - 1 + *span,
+                        value,
+                    }),
+                    _ => None,
+                }
+            }
+            None => None,
+        };
+        match record {
+            Some(record) => records.push(record),
+            None => warn!("SKIP: Error parsing WIG line: {}", line),
+        }
+    }
+    Ok(records)
+}
+
+/// Resolve overlapping intervals of one chromosome into sorted, non-overlapping
+/// intervals; neighbours with equal values are joined.
+fn resolve_overlaps(
+    chrom: &str,
+    mut ivls: Vec<(usize, usize, f64)>,
+    policy: MergePolicy,
+) -> Vec<SignalRecord> {
+    ivls.sort_by_key(|x| x.0);
+    let mut bounds: Vec<usize> = ivls.iter().flat_map(|x| [x.0, x.1]).collect();
+    bounds.sort_unstable();
+    bounds.dedup();
+    let mut resolved: Vec<SignalRecord> = Vec::new();
+    let mut active: Vec<(usize, f64)> = Vec::new();
+    let mut next = 0;
+    for w in bounds.windows(2) {
+        let (seg_start, seg_end) = (w[0], w[1]);
+        active.retain(|x| x.0 > seg_start);
+        while next < ivls.len() && ivls[next].0 <= seg_start {
+            active.push((ivls[next].1, ivls[next].2));
+            next += 1;
+        }
+        let values: Vec<f64> = active.iter().map(|x| x.1).collect();
+        let value = match policy.merge(&values) {
+            Some(value) => value,
+            None => continue,
+        };
+        match resolved.last_mut() {
+            Some(last) if last.end == seg_start && last.value == value => last.end = seg_end,
+            _ => resolved.push(SignalRecord {
+                chrom: chrom.to_string(),
+                start: seg_start,
+                end: seg_end,
+                value,
+            }),
+        }
+    }
+    resolved
+}
+
+/// Lift every interval and write sorted, non-overlapping bedGraph.
+fn cross_signal(
+    records: impl Iterator<Item = Result<SignalRecord>>,
+    lapper_hashmap: &LapperHashMap,
+    output: &Option<String>,
+    unmaped: &Option<String>,
+    policy: MergePolicy,
+    rewrite: bool,
+) -> Result<()> {
+    let (mut output_file, stdout_mode) = get_output_writer(output, rewrite)?;
+    let mut unmaped_file = match stdout_mode {
+        true => Box::new(io::stdout()),
+        false => get_output_writer(unmaped, rewrite)?.0,
+    };
+    let mut chrom_ivls: BTreeMap<String, Vec<(usize, usize, f64)>> = BTreeMap::new();
+    for record in records {
+        let record = record?;
+        if record.start >= record.end {
+            warn!("SKIP: Start not smaller than end in record: {}", record);
+            continue;
+        }
+        let q_region = Region {
+            chrom: &record.chrom,
+            start: record.start,
+            end: record.end,
+            strand: Strand::Positive,
+        };
+        match find_in_lapper(lapper_hashmap, &q_region) {
+            None => unmaped_file.write_all(format!("{}\tUNMAP\n", record).as_bytes())?,
+            Some(matches) => {
+                for hit in matches.iter().skip(1).step_by(2) {
+                    chrom_ivls.entry(hit.chrom.to_string()).or_default().push((
+                        hit.start,
+                        hit.end,
+                        record.value,
+                    ));
+                }
+            }
+        }
+    }
+    for (chrom, ivls) in chrom_ivls {
+        for record in resolve_overlaps(&chrom, ivls, policy) {
+            output_file.write_all(format!("{}\n", record).as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+pub fn cross_bedgraph(
+    bedgraph_file: &String,
+    input_chain: &Option<String>,
+    output: &Option<String>,
+    unmaped: &Option<String>,
+    policy: MergePolicy,
+    rewrite: bool,
+) -> Result<()> {
+    let lapper_hashmap = get_lapper_hashmap(input_chain)?;
+    info!("get lapper hashmap done!");
+    let reader = BufReader::new(get_file_reader(bedgraph_file)?);
+    let records = reader.lines().filter_map(|line| {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e.into())),
+        };
+        if is_track_line(&line) {
+            return None;
+        }
+        let record = parse_bedgraph_line(&line);
+        if record.is_none() {
+            warn!("SKIP: Error parsing bedGraph record: {}", line);
+        }
+        record.map(Ok)
+    });
+    cross_signal(records, &lapper_hashmap, output, unmaped, policy, rewrite)
+}
+
+pub fn cross_wig(
+    wig_file: &String,
+    input_chain: &Option<String>,
+    output: &Option<String>,
+    unmaped: &Option<String>,
+    policy: MergePolicy,
+    rewrite: bool,
+) -> Result<()> {
+    let lapper_hashmap = get_lapper_hashmap(input_chain)?;
+    info!("get lapper hashmap done!");
+    let reader = BufReader::new(get_file_reader(wig_file)?);
+    let records = read_wig(reader)?;
+    cross_signal(
+        records.into_iter().map(Ok),
+        &lapper_hashmap,
+        output,
+        unmaped,
+        policy,
+        rewrite,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interval::lapper_hashmap_from_str;
+
+    /// Overlapping intervals as lifted, with a gap before the last one.
+    fn lifted() -> Vec<(usize, usize, f64)> {
+        vec![(20, 25, 2.0), (5, 15, 3.0), (0, 10, 1.0), (8, 12, 5.0)]
+    }
+
+    fn resolved(policy: MergePolicy) -> Vec<(usize, usize, f64)> {
+        resolve_overlaps("chr1", lifted(), policy)
+            .into_iter()
+            .map(|x| {
+                assert_eq!(x.chrom, "chr1");
+                (x.start, x.end, x.value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mean() {
+        assert_eq!(
+            resolved(MergePolicy::Mean),
+            [
+                (0, 5, 1.0),
+                (5, 8, 2.0),
+                (8, 10, 3.0),
+                (10, 12, 4.0),
+                (12, 15, 3.0),
+                (20, 25, 2.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn max() {
+        // 8-10 and 10-12 both take 5 and are joined
+        assert_eq!(
+            resolved(MergePolicy::Max),
+            [
+                (0, 5, 1.0),
+                (5, 8, 3.0),
+                (8, 12, 5.0),
+                (12, 15, 3.0),
+                (20, 25, 2.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn sum() {
+        assert_eq!(
+            resolved(MergePolicy::Sum),
+            [
+                (0, 5, 1.0),
+                (5, 8, 4.0),
+                (8, 10, 9.0),
+                (10, 12, 8.0),
+                (12, 15, 3.0),
+                (20, 25, 2.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn drop() {
+        assert_eq!(
+            resolved(MergePolicy::Drop),
+            [(0, 5, 1.0), (12, 15, 3.0), (20, 25, 2.0)]
+        );
+    }
+
+    #[test]
+    fn lifted_onto_the_same_bases() {
+        // chr1 and chr2 both map onto chrN:100-110
+        let chains = "chain 10 chr1 50 + 0 10 chrN 200 + 100 110 1\n10\n\n\
+                      chain 10 chr2 50 + 0 10 chrN 200 + 100 110 2\n10\n\n";
+        let (lapper_hashmap, _) = lapper_hashmap_from_str(chains).unwrap();
+        let records = vec![
+            SignalRecord {
+                chrom: "chr1".to_string(),
+                start: 0,
+                end: 10,
+                value: 1.0,
+            },
+            SignalRecord {
+                chrom: "chr2".to_string(),
+                start: 5,
+                end: 10,
+                value: 3.0,
+            },
+        ];
+        let output = std::env::temp_dir()
+            .join(format!("crussmap_bedgraph_{}.bedgraph", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        cross_signal(
+            records.into_iter().map(Ok),
+            &lapper_hashmap,
+            &Some(output.clone()),
+            &None,
+            MergePolicy::Sum,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&output).unwrap(),
+            "chrN\t100\t105\t1\nchrN\t105\t110\t4\n"
+        );
+        std::fs::remove_file(output).unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+use flate2::{write::DeflateEncoder, Compression, Crc};
+use std::io::{self, Write};
+
+/// Largest amount of uncompressed data put in one BGZF block, same as htslib.
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+/// Empty block which marks the end of a BGZF file.
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Writer of the blocked gzip format used by BAM and tabix.
+///
+/// Reading needs no special type: BGZF is a multi-member gzip stream, so
+/// `flate2::read::MultiGzDecoder` decodes it.
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    /// size of the compressed blocks written so far
+    coffset: u64,
+    finished: bool,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> Self {
+        BgzfWriter {
+            inner,
+            buf: Vec::with_capacity(BGZF_BLOCK_SIZE),
+            coffset: 0,
+            finished: false,
+        }
+    }
+
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.buf)?;
+        let cdata = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(&self.buf);
+        // 18 bytes of header and 8 bytes of footer around the deflate data
+        let block_size = (cdata.len() + 25) as u16;
+        let mut block = Vec::with_capacity(cdata.len() + 26);
+        block.extend_from_slice(&[
+            0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+            0x02, 0x00,
+        ]);
+        block.extend_from_slice(&block_size.to_le_bytes());
+        block.extend_from_slice(&cdata);
+        block.extend_from_slice(&crc.sum().to_le_bytes());
+        block.extend_from_slice(&(self.buf.len() as u32).to_le_bytes());
+        self.inner.write_all(&block)?;
+        self.coffset += block.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Virtual offset of the next byte written: offset of the current block in the
+    /// file in the upper 48 bits, offset inside its uncompressed data in the lower 16.
+    pub fn virtual_offset(&self) -> u64 {
+        (self.coffset << 16) | self.buf.len() as u64
+    }
+
+    /// Flush pending data and write the EOF marker block.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.write_block()?;
+        self.inner.write_all(&BGZF_EOF)?;
+        self.inner.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = data.len().min(BGZF_BLOCK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..n]);
+        if self.buf.len() == BGZF_BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    /// Compressed size of every block, read from the BSIZE field of its header.
+    fn block_sizes(data: &[u8]) -> Vec<usize> {
+        let mut sizes = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            assert_eq!(data[offset..offset + 4], [0x1f, 0x8b, 0x08, 0x04]);
+            let size = u16::from_le_bytes([data[offset + 16], data[offset + 17]]) as usize + 1;
+            sizes.push(size);
+            offset += size;
+        }
+        assert_eq!(offset, data.len());
+        sizes
+    }
+
+    #[test]
+    fn blocks_round_trip() {
+        let text: String = (0..20000)
+            .map(|i| format!("chr1\t{}\t{}\n", i, i * 7))
+            .collect();
+        let mut data: Vec<u8> = Vec::new();
+        let mut writer = BgzfWriter::new(&mut data);
+        writer.write_all(&text.as_bytes()[..100]).unwrap();
+        assert_eq!(writer.virtual_offset(), 100);
+        writer.write_all(&text.as_bytes()[100..]).unwrap();
+        let full_blocks = text.len() / BGZF_BLOCK_SIZE;
+        let virtual_offset = writer.virtual_offset();
+        assert_eq!(
+            virtual_offset & 0xffff,
+            (text.len() % BGZF_BLOCK_SIZE) as u64
+        );
+        writer.finish().unwrap();
+        drop(writer);
+
+        let sizes = block_sizes(&data);
+        // full blocks, the rest and the EOF marker
+        assert_eq!(sizes.len(), full_blocks + 2);
+        let last_block: usize = sizes[..full_blocks].iter().sum();
+        assert_eq!(virtual_offset >> 16, last_block as u64);
+        assert!(data.ends_with(&BGZF_EOF));
+        let mut decoded = String::new();
+        MultiGzDecoder::new(data.as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+    }
+
+    #[test]
+    fn empty_file_is_the_eof_block() {
+        let mut data: Vec<u8> = Vec::new();
+        BgzfWriter::new(&mut data).finish().unwrap();
+        assert_eq!(data, BGZF_EOF);
+        assert_eq!(block_sizes(&data), [28]);
+    }
+}
@@ -0,0 +1,233 @@
+use crate::{
+    error::Result,
+    parser::{
+        alignments_from_blocks, write_chain, ChainReader, ChainRecord, Header, SeqInfo, Strand,
+    },
+    utils::{get_input_reader, get_output_writer},
+};
+use log::info;
+use rust_lapper::{Interval, Lapper};
+use std::collections::{BTreeMap, HashMap};
+
+/// A block of the second chain: query interval on the positive strand, with the
+/// index of the chain it belongs to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SecondBlock {
+    chain_idx: usize,
+    start: usize,
+    end: usize,
+    strand: Strand,
+}
+
+/// Blocks of the second chain file keyed by its target (intermediate assembly)
+/// chromosome, and the query of every chain.
+fn second_lapper(
+    records: Vec<ChainRecord>,
+) -> (HashMap<String, Lapper<usize, SecondBlock>>, Vec<SeqInfo>) {
+    let mut ivls: HashMap<String, Vec<Interval<usize, SecondBlock>>> = HashMap::new();
+    let mut queries: Vec<SeqInfo> = Vec::with_capacity(records.len());
+    for (chain_idx, record) in records.into_iter().enumerate() {
+        let chrom_ivls = ivls.entry(record.header.target.name.clone()).or_default();
+        for block_ivl in record.block_ivls {
+            chrom_ivls.push(Interval {
+                start: block_ivl.start,
+                stop: block_ivl.stop,
+                val: SecondBlock {
+                    chain_idx,
+                    start: block_ivl.val.start,
+                    end: block_ivl.val.end,
+                    strand: block_ivl.val.strand,
+                },
+            });
+        }
+        queries.push(record.header.query);
+    }
+    let lappers = ivls
+        .into_iter()
+        .map(|(chrom, x)| (chrom, Lapper::new(x)))
+        .collect();
+    (lappers, queries)
+}
+
+/// Gapless blocks `(target_start, query_start, size)` of `first` carried through
+/// the second chains, grouped by second chain index. Query coordinates are on
+/// the strand of the composed chain.
+fn compose_blocks(
+    first: &ChainRecord,
+    lappers: &HashMap<String, Lapper<usize, SecondBlock>>,
+    queries: &[SeqInfo],
+) -> BTreeMap<usize, Vec<(usize, usize, usize)>> {
+    let mut composed: BTreeMap<usize, Vec<(usize, usize, usize)>> = BTreeMap::new();
+    for block_ivl in &first.block_ivls {
+        let mid = &block_ivl.val;
+        let lapper = match lappers.get(&mid.name) {
+            Some(lapper) => lapper,
+            None => continue,
+        };
+        for second in lapper.find(mid.start, mid.end) {
+            // overlap on the intermediate assembly, positive strand
+            let x = second.start.max(mid.start);
+            let y = second.stop.min(mid.end);
+            if x >= y {
+                continue;
+            }
+            let (a_start, a_end) = match mid.strand {
+                Strand::Positive => (
+                    block_ivl.start + (x - mid.start),
+                    block_ivl.start + (y - mid.start),
+                ),
+                Strand::Negative => (
+                    block_ivl.start + (mid.end - y),
+                    block_ivl.start + (mid.end - x),
+                ),
+            };
+            let last = &second.val;
+            let (c_start, c_end) = match last.strand {
+                Strand::Positive => (
+                    last.start + (x - second.start),
+                    last.start + (y - second.start),
+                ),
+                Strand::Negative => (last.end - (y - second.start), last.end - (x - second.start)),
+            };
+            let c_size = queries[last.chain_idx].size;
+            let c_start = match mid.strand == last.strand {
+                true => c_start,
+                false => c_size - c_end,
+            };
+            composed
+                .entry(last.chain_idx)
+                .or_default()
+                .push((a_start, c_start, a_end - a_start));
+        }
+    }
+    composed
+}
+
+/// Compose chains `first` (A to B) and `second` (B to C) into chains from A to C,
+/// one for every pair of chains sharing aligned bases on B. Scores are the number of
+/// aligned bases, chain ids are renumbered.
+pub fn compose_chain(
+    first: &String,
+    second: &String,
+    output: &Option<String>,
+    rewrite: bool,
+) -> Result<()> {
+    let second_reader = get_input_reader(&Some(second.to_string()))?;
+    let second_records: Vec<ChainRecord> = ChainReader::new(second_reader)
+        .skip_malformed()
+        .collect::<Result<_>>()?;
+    let (lappers, queries) = second_lapper(second_records);
+    info!("get lapper hashmap done!");
+    let first_reader = get_input_reader(&Some(first.to_string()))?;
+    let (mut output_file, _) = get_output_writer(output, rewrite)?;
+    let mut chain_id = 0;
+    for first_record in ChainReader::new(first_reader).skip_malformed() {
+        let first_record = first_record?;
+        for (chain_idx, mut blocks) in compose_blocks(&first_record, &lappers, &queries) {
+            blocks.sort_unstable();
+            // both chains are colinear, drop anything that is not after the previous block
+            let mut kept: Vec<(usize, usize, usize)> = Vec::with_capacity(blocks.len());
+            for block in blocks {
+                match kept.last() {
+                    Some(prev) if block.0 < prev.0 + prev.2 || block.1 < prev.1 + prev.2 => {}
+                    _ => kept.push(block),
+                }
+            }
+            let (first_block, last_block) = match (kept.first(), kept.last()) {
+                (Some(first_block), Some(last_block)) => (*first_block, *last_block),
+                _ => continue,
+            };
+            let query = &queries[chain_idx];
+            let strand = match first_record.header.query.strand == query.strand {
+                true => Strand::Positive,
+                false => Strand::Negative,
+            };
+            chain_id += 1;
+            let score: usize = kept.iter().map(|x| x.2).sum();
+            let header = Header::new(
+                score as f64,
+                SeqInfo {
+                    start: first_block.0,
+                    end: last_block.0 + last_block.2,
+                    ..first_record.header.target.clone()
+                },
+                SeqInfo {
+                    name: query.name.clone(),
+                    size: query.size,
+                    strand,
+                    start: first_block.1,
+                    end: last_block.1 + last_block.2,
+                },
+                chain_id,
+            );
+            write_chain(&mut output_file, &header, &alignments_from_blocks(&kept))?;
+        }
+    }
+    info!("write {} chains", chain_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Alignment;
+    use flate2::read::MultiGzDecoder;
+    use std::{fs::File, io::BufReader};
+
+    /// Chain mapping every base of `name` to itself.
+    fn identity(name: &str, size: usize) -> ChainRecord {
+        let seq_info = SeqInfo {
+            name: name.to_string(),
+            size,
+            strand: Strand::Positive,
+            start: 0,
+            end: size,
+        };
+        ChainRecord::new(
+            Header::new(size as f64, seq_info.clone(), seq_info, 1),
+            vec![Alignment {
+                size,
+                target_diff: 0,
+                query_diff: 0,
+            }],
+        )
+    }
+
+    /// Gapless blocks `(target_start, query_start, size)` of `record`.
+    fn own_blocks(record: &ChainRecord) -> Vec<(usize, usize, usize)> {
+        let mut target = record.header.target.start;
+        let mut query = record.header.query.start;
+        let mut blocks = Vec::with_capacity(record.alignments.len());
+        for x in &record.alignments {
+            blocks.push((target, query, x.size));
+            target += x.size + x.target_diff;
+            query += x.size + x.query_diff;
+        }
+        blocks
+    }
+
+    #[test]
+    fn compose_with_identity() {
+        let path = format!("{}/data/v4_to_v5.chain.gz", env!("CARGO_MANIFEST_DIR"));
+        let reader = BufReader::new(MultiGzDecoder::new(File::open(path).unwrap()));
+        for record in ChainReader::new(reader) {
+            let record = record.unwrap();
+            let expected = BTreeMap::from([(0, own_blocks(&record))]);
+
+            // chain then identity of its query
+            let query = &record.header.query;
+            let (lappers, queries) = second_lapper(vec![identity(&query.name, query.size)]);
+            let mut composed = compose_blocks(&record, &lappers, &queries);
+            composed.values_mut().for_each(|x| x.sort_unstable());
+            assert_eq!(composed, expected);
+
+            // identity of its target then chain
+            let target = &record.header.target;
+            let first = identity(&target.name, target.size);
+            let (lappers, queries) = second_lapper(vec![record]);
+            let mut composed = compose_blocks(&first, &lappers, &queries);
+            composed.values_mut().for_each(|x| x.sort_unstable());
+            assert_eq!(composed, expected);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use crate::{
+    error::Result,
+    parser::{alignments_from_blocks, write_chain, Header, SeqInfo, Strand},
+    utils::{get_file_reader, get_output_writer},
+};
+use log::{info, warn};
+use std::io::{BufRead, BufReader};
+
+/// One alignment of a nucmer delta file, coordinates 1-based and inclusive.
+/// `query_start > query_end` for alignments to the reverse strand of the query.
+#[derive(Debug, Clone)]
+pub struct DeltaAlignment {
+    pub ref_start: usize,
+    pub ref_end: usize,
+    pub query_start: usize,
+    pub query_end: usize,
+    pub errors: usize,
+    /// distances between indels: positive for a reference base against a gap,
+    /// negative for a query base against a gap
+    pub indels: Vec<i64>,
+}
+
+impl DeltaAlignment {
+    pub fn strand(&self) -> Strand {
+        match self.query_start > self.query_end {
+            true => Strand::Negative,
+            false => Strand::Positive,
+        }
+    }
+
+    /// Gapless blocks in chain coordinates, with the query counted on its
+    /// reverse strand for reverse alignments like `parser::blocks` expects.
+    fn blocks(&self, query_len: usize) -> Option<Vec<(usize, usize, usize)>> {
+        let mut r = self.ref_start - 1;
+        let (mut q, query_stop) = match self.strand() {
+            Strand::Positive => (self.query_start.checked_sub(1)?, self.query_end),
+            Strand::Negative => (
+                query_len.checked_sub(self.query_start)?,
+                (query_len + 1).checked_sub(self.query_end)?,
+            ),
+        };
+        let mut blocks = Vec::new();
+        for &d in &self.indels {
+            let n = d.unsigned_abs() as usize - 1;
+            blocks.push((r, q, n));
+            r += n;
+            q += n;
+            if d > 0 {
+                r += 1;
+            } else {
+                q += 1;
+            }
+        }
+        let n = self.ref_end.checked_sub(r)?;
+        blocks.push((r, q, n));
+        if q + n != query_stop {
+            return None;
+        }
+        Some(blocks.into_iter().filter(|x| x.2 > 0).collect())
+    }
+}
+
+/// Reference/query pair of a `>` line in a delta file.
+#[derive(Debug, Clone)]
+struct DeltaHeader {
+    ref_name: String,
+    query_name: String,
+    ref_len: usize,
+    query_len: usize,
+}
+
+fn parse_delta_header(line: &str) -> Option<DeltaHeader> {
+    let fields: Vec<&str> = line.trim_start_matches('>').split_whitespace().collect();
+    if fields.len() != 4 {
+        return None;
+    }
+    Some(DeltaHeader {
+        ref_name: fields[0].to_string(),
+        query_name: fields[1].to_string(),
+        ref_len: fields[2].parse().ok()?,
+        query_len: fields[3].parse().ok()?,
+    })
+}
+
+fn parse_alignment_line(line: &str) -> Option<DeltaAlignment> {
+    let fields: Vec<usize> = line
+        .split_whitespace()
+        .map(|x| x.parse::<usize>().ok())
+        .collect::<Option<Vec<usize>>>()?;
+    if fields.len() != 7 || fields[0] == 0 || fields[0] > fields[1] {
+        return None;
+    }
+    Some(DeltaAlignment {
+        ref_start: fields[0],
+        ref_end: fields[1],
+        query_start: fields[2],
+        query_end: fields[3],
+        errors: fields[4],
+        indels: Vec::new(),
+    })
+}
+
+fn to_chain_header(
+    delta_header: &DeltaHeader,
+    blocks: &[(usize, usize, usize)],
+    score: f64,
+    strand: Strand,
+    chain_id: usize,
+) -> Header {
+    let first = blocks[0];
+    let last = blocks[blocks.len() - 1];
+    Header::new(
+        score,
+        SeqInfo {
+            name: delta_header.ref_name.clone(),
+            size: delta_header.ref_len,
+            strand: Strand::Positive,
+            start: first.0,
+            end: last.0 + last.2,
+        },
+        SeqInfo {
+            name: delta_header.query_name.clone(),
+            size: delta_header.query_len,
+            strand,
+            start: first.1,
+            end: last.1 + last.2,
+        },
+        chain_id,
+    )
+}
+
+/// Convert nucmer delta alignments into UCSC chains, the delta reference becomes
+/// the chain target, i.e. the assembly lifted from.
+pub fn delta_to_chain(delta_file: &String, output: &Option<String>, rewrite: bool) -> Result<()> {
+    let reader = BufReader::new(get_file_reader(delta_file)?);
+    let (mut output_file, _) = get_output_writer(output, rewrite)?;
+    let mut lines = reader.lines();
+    // first line holds the two FASTA paths, second the program
+    lines.next().transpose()?;
+    match lines.next().transpose()?.as_deref() {
+        Some("NUCMER") => {}
+        Some(program) => {
+            warn!("{} delta is not nucleotide based, skip it", program);
+            return Ok(());
+        }
+        None => return Ok(()),
+    }
+    let mut chain_id = 0;
+    let mut delta_header: Option<DeltaHeader> = None;
+    let mut current: Option<DeltaAlignment> = None;
+    for line in lines {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('>') {
+            delta_header = parse_delta_header(line);
+            if delta_header.is_none() {
+                warn!("SKIP: Error parsing delta header: {}", line);
+            }
+            current = None;
+            continue;
+        }
+        let delta_header = match &delta_header {
+            Some(x) => x,
+            None => continue,
+        };
+        match current.as_mut() {
+            None => {
+                current = parse_alignment_line(line);
+                if current.is_none() {
+                    warn!("SKIP: Error parsing delta alignment: {}", line);
+                }
+            }
+            Some(alignment) => {
+                let d = match line.parse::<i64>() {
+                    Ok(d) => d,
+                    Err(_) => {
+                        warn!("SKIP: Error parsing delta indel: {}", line);
+                        current = None;
+                        continue;
+                    }
+                };
+                if d != 0 {
+                    alignment.indels.push(d);
+                    continue;
+                }
+                let alignment = current.take().unwrap();
+                let blocks = match alignment.blocks(delta_header.query_len) {
+                    Some(blocks) if !blocks.is_empty() => blocks,
+                    _ => {
+                        warn!(
+                            "SKIP: Indels do not match coordinates of delta alignment {} {} {} {}",
+                            alignment.ref_start,
+                            alignment.ref_end,
+                            alignment.query_start,
+                            alignment.query_end
+                        );
+                        continue;
+                    }
+                };
+                chain_id += 1;
+                let aligned: usize = blocks.iter().map(|x| x.2).sum();
+                let score = aligned.saturating_sub(alignment.errors) as f64;
+                let header =
+                    to_chain_header(delta_header, &blocks, score, alignment.strand(), chain_id);
+                write_chain(&mut output_file, &header, &alignments_from_blocks(&blocks))?;
+            }
+        }
+    }
+    info!("write {} chains", chain_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Alignment, ChainReader, ChainRecord};
+    use crate::validate::validate_chain_reader;
+    use std::fs::File;
+
+    const DELTA: &str = "/data/ref.fa /data/qry.fa
+NUCMER
+>r1 q1 100 80
+11 30 5 24 2 2 0
+4
+-3
+0
+41 50 70 60 1 1 0
+-5
+0
+1 5 0 4 0 0 0
+0
+61 65 90 86 0 0 0
+0
+";
+
+    #[test]
+    fn forward_and_reverse_alignments() {
+        let dir = std::env::temp_dir();
+        let delta_file = dir.join(format!("crussmap_delta_{}.delta", std::process::id()));
+        let chain_file = dir.join(format!("crussmap_delta_{}.chain", std::process::id()));
+        std::fs::write(&delta_file, DELTA).unwrap();
+        delta_to_chain(
+            &delta_file.to_string_lossy().to_string(),
+            &Some(chain_file.to_string_lossy().to_string()),
+            true,
+        )
+        .unwrap();
+
+        // the last two alignments have query coordinates outside of q1
+        let (chains, diagnostics) =
+            validate_chain_reader(BufReader::new(File::open(&chain_file).unwrap())).unwrap();
+        assert_eq!((chains, diagnostics.len()), (2, 0));
+        let chains: Vec<_> = ChainReader::new(BufReader::new(File::open(&chain_file).unwrap()))
+            .map(|x| x.unwrap())
+            .collect();
+        let summary = |x: &ChainRecord| {
+            let (t, q) = (&x.header.target, &x.header.query);
+            (x.header.score, t.start, t.end, q.strand, q.start, q.end)
+        };
+
+        // a deleted reference base after 3 bases, then an inserted query base 2 later
+        assert_eq!(summary(&chains[0]), (17.0, 10, 30, Strand::Positive, 4, 24));
+        assert_eq!(
+            chains[0].alignments,
+            [
+                Alignment {
+                    size: 3,
+                    target_diff: 1,
+                    query_diff: 0
+                },
+                Alignment {
+                    size: 2,
+                    target_diff: 0,
+                    query_diff: 1
+                },
+                Alignment {
+                    size: 14,
+                    target_diff: 0,
+                    query_diff: 0
+                },
+            ]
+        );
+        // query 70..60 on the reverse strand of q1 is 10..21
+        assert_eq!(summary(&chains[1]), (9.0, 40, 50, Strand::Negative, 10, 21));
+        assert_eq!(
+            chains[1].alignments,
+            [
+                Alignment {
+                    size: 4,
+                    target_diff: 0,
+                    query_diff: 1
+                },
+                Alignment {
+                    size: 6,
+                    target_diff: 0,
+                    query_diff: 0
+                },
+            ]
+        );
+        std::fs::remove_file(delta_file).unwrap();
+        std::fs::remove_file(chain_file).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+use std::{fmt, io};
+
+/// Errors of crussmap, library functions return them instead of exiting.
+#[derive(Debug)]
+pub enum CrussmapError {
+    Io(io::Error),
+    /// input file given on the command line does not exist
+    InputNotFound(String),
+    /// output file exists and rewrite is not allowed
+    OutputExists(String),
+    /// malformed chain file, `line` and `column` are 1-based
+    ChainParse {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    /// malformed BED record, `line` is 1-based
+    BedParse {
+        line: usize,
+        message: String,
+    },
+    /// a position or region could not be converted
+    Mapping(String),
+    /// output could not be sorted or indexed
+    Index(String),
+}
+
+pub type Result<T> = std::result::Result<T, CrussmapError>;
+
+impl CrussmapError {
+    pub fn chain_parse(line: usize, column: usize, message: impl Into<String>) -> Self {
+        CrussmapError::ChainParse {
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for CrussmapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CrussmapError::Io(e) => write!(f, "I/O error: {}", e),
+            CrussmapError::InputNotFound(path) => write!(f, "file {} does not exist", path),
+            CrussmapError::OutputExists(path) => {
+                write!(f, "file {} exist, use -r to rewrite it", path)
+            }
+            CrussmapError::ChainParse {
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "chain parse error at line {}, column {}: {}",
+                line, column, message
+            ),
+            CrussmapError::BedParse { line, message } => {
+                write!(f, "BED parse error at line {}: {}", line, message)
+            }
+            CrussmapError::Mapping(message) => write!(f, "mapping error: {}", message),
+            CrussmapError::Index(message) => write!(f, "index error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for CrussmapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CrussmapError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CrussmapError {
+    fn from(e: io::Error) -> Self {
+        CrussmapError::Io(e)
+    }
+}
+
+impl From<csv::Error> for CrussmapError {
+    fn from(e: csv::Error) -> Self {
+        let line = e.position().map_or(0, |x| x.line() as usize);
+        let message = e.to_string();
+        match e.into_kind() {
+            csv::ErrorKind::Io(e) => CrussmapError::Io(e),
+            _ => CrussmapError::BedParse { line, message },
+        }
+    }
+}
+
+impl From<bincode::Error> for CrussmapError {
+    fn from(e: bincode::Error) -> Self {
+        match *e {
+            bincode::ErrorKind::Io(e) => CrussmapError::Io(e),
+            e => CrussmapError::Index(format!("invalid chain index: {}", e)),
+        }
+    }
+}
@@ -0,0 +1,381 @@
+use flate2::read::MultiGzDecoder;
+use log::{info, warn};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+/// One line of a samtools `.fai` index.
+#[derive(Debug, Clone)]
+pub struct FaiEntry {
+    pub length: usize,
+    pub offset: u64,
+    pub line_bases: usize,
+    pub line_width: usize,
+}
+
+/// Bytes of the FASTA file, `.fai` offsets are offsets in the uncompressed data.
+enum FastaSource {
+    Plain(File),
+    /// bgzip compressed, with the `(compressed, uncompressed)` offsets of every
+    /// block after the first, as in a `.gzi` index
+    Bgzf(File, Vec<(u64, u64)>),
+}
+
+/// Random access reader over a plain or bgzip compressed FASTA file.
+///
+/// Uses `<fasta>.fai` (and `<fasta>.gzi` when compressed) when they exist,
+/// otherwise the indexes are built in memory by scanning the file once, so the
+/// sequences are never fully loaded.
+pub struct FastaReader {
+    source: FastaSource,
+    index: HashMap<String, FaiEntry>,
+}
+
+impl FastaReader {
+    pub fn from_path(path: &String) -> io::Result<Self> {
+        let mut file = File::open(path)?;
+        let mut header = Vec::with_capacity(18);
+        file.by_ref().take(18).read_to_end(&mut header)?;
+        file.rewind()?;
+        let source = if is_bgzf(&header) {
+            let gzi_path = format!("{}.gzi", path);
+            let gzi = if Path::new(&gzi_path).exists() {
+                info!("load bgzip index from {}", gzi_path);
+                read_gzi(&gzi_path)?
+            } else {
+                info!("no {} found, scan bgzip blocks to build index", gzi_path);
+                build_gzi(path)?
+            };
+            FastaSource::Bgzf(file, gzi)
+        } else if header.starts_with(&[0x1f, 0x8b])
+            || header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd])
+            || header.starts_with(b"BZh")
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is compressed without random access, recompress it with `bgzip` and index it with `samtools faidx`",
+                    path
+                ),
+            ));
+        } else {
+            FastaSource::Plain(file)
+        };
+        let fai_path = format!("{}.fai", path);
+        let index = if Path::new(&fai_path).exists() {
+            info!("load fasta index from {}", fai_path);
+            read_fai(&fai_path)?
+        } else {
+            info!("no {} found, scan fasta to build index", fai_path);
+            match source {
+                FastaSource::Plain(_) => build_fai(File::open(path)?)?,
+                FastaSource::Bgzf(..) => build_fai(MultiGzDecoder::new(File::open(path)?))?,
+            }
+        };
+        Ok(FastaReader { source, index })
+    }
+
+    /// Reader of the uncompressed data from `offset` on.
+    fn read_from(&mut self, offset: u64) -> io::Result<Box<dyn Read + '_>> {
+        match &mut self.source {
+            FastaSource::Plain(file) => {
+                file.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(file))
+            }
+            FastaSource::Bgzf(file, gzi) => {
+                let (coffset, uoffset) = match gzi.partition_point(|x| x.1 <= offset) {
+                    0 => (0, 0),
+                    i => gzi[i - 1],
+                };
+                file.seek(SeekFrom::Start(coffset))?;
+                let mut reader = MultiGzDecoder::new(BufReader::new(file));
+                io::copy(&mut reader.by_ref().take(offset - uoffset), &mut io::sink())?;
+                Ok(Box::new(reader))
+            }
+        }
+    }
+
+    pub fn seq_len(&self, chrom: &str) -> Option<usize> {
+        self.index.get(chrom).map(|x| x.length)
+    }
+
+    /// Fetch the 0-based, half-open sequence `[start, end)` of `chrom` in upper case.
+    pub fn fetch(&mut self, chrom: &str, start: usize, end: usize) -> Option<Vec<u8>> {
+        let entry = self.index.get(chrom)?;
+        if start > end || end > entry.length || entry.line_bases == 0 {
+            return None;
+        }
+        let line_start = start / entry.line_bases;
+        let offset =
+            entry.offset + (line_start * entry.line_width + start % entry.line_bases) as u64;
+        let mut reader = self.read_from(offset).ok()?;
+        let mut seq = Vec::with_capacity(end - start);
+        let mut buf = [0u8; 8192];
+        while seq.len() < end - start {
+            let n = reader.read(&mut buf).ok()?;
+            if n == 0 {
+                break;
+            }
+            for &b in &buf[..n] {
+                if b != b'\n' && b != b'\r' {
+                    seq.push(b.to_ascii_uppercase());
+                    if seq.len() == end - start {
+                        break;
+                    }
+                }
+            }
+        }
+        if seq.len() != end - start {
+            warn!("truncated sequence for {}:{}-{}", chrom, start, end);
+            return None;
+        }
+        Some(seq)
+    }
+}
+
+fn read_fai(fai_path: &String) -> io::Result<HashMap<String, FaiEntry>> {
+    let mut index = HashMap::new();
+    for line in BufReader::new(File::open(fai_path)?).lines() {
+        let line = line?;
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 5 {
+            continue;
+        }
+        let parse = |x: &str| {
+            x.parse::<usize>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+        index.insert(
+            fields[0].to_string(),
+            FaiEntry {
+                length: parse(fields[1])?,
+                offset: parse(fields[2])? as u64,
+                line_bases: parse(fields[3])?,
+                line_width: parse(fields[4])?,
+            },
+        );
+    }
+    Ok(index)
+}
+
+fn build_fai<R: Read>(reader: R) -> io::Result<HashMap<String, FaiEntry>> {
+    let mut index = HashMap::new();
+    let mut reader = BufReader::new(reader);
+    let mut offset: u64 = 0;
+    let mut line = String::new();
+    let mut current: Option<(String, FaiEntry)> = None;
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line)?;
+        if n == 0 {
+            break;
+        }
+        offset += n as u64;
+        if let Some(name) = line.strip_prefix('>') {
+            if let Some((name, entry)) = current.take() {
+                index.insert(name, entry);
+            }
+            let name = name.split_whitespace().next().unwrap_or("").to_string();
+            current = Some((
+                name,
+                FaiEntry {
+                    length: 0,
+                    offset,
+                    line_bases: 0,
+                    line_width: 0,
+                },
+            ));
+        } else if let Some((_, entry)) = current.as_mut() {
+            let bases = line.trim_end_matches(['\n', '\r']).len();
+            if entry.line_bases == 0 {
+                entry.line_bases = bases;
+                entry.line_width = n;
+            }
+            entry.length += bases;
+        }
+    }
+    if let Some((name, entry)) = current.take() {
+        index.insert(name, entry);
+    }
+    Ok(index)
+}
+
+/// Gzip header with the `BC` extra subfield of a BGZF block.
+fn is_bgzf(header: &[u8]) -> bool {
+    header.len() >= 18
+        && header.starts_with(&[0x1f, 0x8b, 0x08])
+        && header[3] & 0x04 != 0
+        && header[12..14] == *b"BC"
+}
+
+/// Read a bgzip `.gzi` index: the number of entries, then `(compressed,
+/// uncompressed)` offset pairs, all little endian u64.
+fn read_gzi(gzi_path: &String) -> io::Result<Vec<(u64, u64)>> {
+    let mut data = Vec::new();
+    File::open(gzi_path)?.read_to_end(&mut data)?;
+    let values: Vec<u64> = data
+        .chunks_exact(8)
+        .map(|x| u64::from_le_bytes(x.try_into().unwrap()))
+        .collect();
+    match values.split_first() {
+        Some((&n, pairs)) if data.len() % 8 == 0 && pairs.len() as u64 == n * 2 => {
+            Ok(pairs.chunks_exact(2).map(|x| (x[0], x[1])).collect())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("malformed bgzip index {}", gzi_path),
+        )),
+    }
+}
+
+/// Offsets of the `.gzi` index, read from the header and ISIZE field of every block.
+fn build_gzi(path: &String) -> io::Result<Vec<(u64, u64)>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut gzi = Vec::new();
+    let (mut coffset, mut uoffset) = (0, 0);
+    let mut header = [0u8; 18];
+    while !reader.fill_buf()?.is_empty() {
+        reader.read_exact(&mut header)?;
+        let block_size = u16::from_le_bytes([header[16], header[17]]) as u64 + 1;
+        if !is_bgzf(&header) || block_size < 26 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not bgzip compressed at byte {}", path, coffset),
+            ));
+        }
+        if coffset > 0 {
+            gzi.push((coffset, uoffset));
+        }
+        // skip the deflate data and CRC32 to the uncompressed size
+        reader.seek_relative(block_size as i64 - 22)?;
+        let mut isize = [0u8; 4];
+        reader.read_exact(&mut isize)?;
+        coffset += block_size;
+        uoffset += u32::from_le_bytes(isize) as u64;
+    }
+    Ok(gzi)
+}
+
+/// Reverse complement a nucleotide sequence, keeping IUPAC ambiguity codes.
+pub fn revcomp(seq: &str) -> String {
+    seq.chars()
+        .rev()
+        .map(|c| match c {
+            'A' => 'T',
+            'C' => 'G',
+            'G' => 'C',
+            'T' => 'A',
+            'a' => 't',
+            'c' => 'g',
+            'g' => 'c',
+            't' => 'a',
+            'R' => 'Y',
+            'Y' => 'R',
+            'K' => 'M',
+            'M' => 'K',
+            'B' => 'V',
+            'V' => 'B',
+            'D' => 'H',
+            'H' => 'D',
+            x => x,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bgzf::BgzfWriter;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    /// Two sequences of 60 bases per line, chr2 spans three BGZF blocks.
+    fn fasta_text() -> (String, String) {
+        let chr2: String = (0..150_000u64)
+            .map(|i| b"ACGT"[((i * 7 + i / 13) % 4) as usize] as char)
+            .collect();
+        let mut text = String::from(">chr1 first\nacgtn\n>chr2\n");
+        for line in chr2.as_bytes().chunks(60) {
+            text.push_str(std::str::from_utf8(line).unwrap());
+            text.push('\n');
+        }
+        (text, chr2)
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("crussmap_fasta_{}_{}", std::process::id(), name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn fetch_all(path: &String, chr2: &str) {
+        let mut fasta = FastaReader::from_path(path).unwrap();
+        assert_eq!(fasta.seq_len("chr1"), Some(5));
+        assert_eq!(fasta.seq_len("chr2"), Some(chr2.len()));
+        assert_eq!(fasta.fetch("chr1", 1, 4).unwrap(), b"CGT");
+        for (start, end) in [
+            (0, 10),
+            (65_200, 65_400),
+            (130_000, 150_000),
+            (149_999, 150_000),
+        ] {
+            assert_eq!(
+                fasta.fetch("chr2", start, end).unwrap(),
+                chr2.as_bytes()[start..end]
+            );
+        }
+        assert_eq!(fasta.fetch("chr2", 10, 150_001), None);
+    }
+
+    #[test]
+    fn plain_and_bgzip() {
+        let (text, chr2) = fasta_text();
+        let plain = temp_path("a.fa");
+        std::fs::write(&plain, &text).unwrap();
+        fetch_all(&plain, &chr2);
+
+        let bgzip = temp_path("a.fa.gz");
+        let mut writer = BgzfWriter::new(File::create(&bgzip).unwrap());
+        writer.write_all(text.as_bytes()).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        let gzi = build_gzi(&bgzip).unwrap();
+        // two full blocks of 0xff00 bytes, then the last one and the EOF marker
+        assert_eq!(gzi.len(), 3);
+        assert_eq!((gzi[0].1, gzi[1].1), (0xff00, 0x1fe00));
+        fetch_all(&bgzip, &chr2);
+
+        // same as samtools faidx writes, with offsets in the uncompressed text
+        let fai = format!("{}.fai", bgzip);
+        std::fs::write(&fai, "chr1\t5\t12\t5\t6\nchr2\t150000\t24\t60\t61\n").unwrap();
+        let mut data = (gzi.len() as u64).to_le_bytes().to_vec();
+        for (coffset, uoffset) in &gzi {
+            data.extend_from_slice(&coffset.to_le_bytes());
+            data.extend_from_slice(&uoffset.to_le_bytes());
+        }
+        let gzi_path = format!("{}.gzi", bgzip);
+        std::fs::write(&gzi_path, data).unwrap();
+        assert_eq!(read_gzi(&gzi_path).unwrap(), gzi);
+        fetch_all(&bgzip, &chr2);
+
+        for file in [plain, bgzip, fai, gzi_path] {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn gzip_is_an_error() {
+        let path = temp_path("b.fa.gz");
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::default());
+        encoder.write_all(b">chr1\nACGT\n").unwrap();
+        encoder.finish().unwrap();
+        let error = FastaReader::from_path(&path).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("bgzip"));
+        std::fs::remove_file(path).unwrap();
+    }
+}
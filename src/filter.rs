@@ -0,0 +1,186 @@
+use crate::{
+    error::Result,
+    parser::{ChainReader, ChainRecord, Strand},
+    utils::{get_input_reader, get_output_writer},
+};
+use log::info;
+use regex::Regex;
+use std::collections::HashSet;
+
+/// Conditions a chain must meet to be kept, unset ones always pass.
+#[derive(Debug, Default)]
+pub struct ChainFilter {
+    pub min_score: Option<f64>,
+    /// minimum number of aligned bases, i.e. the sum of block sizes
+    pub min_aligned: Option<usize>,
+    pub target_chrom: Option<Regex>,
+    pub query_chrom: Option<Regex>,
+    pub chain_ids: HashSet<usize>,
+    pub query_strand: Option<Strand>,
+    /// keep the chains failing the conditions instead
+    pub invert: bool,
+}
+
+impl ChainFilter {
+    pub fn keep(&self, record: &ChainRecord) -> bool {
+        let header = &record.header;
+        let aligned: usize = record.alignments.iter().map(|x| x.size).sum();
+        let pass = self.min_score.is_none_or(|x| header.score >= x)
+            && self.min_aligned.is_none_or(|x| aligned >= x)
+            && self
+                .target_chrom
+                .as_ref()
+                .is_none_or(|x| x.is_match(&header.target.name))
+            && self
+                .query_chrom
+                .as_ref()
+                .is_none_or(|x| x.is_match(&header.query.name))
+            && (self.chain_ids.is_empty() || self.chain_ids.contains(&header.chain_id))
+            && self.query_strand.is_none_or(|x| header.query.strand == x);
+        pass != self.invert
+    }
+}
+
+/// Write the chains of `input` accepted by `chain_filter`, unchanged.
+pub fn filter_chain(
+    input: &Option<String>,
+    output: &Option<String>,
+    chain_filter: &ChainFilter,
+    rewrite: bool,
+) -> Result<()> {
+    let reader = get_input_reader(input)?;
+    let (mut output_file, _) = get_output_writer(output, rewrite)?;
+    let mut total = 0;
+    let mut kept = 0;
+    for chain_record in ChainReader::new(reader).skip_malformed() {
+        let chain_record = chain_record?;
+        total += 1;
+        if chain_filter.keep(&chain_record) {
+            chain_record.write(&mut output_file)?;
+            kept += 1;
+        }
+    }
+    info!("keep {} of {} chains", kept, total);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ChainRecords;
+
+    const CHAINS: &str = "chain 1000 chr1 100 + 0 35 chrA 100 + 0 30 1
+10\t5\t0
+20
+
+chain 500 chr2 100 + 0 50 chrB 100 - 10 60 2
+50
+
+chain 200 chrUn_x 100 + 0 5 chrA 100 + 50 55 3
+5
+";
+
+    /// Chain ids kept by `chain_filter`.
+    fn kept(chain_filter: ChainFilter) -> Vec<usize> {
+        ChainRecords::new(CHAINS)
+            .map(|x| x.unwrap())
+            .filter(|x| chain_filter.keep(x))
+            .map(|x| x.header.chain_id)
+            .collect()
+    }
+
+    #[test]
+    fn no_criteria() {
+        assert_eq!(kept(ChainFilter::default()), [1, 2, 3]);
+    }
+
+    #[test]
+    fn min_score() {
+        let chain_filter = ChainFilter {
+            min_score: Some(500.0),
+            ..Default::default()
+        };
+        assert_eq!(kept(chain_filter), [1, 2]);
+    }
+
+    #[test]
+    fn min_aligned() {
+        // chain 1 aligns 30 bases over 35 target bases
+        let chain_filter = ChainFilter {
+            min_aligned: Some(31),
+            ..Default::default()
+        };
+        assert_eq!(kept(chain_filter), [2]);
+    }
+
+    #[test]
+    fn chrom_patterns() {
+        let chain_filter = ChainFilter {
+            target_chrom: Some(Regex::new(r"^chr\d+$").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(kept(chain_filter), [1, 2]);
+        let chain_filter = ChainFilter {
+            query_chrom: Some(Regex::new("A").unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(kept(chain_filter), [1, 3]);
+    }
+
+    #[test]
+    fn chain_ids() {
+        let chain_filter = ChainFilter {
+            chain_ids: HashSet::from([2, 3, 4]),
+            ..Default::default()
+        };
+        assert_eq!(kept(chain_filter), [2, 3]);
+    }
+
+    #[test]
+    fn query_strand() {
+        let chain_filter = ChainFilter {
+            query_strand: Some(Strand::Negative),
+            ..Default::default()
+        };
+        assert_eq!(kept(chain_filter), [2]);
+    }
+
+    #[test]
+    fn invert() {
+        let chain_filter = ChainFilter {
+            query_strand: Some(Strand::Negative),
+            invert: true,
+            ..Default::default()
+        };
+        assert_eq!(kept(chain_filter), [1, 3]);
+    }
+
+    #[test]
+    fn combined_criteria() {
+        // every criterion must pass
+        let chain_filter = ChainFilter {
+            min_score: Some(100.0),
+            query_chrom: Some(Regex::new("^chrA$").unwrap()),
+            query_strand: Some(Strand::Positive),
+            ..Default::default()
+        };
+        assert_eq!(kept(chain_filter), [1, 3]);
+        let chain_filter = ChainFilter {
+            min_score: Some(100.0),
+            min_aligned: Some(10),
+            query_chrom: Some(Regex::new("^chrA$").unwrap()),
+            query_strand: Some(Strand::Positive),
+            ..Default::default()
+        };
+        assert_eq!(kept(chain_filter), [1]);
+        // and the inverted filter keeps the chains failing any of them
+        let chain_filter = ChainFilter {
+            min_score: Some(100.0),
+            min_aligned: Some(10),
+            query_chrom: Some(Regex::new("^chrA$").unwrap()),
+            invert: true,
+            ..Default::default()
+        };
+        assert_eq!(kept(chain_filter), [2, 3]);
+    }
+}
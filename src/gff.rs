@@ -0,0 +1,452 @@
+use crate::{
+    error::CrussmapError,
+    interval::{get_lapper_hashmap, LapperHashMap},
+    liftover::{map_pieces, merge_pieces},
+    parser::Strand,
+    tabix::{OutputOptions, TabixConf},
+    utils::{get_compressed_output_writer, get_file_reader},
+};
+use log::{info, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+};
+
+/// Why a GFF3/GTF feature could not be lifted to the new assembly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GffUnmapReason {
+    /// feature does not overlap any chain block
+    Unmap,
+    /// feature is split across several chains, chromosomes or strands
+    Split,
+    /// only part of the feature is covered by a chain block
+    PartialMap,
+    /// parent feature failed to be lifted
+    ParentUnmapped,
+    /// feature lands on another chromosome/strand than its parent
+    ParentMismatch,
+}
+
+impl fmt::Display for GffUnmapReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            GffUnmapReason::Unmap => "Unmap",
+            GffUnmapReason::Split => "Split",
+            GffUnmapReason::PartialMap => "PartialMap",
+            GffUnmapReason::ParentUnmapped => "ParentUnmapped",
+            GffUnmapReason::ParentMismatch => "ParentMismatch",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GffFormat {
+    Gff3,
+    Gtf,
+}
+
+#[derive(Debug, Clone)]
+pub struct GffRecord {
+    pub seqid: String,
+    pub source: String,
+    pub feature: String,
+    /// 1-based, inclusive
+    pub start: usize,
+    /// 1-based, inclusive
+    pub end: usize,
+    pub score: String,
+    pub strand: String,
+    pub phase: String,
+    pub attributes: String,
+}
+
+impl GffRecord {
+    pub fn from_line(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 9 {
+            return None;
+        }
+        Some(GffRecord {
+            seqid: fields[0].to_string(),
+            source: fields[1].to_string(),
+            feature: fields[2].to_string(),
+            start: fields[3].parse::<usize>().ok()?,
+            end: fields[4].parse::<usize>().ok()?,
+            score: fields[5].to_string(),
+            strand: fields[6].to_string(),
+            phase: fields[7].to_string(),
+            attributes: fields[8].to_string(),
+        })
+    }
+
+    /// Guess the format from the attribute column syntax: `key "value";` for GTF, `key=value` for GFF3.
+    pub fn format(&self) -> GffFormat {
+        if self.attributes.contains("gene_id \"") || self.attributes.contains("transcript_id \"") {
+            GffFormat::Gtf
+        } else {
+            GffFormat::Gff3
+        }
+    }
+
+    /// Get an attribute value by key in either GFF3 or GTF syntax.
+    pub fn attribute(&self, key: &str) -> Option<&str> {
+        for attr in self.attributes.split(';') {
+            let attr = attr.trim();
+            let (k, v) = match attr.split_once('=') {
+                Some(kv) => kv,
+                None => match attr.split_once(' ') {
+                    Some(kv) => kv,
+                    None => continue,
+                },
+            };
+            if k.trim() == key {
+                return Some(v.trim().trim_matches('"'));
+            }
+        }
+        None
+    }
+
+    /// Identifier other features refer to: `ID` in GFF3; `gene_id`/`transcript_id` of gene/transcript rows in GTF.
+    fn own_id(&self, format: GffFormat) -> Option<String> {
+        match format {
+            GffFormat::Gff3 => self.attribute("ID").map(|x| x.to_string()),
+            GffFormat::Gtf => match self.feature.as_str() {
+                "gene" => self.attribute("gene_id").map(|x| format!("gene:{}", x)),
+                "transcript" => self
+                    .attribute("transcript_id")
+                    .map(|x| format!("transcript:{}", x)),
+                _ => None,
+            },
+        }
+    }
+
+    /// Identifiers of the parent features, see [`GffRecord::own_id`].
+    fn parent_ids(&self, format: GffFormat) -> Vec<String> {
+        match format {
+            GffFormat::Gff3 => match self.attribute("Parent") {
+                Some(parents) => parents.split(',').map(|x| x.to_string()).collect(),
+                None => Vec::new(),
+            },
+            GffFormat::Gtf => match self.feature.as_str() {
+                "gene" => Vec::new(),
+                "transcript" => self
+                    .attribute("gene_id")
+                    .map(|x| vec![format!("gene:{}", x)])
+                    .unwrap_or_default(),
+                _ => match self.attribute("transcript_id") {
+                    Some(x) => vec![format!("transcript:{}", x)],
+                    None => self
+                        .attribute("gene_id")
+                        .map(|x| vec![format!("gene:{}", x)])
+                        .unwrap_or_default(),
+                },
+            },
+        }
+    }
+
+    fn location(&self) -> (String, String) {
+        (self.seqid.clone(), self.strand.clone())
+    }
+}
+
+impl fmt::Display for GffRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.seqid,
+            self.source,
+            self.feature,
+            self.start,
+            self.end,
+            self.score,
+            self.strand,
+            self.phase,
+            self.attributes
+        )
+    }
+}
+
+/// Lift columns 1/4/5/7 of a single feature. Blocks of one chain are joined, so
+/// chain gaps inside the feature are allowed, but both ends must be covered and all
+/// of it must land on one chromosome and strand through one chain.
+fn lift_gff_record(
+    record: &GffRecord,
+    lapper_hashmap: &LapperHashMap,
+) -> Result<GffRecord, GffUnmapReason> {
+    let start = record.start.saturating_sub(1);
+    let end = record.end;
+    let strand = match record.strand.as_str() {
+        "-" => Strand::Negative,
+        _ => Strand::Positive,
+    };
+    if !lapper_hashmap.contains_key(&record.seqid) {
+        warn!("chrom:{} not found in chain file", record.seqid);
+        return Err(GffUnmapReason::Unmap);
+    }
+    let pieces = map_pieces(lapper_hashmap, &record.seqid, start, end, strand);
+    let hit = match merge_pieces(pieces, None).as_slice() {
+        [] => return Err(GffUnmapReason::Unmap),
+        [hit] => hit.clone(),
+        _ => return Err(GffUnmapReason::Split),
+    };
+    if hit.source.start != start || hit.source.end != end {
+        return Err(GffUnmapReason::PartialMap);
+    }
+    let mut lifted = record.clone();
+    lifted.seqid = hit.lifted.chrom;
+    lifted.start = hit.lifted.start + 1;
+    lifted.end = hit.lifted.end;
+    if record.strand == "+" || record.strand == "-" {
+        lifted.strand = hit.lifted.strand.to_string();
+    }
+    Ok(lifted)
+}
+
+enum GffLine {
+    Comment(String),
+    Feature(Box<(GffRecord, Result<GffRecord, GffUnmapReason>)>),
+}
+
+/// Unmap features whose parent failed or landed elsewhere, down to the leaves.
+fn reject_orphans(gff_lines: &mut [GffLine], format: GffFormat) {
+    // locations of lifted parents; in GTF the first lifted member stands for a
+    // gene/transcript without its own row
+    let mut locations: HashMap<String, (String, String)> = HashMap::new();
+    let mut failed_ids: HashSet<String> = HashSet::new();
+    for gff_line in gff_lines.iter() {
+        if let GffLine::Feature(feature) = gff_line {
+            let (gff_rcd, lifted) = feature.as_ref();
+            if let Some(id) = gff_rcd.own_id(format) {
+                match lifted {
+                    Ok(lifted) => {
+                        locations.insert(id, lifted.location());
+                    }
+                    Err(_) => {
+                        failed_ids.insert(id);
+                    }
+                }
+            }
+        }
+    }
+    if format == GffFormat::Gtf {
+        for gff_line in gff_lines.iter() {
+            if let GffLine::Feature(feature) = gff_line {
+                let (gff_rcd, lifted) = match feature.as_ref() {
+                    (gff_rcd, Ok(lifted)) => (gff_rcd, lifted),
+                    _ => continue,
+                };
+                for parent in gff_rcd.parent_ids(format) {
+                    if !failed_ids.contains(&parent) {
+                        locations.entry(parent).or_insert_with(|| lifted.location());
+                    }
+                }
+            }
+        }
+    }
+    // reject children of failed parents until nothing changes, so whole subtrees go away
+    loop {
+        let mut changed = false;
+        for gff_line in gff_lines.iter_mut() {
+            if let GffLine::Feature(feature) = gff_line {
+                let (gff_rcd, lifted) = feature.as_mut();
+                let location = match lifted {
+                    Ok(lifted) => lifted.location(),
+                    Err(_) => continue,
+                };
+                let mut reason = None;
+                for parent in gff_rcd.parent_ids(format) {
+                    if failed_ids.contains(&parent) {
+                        reason = Some(GffUnmapReason::ParentUnmapped);
+                        break;
+                    }
+                    if let Some(parent_location) = locations.get(&parent) {
+                        if parent_location != &location {
+                            reason = Some(GffUnmapReason::ParentMismatch);
+                            break;
+                        }
+                    }
+                }
+                if let Some(reason) = reason {
+                    *lifted = Err(reason);
+                    if let Some(id) = gff_rcd.own_id(format) {
+                        failed_ids.insert(id);
+                    }
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+}
+
+pub fn cross_gff(
+    gff_file: &String,
+    input_chain: &Option<String>,
+    output_gff: &Option<String>,
+    unmaped_gff: &Option<String>,
+    output_options: &OutputOptions,
+    rewrite: bool,
+) -> Result<(), CrussmapError> {
+    let lapper_hashmap = get_lapper_hashmap(input_chain)?;
+    info!("get lapper hashmap done!");
+    let gff_file = BufReader::new(get_file_reader(gff_file)?);
+    let compression = output_options.compression(output_gff)?;
+    let (mut output_file, stdout_mode) =
+        get_compressed_output_writer(output_gff, compression, rewrite)?;
+    let mut unmaped_file = match stdout_mode {
+        true => Box::new(io::stdout()),
+        false => get_compressed_output_writer(unmaped_gff, output_options.compress, rewrite)?.0,
+    };
+
+    // whole file is kept to check the hierarchy, parents may come after their children
+    let mut format: Option<GffFormat> = None;
+    let mut gff_lines: Vec<GffLine> = Vec::new();
+    for line in gff_file.lines() {
+        let line = line?;
+        if line.starts_with("##FASTA") {
+            break;
+        }
+        if line.starts_with("##sequence-region") || line.is_empty() {
+            // describes sequences of the old assembly
+            continue;
+        }
+        if line.starts_with('#') {
+            gff_lines.push(GffLine::Comment(line));
+            continue;
+        }
+        let gff_rcd = match GffRecord::from_line(&line) {
+            Some(gff_rcd) => gff_rcd,
+            None => {
+                warn!("SKIP: Error parsing GFF record: {}", line);
+                continue;
+            }
+        };
+        if gff_rcd.start > gff_rcd.end {
+            warn!("SKIP: Start larger end in GFF record: {}", line);
+            continue;
+        }
+        format.get_or_insert(gff_rcd.format());
+        let lifted = lift_gff_record(&gff_rcd, &lapper_hashmap);
+        gff_lines.push(GffLine::Feature(Box::new((gff_rcd, lifted))));
+    }
+    let format = format.unwrap_or(GffFormat::Gff3);
+
+    reject_orphans(&mut gff_lines, format);
+
+    for gff_line in gff_lines {
+        match gff_line {
+            GffLine::Comment(line) => output_file.write_all(format!("{}\n", line).as_bytes())?,
+            GffLine::Feature(feature) => match *feature {
+                (_, Ok(lifted)) => output_file.write_all(format!("{}\n", lifted).as_bytes())?,
+                (gff_rcd, Err(reason)) => {
+                    unmaped_file.write_all(format!("{}\t{}\n", gff_rcd, reason).as_bytes())?
+                }
+            },
+        }
+    }
+    // finish the compressed stream before it is read back
+    output_file.flush()?;
+    drop(output_file);
+    output_options.finish(output_gff, &TabixConf::GFF, rewrite)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interval::lapper_hashmap_from_str;
+
+    /// chrT [0, 400) to chrQ with chrT:200 deleted, and chrT [500, 600) to chrR.
+    const INDEL_CHAIN: &str = "chain 100 chrT 1000 + 0 400 chrQ 1000 + 0 399 1\n200\t1\t0\n199\n\n\
+                               chain 50 chrT 1000 + 500 600 chrR 1000 + 0 100 2\n100\n\n";
+
+    /// Lifted line or unmap reason of every feature.
+    fn lift_features(lines: &[&str]) -> Vec<std::result::Result<String, GffUnmapReason>> {
+        let (lapper_hashmap, _) = lapper_hashmap_from_str(INDEL_CHAIN).unwrap();
+        let mut gff_lines: Vec<GffLine> = lines
+            .iter()
+            .map(|line| {
+                let gff_rcd = GffRecord::from_line(line).unwrap();
+                let lifted = lift_gff_record(&gff_rcd, &lapper_hashmap);
+                GffLine::Feature(Box::new((gff_rcd, lifted)))
+            })
+            .collect();
+        let format = match gff_lines.first() {
+            Some(GffLine::Feature(feature)) => feature.0.format(),
+            _ => GffFormat::Gff3,
+        };
+        reject_orphans(&mut gff_lines, format);
+        gff_lines
+            .into_iter()
+            .map(|x| match x {
+                GffLine::Feature(feature) => feature.1.map(|x| x.to_string()),
+                GffLine::Comment(line) => Ok(line),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn features_across_an_indel() {
+        let lifted = lift_features(&[
+            "chrT\tt\tgene\t101\t300\t.\t+\t.\tID=g1",
+            "chrT\tt\tmRNA\t101\t300\t.\t+\t.\tID=t1;Parent=g1",
+            "chrT\tt\texon\t101\t150\t.\t+\t.\tParent=t1",
+            "chrT\tt\texon\t250\t300\t.\t+\t.\tParent=t1",
+            "chrT\tt\tgene\t391\t410\t.\t-\t.\tID=g3",
+            "chrT\tt\tgene\t451\t460\t.\t-\t.\tID=g4",
+        ]);
+        assert_eq!(
+            lifted,
+            [
+                Ok("chrQ\tt\tgene\t101\t299\t.\t+\t.\tID=g1".to_string()),
+                Ok("chrQ\tt\tmRNA\t101\t299\t.\t+\t.\tID=t1;Parent=g1".to_string()),
+                Ok("chrQ\tt\texon\t101\t150\t.\t+\t.\tParent=t1".to_string()),
+                Ok("chrQ\tt\texon\t249\t299\t.\t+\t.\tParent=t1".to_string()),
+                Err(GffUnmapReason::PartialMap),
+                Err(GffUnmapReason::Unmap),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_parent_unmaps_its_subtree() {
+        let lifted = lift_features(&[
+            "chrT\tt\texon\t351\t360\t.\t+\t.\tParent=t2",
+            "chrT\tt\tgene\t351\t550\t.\t+\t.\tID=g2",
+            "chrT\tt\tmRNA\t351\t380\t.\t+\t.\tID=t2;Parent=g2",
+            "chrT\tt\tgene\t511\t520\t.\t+\t.\tID=g5",
+        ]);
+        assert_eq!(
+            lifted,
+            [
+                Err(GffUnmapReason::ParentUnmapped),
+                Err(GffUnmapReason::Split),
+                Err(GffUnmapReason::ParentUnmapped),
+                Ok("chrR\tt\tgene\t11\t20\t.\t+\t.\tID=g5".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn gtf_transcript_follows_its_exons() {
+        let lifted = lift_features(&[
+            "chrT\tt\texon\t101\t150\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\";",
+            "chrT\tt\texon\t511\t520\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\";",
+        ]);
+        assert_eq!(
+            lifted,
+            [
+                Ok(
+                    "chrQ\tt\texon\t101\t150\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\";"
+                        .to_string()
+                ),
+                Err(GffUnmapReason::ParentMismatch),
+            ]
+        );
+    }
+}
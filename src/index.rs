@@ -0,0 +1,393 @@
+use crate::{
+    error::{CrussmapError, Result},
+    interval::{lapper_hashmap_from_reader, Block, BlockIvl, LapperHashMap},
+    parser::Strand,
+    utils::{get_input_reader, input_files_exist, outfile_exist},
+};
+use bincode::Options;
+use crc32fast::Hasher;
+use log::info;
+use memmap2::Mmap;
+use rust_lapper::Lapper;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+/// First bytes of a chain index file.
+pub const INDEX_MAGIC: &[u8; 8] = b"CRUSSIDX";
+/// Bumped whenever the serialized layout changes.
+const INDEX_VERSION: u32 = 4;
+/// Size of [`IndexHeader`] once serialized.
+const HEADER_LEN: usize = 24;
+
+#[derive(Serialize, Deserialize)]
+struct IndexHeader {
+    magic: [u8; 8],
+    version: u32,
+    source_checksum: u32,
+    source_len: u64,
+}
+
+/// Fields shared by all blocks of a chain, stored once in the index file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct IndexChain {
+    chain_id: usize,
+    score: f64,
+    /// query chromosome, position in [`IndexBody::names`]
+    query: u32,
+    strand: Strand,
+}
+
+/// One block of a target chromosome, both intervals are `size` long.
+#[derive(Serialize, Deserialize)]
+struct IndexBlock {
+    start: usize,
+    query_start: usize,
+    size: usize,
+    /// position in [`IndexBody::chains`]
+    chain: u32,
+    block_index: usize,
+}
+
+/// Everything after the header, written with variable length integers.
+#[derive(Serialize, Deserialize)]
+struct IndexBody {
+    /// chromosome names of both assemblies
+    names: Vec<String>,
+    /// query chromosomes with their size, in order of first appearance
+    query_sizes: Vec<(u32, usize)>,
+    chains: Vec<IndexChain>,
+    /// blocks of every target chromosome sorted by start
+    targets: Vec<(u32, Vec<IndexBlock>)>,
+}
+
+/// Position of `name` in `names`, added when missing.
+fn name_id(names: &mut Vec<String>, ids: &mut HashMap<String, u32>, name: &str) -> u32 {
+    match ids.get(name) {
+        Some(id) => *id,
+        None => {
+            let id = names.len() as u32;
+            names.push(name.to_string());
+            ids.insert(name.to_string(), id);
+            id
+        }
+    }
+}
+
+impl IndexBody {
+    fn new(lapper_hashmap: &LapperHashMap, query_sizes: &[(String, usize)]) -> Self {
+        let mut names: Vec<String> = Vec::new();
+        let mut name_ids: HashMap<String, u32> = HashMap::new();
+        let query_sizes = query_sizes
+            .iter()
+            .map(|(name, size)| (name_id(&mut names, &mut name_ids, name), *size))
+            .collect();
+        let mut chains: Vec<IndexChain> = Vec::new();
+        let mut chain_ids: HashMap<(usize, u64, u32, bool), u32> = HashMap::new();
+        let mut chroms: Vec<&String> = lapper_hashmap.keys().collect();
+        chroms.sort();
+        let mut targets = Vec::with_capacity(chroms.len());
+        for chrom in chroms {
+            let target = name_id(&mut names, &mut name_ids, chrom);
+            let blocks = lapper_hashmap[chrom]
+                .iter()
+                .map(|ivl| {
+                    let block = &ivl.val;
+                    let chain = IndexChain {
+                        chain_id: block.chain_id,
+                        score: block.score,
+                        query: name_id(&mut names, &mut name_ids, &block.name),
+                        strand: block.strand,
+                    };
+                    let key = (
+                        chain.chain_id,
+                        chain.score.to_bits(),
+                        chain.query,
+                        chain.strand == Strand::Positive,
+                    );
+                    let chain = *chain_ids.entry(key).or_insert_with(|| {
+                        chains.push(chain);
+                        chains.len() as u32 - 1
+                    });
+                    IndexBlock {
+                        start: ivl.start,
+                        query_start: block.start,
+                        size: ivl.stop - ivl.start,
+                        chain,
+                        block_index: block.block_index,
+                    }
+                })
+                .collect();
+            targets.push((target, blocks));
+        }
+        IndexBody {
+            names,
+            query_sizes,
+            chains,
+            targets,
+        }
+    }
+
+    /// Rebuild the interval trees and query sizes.
+    fn into_lappers(self) -> Result<(LapperHashMap, Vec<(String, usize)>)> {
+        let names = &self.names;
+        let name = |id: u32| -> Result<&String> {
+            names
+                .get(id as usize)
+                .ok_or_else(|| CrussmapError::Index(format!("invalid name id {}", id)))
+        };
+        let chain_names = self
+            .chains
+            .iter()
+            .map(|x| name(x.query))
+            .collect::<Result<Vec<&String>>>()?;
+        let mut lapper_hashmap = LapperHashMap::with_capacity(self.targets.len());
+        for (target, blocks) in &self.targets {
+            if let Some(x) = blocks
+                .iter()
+                .find(|x| x.chain as usize >= self.chains.len())
+            {
+                return Err(CrussmapError::Index(format!(
+                    "invalid chain id {}",
+                    x.chain
+                )));
+            }
+            let ivls = blocks
+                .iter()
+                .map(|x| {
+                    let chain = &self.chains[x.chain as usize];
+                    BlockIvl {
+                        start: x.start,
+                        stop: x.start + x.size,
+                        val: Block {
+                            name: chain_names[x.chain as usize].clone(),
+                            start: x.query_start,
+                            end: x.query_start + x.size,
+                            strand: chain.strand,
+                            chain_id: chain.chain_id,
+                            score: chain.score,
+                            block_index: x.block_index,
+                        },
+                    }
+                })
+                .collect();
+            lapper_hashmap.insert(name(*target)?.clone(), Lapper::new(ivls));
+        }
+        let query_sizes = self
+            .query_sizes
+            .iter()
+            .map(|(id, size)| Ok((name(*id)?.clone(), *size)))
+            .collect::<Result<_>>()?;
+        Ok((lapper_hashmap, query_sizes))
+    }
+}
+
+/// Interval trees of a chain file, written by the `index` subcommand so that lifting
+/// jobs load them instead of parsing the chain again.
+pub struct ChainIndex {
+    /// CRC32 of the plain (decompressed) chain text the index was built from
+    pub source_checksum: u32,
+    /// length in bytes of the plain chain text
+    pub source_len: u64,
+    pub lapper_hashmap: LapperHashMap,
+    pub query_sizes: Vec<(String, usize)>,
+}
+
+/// Reader computing the CRC32 and length of everything read through it.
+struct ChecksumReader<R: Read> {
+    inner: R,
+    hasher: Hasher,
+    len: u64,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    fn new(inner: R) -> Self {
+        ChecksumReader {
+            inner,
+            hasher: Hasher::new(),
+            len: 0,
+        }
+    }
+
+    /// CRC32 and length of everything read so far.
+    fn finish(self) -> (u32, u64) {
+        (self.hasher.finalize(), self.len)
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.len += n as u64;
+        Ok(n)
+    }
+}
+
+impl ChainIndex {
+    /// Build the index of plain chain text, checksummed while it is parsed.
+    pub fn from_chain_reader<R: Read>(reader: R) -> Result<Self> {
+        let mut reader = ChecksumReader::new(reader);
+        let (lapper_hashmap, query_sizes) =
+            lapper_hashmap_from_reader(BufReader::new(&mut reader))?;
+        let (source_checksum, source_len) = reader.finish();
+        Ok(ChainIndex {
+            source_checksum,
+            source_len,
+            lapper_hashmap,
+            query_sizes,
+        })
+    }
+
+    /// Load an index file through a memory map, which saves copying it into a buffer
+    /// before deserializing.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the map is only read while deserializing; index files are written
+        // once by the `index` subcommand and not modified in place
+        let mmap = unsafe { Mmap::map(&file)? };
+        Self::from_bytes(&mmap)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let header = read_header(data)?;
+        let body: IndexBody = bincode::DefaultOptions::new().deserialize(&data[HEADER_LEN..])?;
+        let (lapper_hashmap, query_sizes) = body.into_lappers()?;
+        Ok(ChainIndex {
+            source_checksum: header.source_checksum,
+            source_len: header.source_len,
+            lapper_hashmap,
+            query_sizes,
+        })
+    }
+
+    /// Whether the index was built from the plain chain text read from `reader`.
+    pub fn matches_chain<R: Read>(&self, reader: R) -> Result<bool> {
+        Ok(chain_checksum(reader)? == (self.source_checksum, self.source_len))
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<()> {
+        let header = IndexHeader {
+            magic: *INDEX_MAGIC,
+            version: INDEX_VERSION,
+            source_checksum: self.source_checksum,
+            source_len: self.source_len,
+        };
+        bincode::serialize_into(&mut writer, &header)?;
+        let body = IndexBody::new(&self.lapper_hashmap, &self.query_sizes);
+        bincode::DefaultOptions::new().serialize_into(&mut writer, &body)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Check the magic and version of the header at the start of `data`.
+fn read_header(data: &[u8]) -> Result<IndexHeader> {
+    if !data.starts_with(INDEX_MAGIC) {
+        return Err(CrussmapError::Index("not a chain index file".to_string()));
+    }
+    let header: IndexHeader = bincode::deserialize(data)?;
+    if header.version != INDEX_VERSION {
+        return Err(CrussmapError::Index(format!(
+            "chain index version {} is not supported, rebuild it with `crussmap index`",
+            header.version
+        )));
+    }
+    Ok(header)
+}
+
+/// CRC32 and length of the plain chain text read from `reader`.
+fn chain_checksum<R: Read>(reader: R) -> Result<(u32, u64)> {
+    let mut reader = ChecksumReader::new(reader);
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+    Ok(reader.finish())
+}
+
+/// Whether `path` is a chain index file rather than a chain file.
+pub fn is_chain_index<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let mut magic = Vec::with_capacity(INDEX_MAGIC.len());
+    File::open(path)?
+        .take(INDEX_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    Ok(magic == INDEX_MAGIC)
+}
+
+/// Check that the index at `index_path` was built from the chain read from `input`,
+/// only its header is read.
+fn check_index(input: &Option<String>, index_path: &String) -> Result<()> {
+    input_files_exist(index_path)?;
+    let mut data = Vec::with_capacity(HEADER_LEN);
+    File::open(index_path)?
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut data)?;
+    let header = read_header(&data)?;
+    let (checksum, len) = chain_checksum(get_input_reader(input)?)?;
+    if (checksum, len) != (header.source_checksum, header.source_len) {
+        return Err(CrussmapError::Index(format!(
+            "{} was built from another chain (crc32 {:08x}, {} bytes) than the input (crc32 {:08x}, {} bytes), rebuild it",
+            index_path, header.source_checksum, header.source_len, checksum, len
+        )));
+    }
+    info!("{} is up to date, crc32 {:08x}", index_path, checksum);
+    Ok(())
+}
+
+pub fn index_chain(
+    input: &Option<String>,
+    output: &String,
+    check: bool,
+    rewrite: bool,
+) -> Result<()> {
+    if check {
+        return check_index(input, output);
+    }
+    outfile_exist(output, rewrite)?;
+    let index = ChainIndex::from_chain_reader(get_input_reader(input)?)?;
+    info!(
+        "index {} bytes of chain, crc32 {:08x}",
+        index.source_len, index.source_checksum
+    );
+    // written as is, compression would defeat the memory map
+    index.write(BufWriter::new(File::create(output)?))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+
+    #[test]
+    fn index_round_trip_and_staleness() {
+        let path = format!("{}/data/test.chain", env!("CARGO_MANIFEST_DIR"));
+        let chain = std::fs::read(&path).unwrap();
+        let index = ChainIndex::from_chain_reader(chain.as_slice()).unwrap();
+        let mut bytes: Vec<u8> = Vec::new();
+        index.write(&mut bytes).unwrap();
+
+        let loaded = ChainIndex::from_bytes(&bytes).unwrap();
+        assert!(loaded.matches_chain(chain.as_slice()).unwrap());
+        assert!(!loaded.matches_chain(&chain[..chain.len() - 1]).unwrap());
+        assert!(ChainIndex::from_bytes(&chain).is_err());
+    }
+
+    #[test]
+    fn index_keeps_every_block() {
+        let path = format!("{}/data/v4_to_v5.chain.gz", env!("CARGO_MANIFEST_DIR"));
+        let reader = MultiGzDecoder::new(File::open(path).unwrap());
+        let index = ChainIndex::from_chain_reader(reader).unwrap();
+        let mut bytes: Vec<u8> = Vec::new();
+        index.write(&mut bytes).unwrap();
+
+        let loaded = ChainIndex::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.source_checksum, index.source_checksum);
+        assert_eq!(loaded.query_sizes, index.query_sizes);
+        assert_eq!(loaded.lapper_hashmap.len(), index.lapper_hashmap.len());
+        for (chrom, lapper) in &index.lapper_hashmap {
+            assert_eq!(loaded.lapper_hashmap[chrom].intervals, lapper.intervals);
+        }
+    }
+}
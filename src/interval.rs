@@ -1,21 +1,30 @@
 use crate::{
-    parser::{ChainRecords, Strand},
-    utils::get_data_from_input,
+    error::Result,
+    index::{is_chain_index, ChainIndex},
+    parser::{ChainReader, Strand},
+    utils::{get_input_reader, input_files_exist},
 };
-use log::{error, warn};
+use log::{error, info, warn};
 use rust_lapper::{Interval, Lapper};
 use std::{
     cmp::{max, min},
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt,
+    io::BufRead,
 };
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Block {
     pub name: String,
     pub start: usize,
     pub end: usize,
     pub strand: Strand,
+    /// id of the chain the block comes from
+    pub chain_id: usize,
+    /// score of the chain the block comes from
+    pub score: f64,
+    /// 0-based position of the block in its chain
+    pub block_index: usize,
 }
 
 impl Eq for Block {}
@@ -40,6 +49,9 @@ impl fmt::Display for Region<'_> {
 
 pub type BlockIvl = Interval<usize, Block>;
 
+/// Per target chromosome interval tree of chain blocks.
+pub type LapperHashMap = HashMap<String, Lapper<usize, Block>>;
+
 pub fn get_block_ivl(block_target: Block, block_query: Block) -> BlockIvl {
     BlockIvl {
         start: block_target.start,
@@ -54,21 +66,85 @@ pub fn get_block_ivl(block_target: Block, block_query: Block) -> BlockIvl {
 ///
 /// * `input`:
 ///
-/// returns: HashMap<String, Lapper<usize, Block>, RandomState>
+/// returns: Result<HashMap<String, Lapper<usize, Block>, RandomState>>
 ///
 /// # Examples
 ///
 /// ```
 ///
 /// ```
-pub fn get_lapper_hashmap(input: &Option<String>) -> HashMap<String, Lapper<usize, Block>> {
-    let data = get_data_from_input(input);
-    let chain_record_iter = ChainRecords(&data);
+pub fn get_lapper_hashmap(input: &Option<String>) -> Result<LapperHashMap> {
+    Ok(get_lapper_hashmap_with_sizes(input)?.0)
+}
+
+/// Same as [`get_lapper_hashmap`], but also returns the name and size of every
+/// query (new assembly) sequence, in order of first appearance in the chain file.
+pub fn get_lapper_hashmap_with_sizes(
+    input: &Option<String>,
+) -> Result<(LapperHashMap, Vec<(String, usize)>)> {
+    if let Some(path) = input {
+        input_files_exist(path)?;
+        if is_chain_index(path)? {
+            let index = ChainIndex::from_path(path)?;
+            info!(
+                "load index of {} bytes of chain, crc32 {:08x}",
+                index.source_len, index.source_checksum
+            );
+            return Ok((index.lapper_hashmap, index.query_sizes));
+        }
+    }
+    lapper_hashmap_from_reader(get_input_reader(input)?)
+}
+
+/// Span of every chain on each target (old assembly) chromosome, from its first to
+/// its last block, with the chain id as value.
+pub fn get_chain_spans(lapper_hashmap: &LapperHashMap) -> HashMap<String, Lapper<usize, usize>> {
+    lapper_hashmap
+        .iter()
+        .map(|(chrom, lapper)| {
+            let mut spans: HashMap<usize, (usize, usize)> = HashMap::new();
+            for block_ivl in lapper.iter() {
+                spans
+                    .entry(block_ivl.val.chain_id)
+                    .and_modify(|x| *x = (min(x.0, block_ivl.start), max(x.1, block_ivl.stop)))
+                    .or_insert((block_ivl.start, block_ivl.stop));
+            }
+            let spans = spans
+                .into_iter()
+                .map(|(chain_id, (start, stop))| Interval {
+                    start,
+                    stop,
+                    val: chain_id,
+                })
+                .collect();
+            (chrom.clone(), Lapper::new(spans))
+        })
+        .collect()
+}
+
+/// Build the interval trees and query sizes of [`get_lapper_hashmap_with_sizes`]
+/// from chain text held in memory.
+pub fn lapper_hashmap_from_str(data: &str) -> Result<(LapperHashMap, Vec<(String, usize)>)> {
+    lapper_hashmap_from_reader(data.as_bytes())
+}
+
+/// Build the interval trees and query sizes of [`get_lapper_hashmap_with_sizes`]
+/// from a stream of plain chain text.
+pub fn lapper_hashmap_from_reader<R: BufRead>(
+    reader: R,
+) -> Result<(LapperHashMap, Vec<(String, usize)>)> {
+    let chain_record_iter = ChainReader::new(reader);
     let mut chrom_ivls_hashmap: HashMap<String, Lapper<usize, Block>> = HashMap::new();
     let mut chrom_ivls_vec_hashmap: HashMap<String, Vec<Interval<usize, Block>>> = HashMap::new();
+    let mut query_sizes: Vec<(String, usize)> = Vec::new();
+    let mut seen_query: HashSet<String> = HashSet::new();
     for chain_record in chain_record_iter {
-        let chain_record = chain_record.unwrap();
+        let chain_record = chain_record?;
         let target_chrom = chain_record.header.target.name;
+        let query = chain_record.header.query;
+        if seen_query.insert(query.name.clone()) {
+            query_sizes.push((query.name, query.size));
+        }
         let block_ivls = chain_record.block_ivls;
         // combine interval vecs when target_chroms are same:
         if chrom_ivls_vec_hashmap.contains_key(&target_chrom) {
@@ -82,7 +158,7 @@ pub fn get_lapper_hashmap(input: &Option<String>) -> HashMap<String, Lapper<usiz
         let lapper = Lapper::new(ivls);
         chrom_ivls_hashmap.insert(chrom, lapper);
     }
-    chrom_ivls_hashmap
+    Ok((chrom_ivls_hashmap, query_sizes))
 }
 
 fn intersect_two_region(
@@ -99,6 +175,9 @@ fn intersect_two_region(
     Some((final_start, final_end))
 }
 
+/// Pieces of `q_region` covered by chain blocks, as pairs of the source piece (even
+/// index) followed by the converted one (odd index). See [`crate::liftover::LiftOver`]
+/// for owned results with the chain id.
 pub fn find_in_lapper<'a>(
     lapper_hashmap: &'a HashMap<String, Lapper<usize, Block>>,
     q_region: &Region<'a>,
@@ -123,7 +202,7 @@ pub fn find_in_lapper<'a>(
         let target_region = Region {
             chrom: &target.val.name,
             start: target.val.start,
-            end: target.val.start,
+            end: target.val.end,
             strand: target.val.strand,
         };
         let (real_start, real_end) =
@@ -1,9 +1,29 @@
 #[macro_use]
 extern crate serde_derive;
 
+pub mod bam;
 pub mod bed;
+pub mod bedgraph;
+pub mod bgzf;
+pub mod compose;
+pub mod delta;
+pub mod error;
+pub mod fasta;
+pub mod filter;
+pub mod gff;
+pub mod index;
 pub mod interval;
+pub mod liftover;
 pub mod log;
+pub mod maf;
+pub mod paf;
 pub mod parser;
+pub mod region;
+pub mod sam;
+pub mod stats;
+pub mod swap;
+pub mod tabix;
 pub mod utils;
+pub mod validate;
+pub mod vcf;
 pub mod view;
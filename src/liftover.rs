@@ -0,0 +1,412 @@
+use crate::{
+    error::{CrussmapError, Result},
+    index::{is_chain_index, ChainIndex},
+    interval::{lapper_hashmap_from_reader, lapper_hashmap_from_str, LapperHashMap},
+    parser::Strand,
+    utils::decompress_reader,
+};
+use clap::ValueEnum;
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, Read},
+    path::Path,
+};
+
+/// Genomic interval, 0-based and half-open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenomicRange {
+    pub chrom: String,
+    pub start: usize,
+    pub end: usize,
+    pub strand: Strand,
+}
+
+impl fmt::Display for GenomicRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}",
+            self.chrom, self.start, self.end, self.strand
+        )
+    }
+}
+
+/// Part of a converted interval covered by one chain block: `source` is the covered
+/// sub-interval in the old assembly, `lifted` where it lands in the new one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappedPiece {
+    pub source: GenomicRange,
+    pub lifted: GenomicRange,
+    pub chain_id: usize,
+    /// score of the chain
+    pub score: f64,
+    /// 0-based position of the block in its chain, the first one for merged pieces
+    pub block_index: usize,
+}
+
+/// Which pieces to keep when an interval maps through more than one chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum MultiMapPolicy {
+    /// pieces of every chain
+    #[default]
+    All,
+    /// pieces of the chain with the highest score
+    BestScore,
+    /// pieces of the chain covering most bases of the interval
+    Longest,
+    /// no piece, the interval is left unmapped
+    UniqueOnly,
+}
+
+impl MultiMapPolicy {
+    /// Keep the pieces allowed by the policy, `None` if the interval should be left
+    /// unmapped. Ties go to the chain whose first piece comes first.
+    pub fn select(&self, pieces: Vec<MappedPiece>) -> Option<Vec<MappedPiece>> {
+        // (chain id, score, covered bases) in order of first piece
+        let mut chains: Vec<(usize, f64, usize)> = Vec::new();
+        for piece in &pieces {
+            let size = piece.source.end - piece.source.start;
+            match chains.iter_mut().find(|x| x.0 == piece.chain_id) {
+                Some(chain) => chain.2 += size,
+                None => chains.push((piece.chain_id, piece.score, size)),
+            }
+        }
+        if chains.len() < 2 || *self == MultiMapPolicy::All {
+            return Some(pieces);
+        }
+        let mut best = chains[0];
+        for chain in &chains[1..] {
+            let better = match self {
+                MultiMapPolicy::BestScore => chain.1 > best.1,
+                _ => chain.2 > best.2,
+            };
+            if better {
+                best = *chain;
+            }
+        }
+        match self {
+            MultiMapPolicy::UniqueOnly => None,
+            _ => Some(
+                pieces
+                    .into_iter()
+                    .filter(|x| x.chain_id == best.0)
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// Converted position in the new assembly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappedPoint {
+    pub chrom: String,
+    pub pos: usize,
+    pub strand: Strand,
+    pub chain_id: usize,
+    /// score of the chain
+    pub score: f64,
+    /// 0-based position of the block in its chain
+    pub block_index: usize,
+}
+
+/// Chain file loaded for converting coordinates from its target (old) assembly to
+/// its query (new) assembly.
+pub struct LiftOver {
+    lapper_hashmap: LapperHashMap,
+    query_sizes: Vec<(String, usize)>,
+}
+
+impl LiftOver {
+    /// Load a chain file, or an index written by the `index` subcommand.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        if is_chain_index(&path)? {
+            return Ok(Self::from_index(ChainIndex::from_path(path)?));
+        }
+        Self::from_reader(File::open(path)?)
+    }
+
+    pub fn from_index(index: ChainIndex) -> Self {
+        LiftOver {
+            lapper_hashmap: index.lapper_hashmap,
+            query_sizes: index.query_sizes,
+        }
+    }
+
+    /// Load a plain or compressed chain file, see [`decompress_reader`].
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let reader = BufReader::new(decompress_reader(reader)?);
+        let (lapper_hashmap, query_sizes) = lapper_hashmap_from_reader(reader)?;
+        Ok(LiftOver {
+            lapper_hashmap,
+            query_sizes,
+        })
+    }
+
+    pub fn from_chain_str(data: &str) -> Result<Self> {
+        let (lapper_hashmap, query_sizes) = lapper_hashmap_from_str(data)?;
+        Ok(LiftOver {
+            lapper_hashmap,
+            query_sizes,
+        })
+    }
+
+    /// Per old assembly chromosome interval trees of chain blocks.
+    pub fn lapper_hashmap(&self) -> &LapperHashMap {
+        &self.lapper_hashmap
+    }
+
+    /// Name and size of the new assembly sequences, in order of first appearance.
+    pub fn query_sizes(&self) -> &[(String, usize)] {
+        &self.query_sizes
+    }
+
+    /// Whether `chrom` of the old assembly is covered by any chain.
+    pub fn contains_chrom(&self, chrom: &str) -> bool {
+        self.lapper_hashmap.contains_key(chrom)
+    }
+
+    /// Convert a 0-based position, one result per chain block covering it.
+    pub fn map_point(&self, chrom: &str, pos: usize) -> Vec<MappedPoint> {
+        map_pieces(&self.lapper_hashmap, chrom, pos, pos + 1, Strand::Positive)
+            .into_iter()
+            .map(|x| MappedPoint {
+                chrom: x.lifted.chrom,
+                pos: x.lifted.start,
+                strand: x.lifted.strand,
+                chain_id: x.chain_id,
+                score: x.score,
+                block_index: x.block_index,
+            })
+            .collect()
+    }
+
+    /// Convert a positive strand interval, see [`LiftOver::map_region_with_strand`].
+    pub fn map_interval(&self, chrom: &str, start: usize, end: usize) -> Result<Vec<MappedPiece>> {
+        self.map_region_with_strand(chrom, start, end, Strand::Positive)
+    }
+
+    /// Convert an interval into one piece per chain block it overlaps, in order of
+    /// the old assembly. Returns no piece if nothing of it is covered by the chains.
+    pub fn map_region_with_strand(
+        &self,
+        chrom: &str,
+        start: usize,
+        end: usize,
+        strand: Strand,
+    ) -> Result<Vec<MappedPiece>> {
+        if start > end {
+            return Err(CrussmapError::Mapping(format!(
+                "start {} larger than end {} in {}",
+                start, end, chrom
+            )));
+        }
+        Ok(map_pieces(&self.lapper_hashmap, chrom, start, end, strand))
+    }
+}
+
+/// Pieces of `[start, end)` on `chrom` covered by the blocks of `lapper_hashmap`, in
+/// order of the old assembly. An empty interval inside a block gives an empty piece.
+pub fn map_pieces(
+    lapper_hashmap: &LapperHashMap,
+    chrom: &str,
+    start: usize,
+    end: usize,
+    strand: Strand,
+) -> Vec<MappedPiece> {
+    let lapper = match lapper_hashmap.get(chrom) {
+        Some(lapper) => lapper,
+        None => return Vec::new(),
+    };
+    let mut pieces: Vec<MappedPiece> = Vec::new();
+    for block_ivl in lapper.find(start, end) {
+        let piece_start = start.max(block_ivl.start);
+        let piece_end = end.min(block_ivl.stop);
+        if piece_start > piece_end {
+            continue;
+        }
+        let block = &block_ivl.val;
+        let offset = piece_start - block_ivl.start;
+        let size = piece_end - piece_start;
+        let lifted_start = match block.strand {
+            Strand::Positive => block.start + offset,
+            Strand::Negative => block.end - offset - size,
+        };
+        let lifted_strand = match strand {
+            Strand::Positive => block.strand,
+            Strand::Negative => block.strand.reverse(),
+        };
+        pieces.push(MappedPiece {
+            source: GenomicRange {
+                chrom: chrom.to_string(),
+                start: piece_start,
+                end: piece_end,
+                strand,
+            },
+            lifted: GenomicRange {
+                chrom: block.name.clone(),
+                start: lifted_start,
+                end: lifted_start + size,
+                strand: lifted_strand,
+            },
+            chain_id: block.chain_id,
+            score: block.score,
+            block_index: block.block_index,
+        });
+    }
+    pieces
+}
+
+/// Join pieces from the same chain, chromosome and strand into one interval spanning
+/// them, like UCSC liftOver does. Two pieces are joined only if the gap between them
+/// in either assembly is at most `max_gap`, when given.
+pub fn merge_pieces(pieces: Vec<MappedPiece>, max_gap: Option<usize>) -> Vec<MappedPiece> {
+    let mut merged: Vec<MappedPiece> = Vec::new();
+    for piece in pieces {
+        let last = merged.iter_mut().rev().find(|x| {
+            x.chain_id == piece.chain_id
+                && x.lifted.chrom == piece.lifted.chrom
+                && x.lifted.strand == piece.lifted.strand
+        });
+        if let Some(last) = last {
+            let source_gap = piece.source.start.saturating_sub(last.source.end);
+            let lifted_gap = match last.lifted.start < piece.lifted.start {
+                true => piece.lifted.start.saturating_sub(last.lifted.end),
+                false => last.lifted.start.saturating_sub(piece.lifted.end),
+            };
+            if max_gap.is_none_or(|gap| source_gap.max(lifted_gap) <= gap) {
+                last.source.end = last.source.end.max(piece.source.end);
+                last.lifted.start = last.lifted.start.min(piece.lifted.start);
+                last.lifted.end = last.lifted.end.max(piece.lifted.end);
+                continue;
+            }
+        }
+        merged.push(piece);
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// chrT [10, 60) to the negative strand of chrQ, in blocks [10, 20), [25, 45)
+    /// and [45, 60).
+    const NEGATIVE_CHAIN: &str =
+        "chain 100 chrT 100 + 10 60 chrQ 80 - 5 55 7\n10\t5\t0\n20\t0\t5\n15\n\n";
+
+    fn range(chrom: &str, start: usize, end: usize, strand: Strand) -> GenomicRange {
+        GenomicRange {
+            chrom: chrom.to_string(),
+            start,
+            end,
+            strand,
+        }
+    }
+
+    /// Piece of chain `chain_id` from chrT `source` to chrQ `lifted`.
+    fn piece(
+        chain_id: usize,
+        score: f64,
+        source: (usize, usize),
+        lifted: (usize, usize, Strand),
+    ) -> MappedPiece {
+        MappedPiece {
+            source: range("chrT", source.0, source.1, Strand::Positive),
+            lifted: range("chrQ", lifted.0, lifted.1, lifted.2),
+            chain_id,
+            score,
+            block_index: source.0,
+        }
+    }
+
+    #[test]
+    fn negative_strand_pieces() {
+        let (lapper_hashmap, _) = lapper_hashmap_from_str(NEGATIVE_CHAIN).unwrap();
+        let pieces = map_pieces(&lapper_hashmap, "chrT", 12, 30, Strand::Positive);
+        let lifted: Vec<(GenomicRange, usize)> = pieces
+            .iter()
+            .map(|x| (x.lifted.clone(), x.block_index))
+            .collect();
+        assert_eq!(
+            lifted,
+            vec![
+                (range("chrQ", 65, 73, Strand::Negative), 0),
+                (range("chrQ", 60, 65, Strand::Negative), 1),
+            ]
+        );
+        assert_eq!(pieces[0].source, range("chrT", 12, 20, Strand::Positive));
+        assert_eq!(pieces[1].source, range("chrT", 25, 30, Strand::Positive));
+        assert!(pieces.iter().all(|x| x.chain_id == 7 && x.score == 100.0));
+
+        // a negative strand interval lands on the positive strand
+        let pieces = map_pieces(&lapper_hashmap, "chrT", 50, 60, Strand::Negative);
+        assert_eq!(pieces.len(), 1);
+        assert_eq!(pieces[0].lifted, range("chrQ", 25, 35, Strand::Positive));
+
+        // the gap between blocks and other chromosomes map nowhere
+        assert!(map_pieces(&lapper_hashmap, "chrT", 20, 25, Strand::Positive).is_empty());
+        assert!(map_pieces(&lapper_hashmap, "chrQ", 10, 20, Strand::Positive).is_empty());
+    }
+
+    #[test]
+    fn merge_same_chain_pieces() {
+        let pieces = vec![
+            piece(1, 10.0, (0, 10), (100, 110, Strand::Positive)),
+            piece(2, 10.0, (12, 14), (500, 502, Strand::Positive)),
+            piece(1, 10.0, (15, 20), (118, 123, Strand::Positive)),
+        ];
+        let merged = merge_pieces(pieces.clone(), None);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].source, range("chrT", 0, 20, Strand::Positive));
+        assert_eq!(merged[0].lifted, range("chrQ", 100, 123, Strand::Positive));
+        assert_eq!(merged[0].block_index, 0);
+        assert_eq!(merged[1], pieces[1]);
+
+        // gaps are 5 bases in the old assembly and 8 in the new one
+        assert_eq!(merge_pieces(pieces.clone(), Some(8)).len(), 2);
+        assert_eq!(merge_pieces(pieces, Some(7)).len(), 3);
+    }
+
+    #[test]
+    fn merge_negative_strand_pieces() {
+        let pieces = vec![
+            piece(1, 10.0, (0, 10), (100, 110, Strand::Negative)),
+            piece(1, 10.0, (12, 17), (90, 95, Strand::Negative)),
+            piece(1, 10.0, (17, 20), (87, 90, Strand::Positive)),
+        ];
+        let merged = merge_pieces(pieces.clone(), Some(5));
+        assert_eq!(merged.len(), 2);
+        assert_eq!(merged[0].source, range("chrT", 0, 17, Strand::Positive));
+        assert_eq!(merged[0].lifted, range("chrQ", 90, 110, Strand::Negative));
+        assert_eq!(merged[1], pieces[2]);
+        assert_eq!(merge_pieces(pieces, Some(4)).len(), 3);
+    }
+
+    #[test]
+    fn multi_map_policies() {
+        let pieces = vec![
+            piece(1, 10.0, (0, 3), (100, 103, Strand::Positive)),
+            piece(2, 50.0, (0, 4), (200, 204, Strand::Positive)),
+            piece(3, 50.0, (0, 5), (300, 305, Strand::Negative)),
+            piece(1, 10.0, (10, 12), (110, 112, Strand::Positive)),
+        ];
+        let chain_ids = |policy: MultiMapPolicy| {
+            policy
+                .select(pieces.clone())
+                .map(|x| x.iter().map(|x| x.chain_id).collect::<Vec<_>>())
+        };
+        assert_eq!(chain_ids(MultiMapPolicy::All), Some(vec![1, 2, 3, 1]));
+        // chains 2 and 3 tie on score, chains 1 and 3 on covered bases
+        assert_eq!(chain_ids(MultiMapPolicy::BestScore), Some(vec![2]));
+        assert_eq!(chain_ids(MultiMapPolicy::Longest), Some(vec![1, 1]));
+        assert_eq!(chain_ids(MultiMapPolicy::UniqueOnly), None);
+
+        // a single chain is kept by every policy
+        let single = vec![pieces[0].clone(), pieces[3].clone()];
+        for policy in MultiMapPolicy::value_variants() {
+            assert_eq!(policy.select(single.clone()), Some(single.clone()));
+            assert_eq!(policy.select(Vec::new()), Some(Vec::new()));
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use crate::{
+    error::Result,
+    parser::{alignments_from_blocks, write_chain, Header, SeqInfo, Strand},
+    utils::{get_file_reader, get_output_writer},
+};
+use log::{info, warn};
+use std::io::{self, BufRead, BufReader, Write};
+
+/// An `s` line of a MAF alignment block.
+#[derive(Debug, Clone)]
+pub struct MafSeq {
+    pub species: String,
+    pub chrom: String,
+    /// 0-based start on `strand`
+    pub start: usize,
+    pub size: usize,
+    pub strand: Strand,
+    pub src_size: usize,
+    pub text: Vec<u8>,
+}
+
+impl MafSeq {
+    pub fn from_line(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 7 || fields[0] != "s" {
+            return None;
+        }
+        // `species.chrom`, chrom names may contain dots as well
+        let (species, chrom) = fields[1].split_once('.').unwrap_or((fields[1], fields[1]));
+        Some(MafSeq {
+            species: species.to_string(),
+            chrom: chrom.to_string(),
+            start: fields[2].parse().ok()?,
+            size: fields[3].parse().ok()?,
+            strand: match fields[4] {
+                "+" => Strand::Positive,
+                "-" => Strand::Negative,
+                _ => return None,
+            },
+            src_size: fields[5].parse().ok()?,
+            text: fields[6].as_bytes().to_vec(),
+        })
+    }
+
+    /// Same row seen from the other strand of the source sequence, `None` if the
+    /// row runs past the end of the source.
+    fn reverse(&self) -> Option<Self> {
+        Some(MafSeq {
+            start: self
+                .src_size
+                .checked_sub(self.start)?
+                .checked_sub(self.size)?,
+            strand: self.strand.reverse(),
+            text: self.text.iter().rev().copied().collect(),
+            ..self.clone()
+        })
+    }
+}
+
+/// Consecutive colinear MAF blocks of one target/query pair, written as a single chain.
+struct PendingChain {
+    target: SeqInfo,
+    query: SeqInfo,
+    blocks: Vec<(usize, usize, usize)>,
+}
+
+impl PendingChain {
+    fn accepts(&self, target: &MafSeq, query: &MafSeq) -> bool {
+        self.target.name == target.chrom
+            && self.query.name == query.chrom
+            && self.query.strand == query.strand
+            && target.start >= self.target.end
+            && query.start >= self.query.end
+    }
+
+    fn write<W: Write + ?Sized>(self, writer: &mut W, chain_id: usize) -> io::Result<()> {
+        let score: usize = self.blocks.iter().map(|x| x.2).sum();
+        let header = Header::new(score as f64, self.target, self.query, chain_id);
+        write_chain(writer, &header, &alignments_from_blocks(&self.blocks))
+    }
+}
+
+/// Gapless blocks of one MAF alignment block, target must be on the positive strand.
+fn maf_blocks(target: &MafSeq, query: &MafSeq) -> Vec<(usize, usize, usize)> {
+    let mut blocks: Vec<(usize, usize, usize)> = Vec::new();
+    let mut t = target.start;
+    let mut q = query.start;
+    for (&tc, &qc) in target.text.iter().zip(query.text.iter()) {
+        match (tc == b'-', qc == b'-') {
+            (false, false) => {
+                match blocks.last_mut() {
+                    Some(last) if last.0 + last.2 == t && last.1 + last.2 == q => last.2 += 1,
+                    _ => blocks.push((t, q, 1)),
+                }
+                t += 1;
+                q += 1;
+            }
+            (false, true) => t += 1,
+            (true, false) => q += 1,
+            // gap-only column of this pair
+            (true, true) => {}
+        }
+    }
+    blocks
+}
+
+/// Convert pairwise alignments between `target_species` and `query_species` in a
+/// MAF file into UCSC chains; `target_species` is the assembly lifted from.
+pub fn maf_to_chain(
+    maf_file: &String,
+    target_species: &String,
+    query_species: &String,
+    output: &Option<String>,
+    rewrite: bool,
+) -> Result<()> {
+    let reader = BufReader::new(get_file_reader(maf_file)?);
+    let (mut output_file, _) = get_output_writer(output, rewrite)?;
+    let mut chain_id = 0;
+    let mut pending: Option<PendingChain> = None;
+    let mut block_rows: Vec<MafSeq> = Vec::new();
+    // an empty line, the next `a` line or EOF ends an alignment block
+    let lines = reader.lines().chain(Some(Ok(String::new())));
+    for line in lines {
+        let line = line?;
+        let line = line.trim();
+        if line.starts_with('s') {
+            match MafSeq::from_line(line) {
+                Some(row) => block_rows.push(row),
+                None => warn!("SKIP: Error parsing MAF s line: {}", line),
+            }
+            continue;
+        }
+        // `i`/`e`/`q` lines carry no aligned bases
+        if !(line.is_empty() || line.starts_with('a')) || block_rows.is_empty() {
+            continue;
+        }
+        let rows = std::mem::take(&mut block_rows);
+        let target = rows.iter().find(|x| &x.species == target_species);
+        let query = rows.iter().find(|x| &x.species == query_species);
+        let (target, query) = match (target, query) {
+            (Some(target), Some(query)) => match target.strand {
+                Strand::Positive => (target.clone(), query.clone()),
+                Strand::Negative => match (target.reverse(), query.reverse()) {
+                    (Some(target), Some(query)) => (target, query),
+                    _ => {
+                        warn!(
+                            "SKIP: MAF row runs past the end of its sequence at {}:{}",
+                            target.chrom, target.start
+                        );
+                        continue;
+                    }
+                },
+            },
+            _ => continue,
+        };
+        if target.text.len() != query.text.len() {
+            warn!(
+                "SKIP: MAF rows of different length at {}:{}",
+                target.chrom, target.start
+            );
+            continue;
+        }
+        let blocks = maf_blocks(&target, &query);
+        let (first, last) = match (blocks.first(), blocks.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => continue,
+        };
+        if let Some(chain) = pending.as_mut() {
+            if chain.accepts(&target, &query) {
+                chain.target.end = last.0 + last.2;
+                chain.query.end = last.1 + last.2;
+                chain.blocks.extend(blocks);
+                continue;
+            }
+        }
+        if let Some(chain) = pending.take() {
+            chain_id += 1;
+            chain.write(&mut output_file, chain_id)?;
+        }
+        pending = Some(PendingChain {
+            target: SeqInfo {
+                name: target.chrom.clone(),
+                size: target.src_size,
+                strand: Strand::Positive,
+                start: first.0,
+                end: last.0 + last.2,
+            },
+            query: SeqInfo {
+                name: query.chrom.clone(),
+                size: query.src_size,
+                strand: query.strand,
+                start: first.1,
+                end: last.1 + last.2,
+            },
+            blocks,
+        });
+    }
+    if let Some(chain) = pending.take() {
+        chain_id += 1;
+        chain.write(&mut output_file, chain_id)?;
+    }
+    info!("write {} chains", chain_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Alignment, ChainReader};
+    use crate::validate::validate_chain_reader;
+    use std::fs::File;
+
+    const MAF: &str = "##maf version=1
+a score=10
+s hg.chr1 10 12 + 100 ACGTA--CGTACGT
+s mm.chr2  5 12 -  50 ACGTACG--TACGT
+s rn.chr3  0 14 +  80 ACGTACGTACGTAC
+
+a score=5
+s hg.chr1 95 10 - 100 ACGTACGTAC
+s mm.chr2  0 10 +  50 ACGTACGTAC
+
+a score=2
+s hg.chr1 50  4 - 100 ACGT
+s mm.chr2 20  4 +  50 ACGT
+";
+
+    #[test]
+    fn pairwise_chains() {
+        let dir = std::env::temp_dir();
+        let maf_file = dir.join(format!("crussmap_maf_{}.maf", std::process::id()));
+        let chain_file = dir.join(format!("crussmap_maf_{}.chain", std::process::id()));
+        std::fs::write(&maf_file, MAF).unwrap();
+        maf_to_chain(
+            &maf_file.to_string_lossy().to_string(),
+            &"hg".to_string(),
+            &"mm".to_string(),
+            &Some(chain_file.to_string_lossy().to_string()),
+            true,
+        )
+        .unwrap();
+
+        let (chains, diagnostics) =
+            validate_chain_reader(BufReader::new(File::open(&chain_file).unwrap())).unwrap();
+        assert_eq!((chains, diagnostics.len()), (1, 0));
+        let chains: Vec<_> = ChainReader::new(BufReader::new(File::open(&chain_file).unwrap()))
+            .map(|x| x.unwrap())
+            .collect();
+        // the second block runs past the end of chr1 and is skipped, the third one
+        // has hg on its - strand and is flipped, then joins the first
+        let header = &chains[0].header;
+        let (t, q) = (&header.target, &header.query);
+        assert_eq!(
+            (t.name.as_str(), t.size, t.strand, t.start, t.end),
+            ("chr1", 100, Strand::Positive, 10, 50)
+        );
+        assert_eq!(
+            (q.name.as_str(), q.size, q.strand, q.start, q.end),
+            ("chr2", 50, Strand::Negative, 5, 30)
+        );
+        assert_eq!(header.score, 14.0);
+        assert_eq!(
+            chains[0].alignments,
+            [
+                Alignment {
+                    size: 5,
+                    target_diff: 2,
+                    query_diff: 2
+                },
+                Alignment {
+                    size: 5,
+                    target_diff: 24,
+                    query_diff: 9
+                },
+                Alignment {
+                    size: 4,
+                    target_diff: 0,
+                    query_diff: 0
+                },
+            ]
+        );
+        std::fs::remove_file(maf_file).unwrap();
+        std::fs::remove_file(chain_file).unwrap();
+    }
+}
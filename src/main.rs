@@ -1,11 +1,35 @@
 use clap::{Parser, Subcommand};
-use crussmap::{bed::cross_bed, log::init_logger, view::view_chain};
+use crussmap::{
+    bam::cross_bam,
+    bed::{cross_bed, BedLiftOptions},
+    bedgraph::{cross_bedgraph, cross_wig, MergePolicy},
+    compose::compose_chain,
+    delta::delta_to_chain,
+    filter::{filter_chain, ChainFilter},
+    gff::cross_gff,
+    index::index_chain,
+    liftover::MultiMapPolicy,
+    log::init_logger,
+    maf::maf_to_chain,
+    paf::paf_to_chain,
+    parser::Strand,
+    region::cross_region,
+    stats::chain_stats,
+    swap::swap_chain,
+    tabix::{IndexFormat, OutputOptions},
+    utils::OutputCompression,
+    validate::validate_chain,
+    vcf::cross_vcf,
+    view::view_chain,
+};
+use log::error;
+use regex::Regex;
 // use crussmap::test_ryon::test;
 
 fn main() {
     init_logger();
     let cli = Cli::parse();
-    match &cli.command {
+    let result = match &cli.command {
         Commands::View {
             csv,
             input,
@@ -17,12 +41,198 @@ fn main() {
             input,
             output,
             unmap,
+            min_match,
+            min_blocks,
+            multi_map,
+            single_chain,
+            merge_split,
+            max_gap,
+            report,
+            compress,
+            index,
             rewrite,
-        } => cross_bed(bed, input, output, unmap, *rewrite),
+        } => cross_bed(
+            bed,
+            input,
+            output,
+            unmap,
+            &BedLiftOptions {
+                min_match: *min_match,
+                min_blocks: *min_blocks,
+                multi_map: match single_chain {
+                    true => MultiMapPolicy::UniqueOnly,
+                    false => *multi_map,
+                },
+                merge_split: *merge_split,
+                max_gap: *max_gap,
+                report: report.clone(),
+            },
+            &OutputOptions {
+                compress: *compress,
+                index: *index,
+            },
+            *rewrite,
+        ),
+        Commands::Vcf {
+            vcf,
+            input,
+            fasta,
+            output,
+            unmap,
+            compress,
+            index,
+            rewrite,
+        } => cross_vcf(
+            vcf,
+            input,
+            fasta,
+            output,
+            unmap,
+            &OutputOptions {
+                compress: *compress,
+                index: *index,
+            },
+            *rewrite,
+        ),
+        Commands::Gff {
+            gff,
+            input,
+            output,
+            unmap,
+            compress,
+            index,
+            rewrite,
+        } => cross_gff(
+            gff,
+            input,
+            output,
+            unmap,
+            &OutputOptions {
+                compress: *compress,
+                index: *index,
+            },
+            *rewrite,
+        ),
+        Commands::Bam {
+            bam,
+            input,
+            output,
+            unmap,
+            rewrite,
+        } => cross_bam(bam, input, output, unmap, *rewrite),
+        Commands::Region {
+            regions,
+            file,
+            input,
+            output,
+            rewrite,
+        } => cross_region(regions, file, input, output, *rewrite),
+        Commands::Paf2chain {
+            paf,
+            output,
+            rewrite,
+        } => paf_to_chain(paf, output, *rewrite),
+        Commands::Delta2chain {
+            delta,
+            output,
+            rewrite,
+        } => delta_to_chain(delta, output, *rewrite),
+        Commands::Maf2chain {
+            maf,
+            target,
+            query,
+            output,
+            rewrite,
+        } => maf_to_chain(maf, target, query, output, *rewrite),
+        Commands::Swap {
+            input,
+            output,
+            rewrite,
+        } => swap_chain(input, output, *rewrite),
+        Commands::Compose {
+            first,
+            second,
+            output,
+            rewrite,
+        } => compose_chain(first, second, output, *rewrite),
+        Commands::Filter {
+            input,
+            output,
+            min_score,
+            min_aligned,
+            target_chrom,
+            query_chrom,
+            ids,
+            strand,
+            invert,
+            rewrite,
+        } => {
+            let chain_filter = ChainFilter {
+                min_score: *min_score,
+                min_aligned: *min_aligned,
+                target_chrom: target_chrom.clone(),
+                query_chrom: query_chrom.clone(),
+                chain_ids: ids.iter().copied().collect(),
+                query_strand: strand.as_ref().map(|x| match x.as_str() {
+                    "-" => Strand::Negative,
+                    _ => Strand::Positive,
+                }),
+                invert: *invert,
+            };
+            filter_chain(input, output, &chain_filter, *rewrite)
+        }
+        Commands::Stats {
+            input,
+            output,
+            json,
+            rewrite,
+        } => chain_stats(input, output, *json, *rewrite),
+        Commands::Validate {
+            input,
+            output,
+            rewrite,
+        } => validate_chain(input, output, *rewrite).map(|problems| {
+            if problems > 0 {
+                std::process::exit(1);
+            }
+        }),
+        Commands::Index {
+            input,
+            output,
+            check,
+            rewrite,
+        } => index_chain(input, output, *check, *rewrite),
+        Commands::Bedgraph {
+            bedgraph,
+            input,
+            output,
+            unmap,
+            merge,
+            rewrite,
+        } => cross_bedgraph(bedgraph, input, output, unmap, *merge, *rewrite),
+        Commands::Wig {
+            wig,
+            input,
+            output,
+            unmap,
+            merge,
+            rewrite,
+        } => cross_wig(wig, input, output, unmap, *merge, *rewrite),
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1);
     }
     // test();
 }
 
+fn parse_ratio(value: &str) -> std::result::Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+        _ => Err(format!("{} is not a ratio between 0 and 1", value)),
+    }
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
@@ -62,11 +272,335 @@ enum Commands {
         /// unmapped bed file path, if not set, output to STDOUT
         #[arg(short, long)]
         unmap: Option<String>,
+        /// minimum fraction of the region bases that must map, like liftOver -minMatch
+        #[arg(long, default_value = "0", value_parser = parse_ratio)]
+        min_match: f64,
+        /// minimum fraction of BED12 blocks that must map, like liftOver -minBlocks
+        #[arg(long, default_value = "0", value_parser = parse_ratio)]
+        min_blocks: f64,
+        /// which chain to keep when a region maps through several: all, best-score,
+        /// longest or unique-only (sent to unmapped)
+        #[arg(long, value_enum, default_value = "all")]
+        multi_map: MultiMapPolicy,
+        /// reject regions whose pieces come from more than one chain, same as
+        /// --multi-map unique-only
+        #[arg(long, default_value = "false", conflicts_with = "multi_map")]
+        single_chain: bool,
+        /// write the split pieces of a region from one chain, chromosome and strand as
+        /// a single interval spanning them
+        #[arg(short = 'm', long, default_value = "false")]
+        merge_split: bool,
+        /// largest gap (bp, in either assembly) bridged by --merge-split, default is unlimited
+        #[arg(long, requires = "merge_split")]
+        max_gap: Option<usize>,
+        /// json file path for the number of converted records and of unmapped ones per
+        /// reason, always logged to STDERR
+        #[arg(long)]
+        report: Option<String>,
+        /// compression of output files, default is picked from the extension (.gz/.bgz for BGZF)
+        #[arg(short = 'z', long, value_enum)]
+        compress: Option<OutputCompression>,
+        /// sort the output bed file and write a tabix index next to it, implies BGZF
+        #[arg(long, value_enum)]
+        index: Option<IndexFormat>,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Converts VCF file. REF/ALT are reverse complemented on negative strand blocks,
+    /// and checked against the new reference when a FASTA file is given.
+    Vcf {
+        /// vcf file path
+        #[arg(short, long)]
+        vcf: String,
+        /// input chain file path
+        #[arg(short, long)]
+        input: Option<String>,
+        /// reference genome FASTA of the new assembly, plain or bgzip compressed, used
+        /// to check REF alleles
+        #[arg(short, long)]
+        fasta: Option<String>,
+        /// output vcf file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// unmapped vcf file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        unmap: Option<String>,
+        /// compression of output files, default is picked from the extension (.gz/.bgz for BGZF)
+        #[arg(short = 'z', long, value_enum)]
+        compress: Option<OutputCompression>,
+        /// sort the output vcf file and write a tabix index next to it, implies BGZF
+        #[arg(long, value_enum)]
+        index: Option<IndexFormat>,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Converts GFF3/GTF file. Features split by the chain or separated from their parent are reported as unmapped.
+    Gff {
+        /// gff3/gtf file path
+        #[arg(short, long)]
+        gff: String,
+        /// input chain file path
+        #[arg(short, long)]
+        input: Option<String>,
+        /// output gff file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// unmapped gff file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        unmap: Option<String>,
+        /// compression of output files, default is picked from the extension (.gz/.bgz for BGZF)
+        #[arg(short = 'z', long, value_enum)]
+        compress: Option<OutputCompression>,
+        /// sort the output gff file and write a tabix index next to it, implies BGZF
+        #[arg(long, value_enum)]
+        index: Option<IndexFormat>,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Converts SAM/BAM file. CIGAR is rewritten for chain gaps, reads on negative strand blocks are flipped.
+    Bam {
+        /// sam/bam file path
+        #[arg(short, long)]
+        bam: String,
+        /// input chain file path
+        #[arg(short, long)]
+        input: Option<String>,
+        /// output file path, BAM if it ends with .bam, otherwise SAM; if not set, output SAM to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// unmapped reads file path, BAM if it ends with .bam, otherwise SAM; if not set, output to STDOUT
+        #[arg(short, long)]
+        unmap: Option<String>,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Converts bedGraph file. Output is sorted and non-overlapping, ready for bedGraphToBigWig.
+    Bedgraph {
+        /// bedgraph file path
+        #[arg(short, long)]
+        bedgraph: String,
+        /// input chain file path
+        #[arg(short, long)]
+        input: Option<String>,
+        /// output bedgraph file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// unmapped bedgraph file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        unmap: Option<String>,
+        /// how to combine values landing on the same bases
+        #[arg(short, long, value_enum, default_value = "mean")]
+        merge: MergePolicy,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Converts fixedStep/variableStep WIG file into sorted, non-overlapping bedGraph.
+    Wig {
+        /// wig file path
+        #[arg(short, long)]
+        wig: String,
+        /// input chain file path
+        #[arg(short, long)]
+        input: Option<String>,
+        /// output bedgraph file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// unmapped bedgraph file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        unmap: Option<String>,
+        /// how to combine values landing on the same bases
+        #[arg(short, long, value_enum, default_value = "mean")]
+        merge: MergePolicy,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Converts regions given as chr:start-end[:strand] (1-based, inclusive) on the command line or in a file.
+    Region {
+        /// regions like chr1:1,000-2,000 or chr1:1000-2000:-
+        regions: Vec<String>,
+        /// file with one region per line
+        #[arg(short, long)]
+        file: Option<String>,
+        /// input chain file path
+        #[arg(short, long)]
+        input: Option<String>,
+        /// output file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Builds chain file from minimap2 PAF alignments with cg:Z: CIGAR tags (run with --cs or -c).
+    /// PAF target is the assembly to convert from, PAF query the one to convert to.
+    Paf2chain {
+        /// paf file path
+        #[arg(short, long)]
+        paf: String,
+        /// output chain file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Builds chain file from MUMmer nucmer delta file.
+    /// Delta reference is the assembly to convert from, delta query the one to convert to.
+    Delta2chain {
+        /// delta file path
+        #[arg(short, long)]
+        delta: String,
+        /// output chain file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Builds chain file from a MAF multiple alignment for one target/query species pair.
+    Maf2chain {
+        /// maf file path
+        #[arg(short, long)]
+        maf: String,
+        /// species (prefix of species.chrom in s lines) to convert from
+        #[arg(short, long)]
+        target: String,
+        /// species to convert to
+        #[arg(short, long)]
+        query: String,
+        /// output chain file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Swaps target and query of a chain file (like UCSC chainSwap), to convert in the opposite direction.
+    Swap {
+        /// input chain file path, if not set, read from STDIN
+        #[arg(short, long)]
+        input: Option<String>,
+        /// output chain file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Composes two chain files A->B and B->C into one A->C chain file, to convert across
+    /// three assemblies in one step.
+    Compose {
+        /// first chain file path, A->B
+        #[arg(short, long)]
+        first: String,
+        /// second chain file path, B->C
+        #[arg(short, long)]
+        second: String,
+        /// output chain file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Filters chains by score, aligned bases, chromosome name, chain id and strand.
+    Filter {
+        /// input chain file path, if not set, read from STDIN
+        #[arg(short, long)]
+        input: Option<String>,
+        /// output chain file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// minimum chain score
+        #[arg(short = 's', long)]
+        min_score: Option<f64>,
+        /// minimum number of aligned bases (sum of block sizes)
+        #[arg(short = 'a', long)]
+        min_aligned: Option<usize>,
+        /// regex the target (old assembly) chromosome name must match, e.g. '^chr[0-9XY]+$'
+        #[arg(short, long, value_parser = Regex::new)]
+        target_chrom: Option<Regex>,
+        /// regex the query (new assembly) chromosome name must match
+        #[arg(short, long, value_parser = Regex::new)]
+        query_chrom: Option<Regex>,
+        /// chain ids to keep, comma separated
+        #[arg(short = 'd', long, value_delimiter = ',')]
+        ids: Vec<usize>,
+        /// query strand to keep
+        #[arg(long, value_parser = ["+", "-"], allow_hyphen_values = true)]
+        strand: Option<String>,
+        /// drop the chains matching all conditions instead of keeping them
+        #[arg(short = 'v', long, default_value = "false")]
+        invert: bool,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Reports chain, block, gap and coverage statistics of a chain file, per chain,
+    /// per target chromosome and in total.
+    Stats {
+        /// input chain file path, if not set, read from STDIN
+        #[arg(short, long)]
+        input: Option<String>,
+        /// output file path, if not set, output to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// output in json format instead of tsv, default is false
+        #[arg(short, long, default_value = "false")]
+        json: bool,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+
+    /// Checks a chain file for malformed or inconsistent chains, reporting line numbers.
+    /// Exits with status 1 if any problem is found.
+    Validate {
+        /// input chain file path, if not set, read from STDIN
+        #[arg(short, long)]
+        input: Option<String>,
+        /// output file path for the report, if not set, output to STDOUT
+        #[arg(short, long)]
+        output: Option<String>,
+        /// rewrite output file, default is false
+        #[arg(short, long, default_value = "false")]
+        rewrite: bool,
+    },
+    /// Builds a binary index of a chain file for fast loading. Pass the index instead of
+    /// the chain to the `-i` option of any converting subcommand.
+    Index {
+        /// input chain file path, if not set, read from STDIN
+        #[arg(short, long)]
+        input: Option<String>,
+        /// output index file path
+        #[arg(short, long)]
+        output: String,
+        /// check that the existing output index was built from the input chain instead
+        /// of writing it, exits with status 1 if it is out of date
+        #[arg(short, long, default_value = "false")]
+        check: bool,
         /// rewrite output file, default is false
         #[arg(short, long, default_value = "false")]
         rewrite: bool,
     },
-    // TODO: add test
-    // TODO: Region
-    // TODO: Suppprt MAF/PAF/SAM/delta -> chain
+    // TODO: Suppprt SAM -> chain
 }
@@ -0,0 +1,250 @@
+use crate::{
+    error::Result,
+    parser::{alignments_from_blocks, write_chain, Header, SeqInfo, Strand},
+    utils::{get_file_reader, get_output_writer},
+};
+use log::{info, warn};
+use std::io::{BufRead, BufReader};
+
+/// One line of minimap2 PAF output, with the `cg:Z:` CIGAR.
+#[derive(Debug, Clone)]
+pub struct PafRecord {
+    pub query_name: String,
+    pub query_len: usize,
+    pub query_start: usize,
+    pub query_end: usize,
+    pub strand: Strand,
+    pub target_name: String,
+    pub target_len: usize,
+    pub target_start: usize,
+    pub target_end: usize,
+    pub matches: usize,
+    pub cigar: String,
+    /// `AS:i:` alignment score, if present
+    pub score: Option<f64>,
+}
+
+impl PafRecord {
+    pub fn from_line(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 12 {
+            return None;
+        }
+        let tag = |prefix: &str| {
+            fields[12..]
+                .iter()
+                .find_map(|x| x.strip_prefix(prefix))
+                .map(|x| x.to_string())
+        };
+        let paf_rcd = PafRecord {
+            query_name: fields[0].to_string(),
+            query_len: fields[1].parse().ok()?,
+            query_start: fields[2].parse().ok()?,
+            query_end: fields[3].parse().ok()?,
+            strand: match fields[4] {
+                "+" => Strand::Positive,
+                "-" => Strand::Negative,
+                _ => return None,
+            },
+            target_name: fields[5].to_string(),
+            target_len: fields[6].parse().ok()?,
+            target_start: fields[7].parse().ok()?,
+            target_end: fields[8].parse().ok()?,
+            matches: fields[9].parse().ok()?,
+            cigar: tag("cg:Z:")?,
+            score: tag("AS:i:").and_then(|x| x.parse().ok()),
+        };
+        // intervals must lie within their sequences, or the chain coordinates underflow
+        if paf_rcd.query_start > paf_rcd.query_end
+            || paf_rcd.query_end > paf_rcd.query_len
+            || paf_rcd.target_start > paf_rcd.target_end
+            || paf_rcd.target_end > paf_rcd.target_len
+        {
+            return None;
+        }
+        Some(paf_rcd)
+    }
+
+    /// Gapless blocks in chain coordinates, the query is counted on its
+    /// reverse strand for `-` alignments as the CIGAR follows the target.
+    fn blocks(&self) -> Option<Vec<(usize, usize, usize)>> {
+        let mut t = self.target_start;
+        let (mut q, q_end) = match self.strand {
+            Strand::Positive => (self.query_start, self.query_end),
+            Strand::Negative => (
+                self.query_len.checked_sub(self.query_end)?,
+                self.query_len.checked_sub(self.query_start)?,
+            ),
+        };
+        let mut blocks = Vec::new();
+        let mut len: usize = 0;
+        for c in self.cigar.chars() {
+            if let Some(d) = c.to_digit(10) {
+                len = len * 10 + d as usize;
+                continue;
+            }
+            match c {
+                'M' | '=' | 'X' => {
+                    blocks.push((t, q, len));
+                    t += len;
+                    q += len;
+                }
+                'I' => q += len,
+                'D' | 'N' => t += len,
+                _ => return None,
+            }
+            len = 0;
+        }
+        if t != self.target_end || q != q_end {
+            return None;
+        }
+        Some(blocks)
+    }
+}
+
+/// Convert PAF records with `cg:Z:` tags into UCSC chains. The PAF target becomes
+/// the chain target, i.e. the assembly lifted from.
+pub fn paf_to_chain(paf_file: &String, output: &Option<String>, rewrite: bool) -> Result<()> {
+    let reader = BufReader::new(get_file_reader(paf_file)?);
+    let (mut output_file, _) = get_output_writer(output, rewrite)?;
+    let mut chain_id = 0;
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let paf_rcd = match PafRecord::from_line(&line) {
+            Some(paf_rcd) => paf_rcd,
+            None => {
+                warn!("SKIP: Error parsing PAF record or no cg:Z: tag: {}", line);
+                continue;
+            }
+        };
+        let blocks = match paf_rcd.blocks() {
+            Some(blocks) => blocks,
+            None => {
+                warn!(
+                    "SKIP: CIGAR does not match coordinates in PAF record: {}",
+                    line
+                );
+                continue;
+            }
+        };
+        let blocks: Vec<_> = blocks.into_iter().filter(|x| x.2 > 0).collect();
+        let (first, last) = match (blocks.first(), blocks.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => {
+                warn!("SKIP: No aligned base in PAF record: {}", line);
+                continue;
+            }
+        };
+        chain_id += 1;
+        let header = Header::new(
+            paf_rcd.score.unwrap_or(paf_rcd.matches as f64),
+            SeqInfo {
+                name: paf_rcd.target_name,
+                size: paf_rcd.target_len,
+                strand: Strand::Positive,
+                start: first.0,
+                end: last.0 + last.2,
+            },
+            SeqInfo {
+                name: paf_rcd.query_name,
+                size: paf_rcd.query_len,
+                strand: paf_rcd.strand,
+                start: first.1,
+                end: last.1 + last.2,
+            },
+            chain_id,
+        );
+        write_chain(&mut output_file, &header, &alignments_from_blocks(&blocks))?;
+    }
+    info!("write {} chains", chain_id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Alignment, ChainReader, ChainRecord};
+    use crate::validate::validate_chain_reader;
+    use std::fs::File;
+
+    #[test]
+    fn cigar_to_chains() {
+        let dir = std::env::temp_dir();
+        let paf_file = dir.join(format!("crussmap_paf_{}.paf", std::process::id()));
+        let chain_file = dir.join(format!("crussmap_paf_{}.chain", std::process::id()));
+        std::fs::write(
+            &paf_file,
+            [
+                "q1\t100\t10\t40\t+\tt1\t200\t50\t80\t28\t32\t60\tcg:Z:10M2D10M2I8M",
+                "q1\t100\t60\t90\t-\tt1\t200\t100\t130\t28\t32\t60\tcg:Z:10M2D10M2I8M\tAS:i:50",
+                // query end past the query length
+                "q1\t100\t60\t120\t-\tt1\t200\t100\t130\t28\t32\t60\tcg:Z:10M2D10M2I8M",
+                // CIGAR longer than the query interval
+                "q1\t100\t10\t30\t+\tt1\t200\t50\t80\t28\t32\t60\tcg:Z:10M2D10M2I8M",
+            ]
+            .join("\n"),
+        )
+        .unwrap();
+        let chain_path = chain_file.to_string_lossy().to_string();
+        paf_to_chain(
+            &paf_file.to_string_lossy().to_string(),
+            &Some(chain_path.clone()),
+            true,
+        )
+        .unwrap();
+
+        let (chains, diagnostics) =
+            validate_chain_reader(BufReader::new(File::open(&chain_file).unwrap())).unwrap();
+        assert_eq!((chains, diagnostics.len()), (2, 0));
+        let chains: Vec<_> = ChainReader::new(BufReader::new(File::open(&chain_file).unwrap()))
+            .map(|x| x.unwrap())
+            .collect();
+        let alignments = vec![
+            Alignment {
+                size: 10,
+                target_diff: 2,
+                query_diff: 0,
+            },
+            Alignment {
+                size: 10,
+                target_diff: 0,
+                query_diff: 2,
+            },
+            Alignment {
+                size: 8,
+                target_diff: 0,
+                query_diff: 0,
+            },
+        ];
+        let summary = |x: &ChainRecord| {
+            let (t, q) = (&x.header.target, &x.header.query);
+            (
+                x.header.score,
+                t.start,
+                t.end,
+                q.strand,
+                q.start,
+                q.end,
+                x.header.chain_id,
+            )
+        };
+        assert_eq!(
+            summary(&chains[0]),
+            (28.0, 50, 80, Strand::Positive, 10, 40, 1)
+        );
+        // the query of a - alignment is counted on its reverse strand
+        assert_eq!(
+            summary(&chains[1]),
+            (50.0, 100, 130, Strand::Negative, 10, 40, 2)
+        );
+        for chain in &chains {
+            assert_eq!(chain.header.query.size, 100);
+            assert_eq!(chain.alignments, alignments);
+        }
+        std::fs::remove_file(paf_file).unwrap();
+        std::fs::remove_file(chain_file).unwrap();
+    }
+}
@@ -1,15 +1,15 @@
-use crate::interval::{get_block_ivl, Block, BlockIvl};
-use log::error;
-use nom::{
-    bytes::complete::{is_not, tag, take_while},
-    character::complete::{line_ending, not_line_ending},
-    multi::fold_many1,
-    sequence::terminated,
-    IResult,
+use crate::{
+    error::{CrussmapError, Result},
+    interval::{get_block_ivl, Block, BlockIvl},
+};
+use log::warn;
+use std::{
+    fmt,
+    io::{self, BufRead, Write},
+    str::FromStr,
 };
-use std::{fmt, io};
 
-#[derive(Debug, Clone, PartialEq, Copy)]
+#[derive(Debug, Clone, PartialEq, Eq, Copy, Serialize, Deserialize)]
 pub enum Strand {
     Positive,
     Negative,
@@ -45,10 +45,10 @@ pub struct SeqInfo {
 #[derive(Clone)]
 
 pub struct Header {
-    score: f64,
+    pub score: f64,
     pub target: SeqInfo,
     pub query: SeqInfo,
-    chain_id: usize,
+    pub chain_id: usize,
 }
 
 impl fmt::Debug for Header {
@@ -61,7 +61,40 @@ impl fmt::Debug for Header {
     }
 }
 
-#[derive(Debug)]
+impl Header {
+    pub fn new(score: f64, target: SeqInfo, query: SeqInfo, chain_id: usize) -> Self {
+        Header {
+            score,
+            target,
+            query,
+            chain_id,
+        }
+    }
+}
+
+/// Header line in UCSC chain format, starting with `chain`.
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "chain {} {} {} {} {} {} {} {} {} {} {} {}",
+            self.score,
+            self.target.name,
+            self.target.size,
+            self.target.strand,
+            self.target.start,
+            self.target.end,
+            self.query.name,
+            self.query.size,
+            self.query.strand,
+            self.query.start,
+            self.query.end,
+            self.chain_id
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Alignment {
     pub size: usize,        //the size of the ungapped alignment
     pub target_diff: usize, //the difference between the end of this block and the beginning of the next block
@@ -72,100 +105,356 @@ pub struct Alignment {
 pub struct ChainRecord {
     pub header: Header,
     pub block_ivls: Vec<BlockIvl>,
+    pub alignments: Vec<Alignment>,
+    /// separator of the fields of `size dt dq` lines, a tab unless the chain was
+    /// read with another one
+    pub block_separator: char,
+}
+
+impl ChainRecord {
+    pub fn new(header: Header, alignments: Vec<Alignment>) -> Self {
+        let block_ivls = get_block_ivls(&header, &alignments);
+        ChainRecord {
+            header,
+            block_ivls,
+            alignments,
+            block_separator: '\t',
+        }
+    }
+
+    /// Write the chain back in UCSC format, see the `Display` impl.
+    pub fn write<W: Write + ?Sized>(&self, writer: &mut W) -> io::Result<()> {
+        write!(writer, "{}", self)
+    }
 }
 
-pub struct ChainRecords<'a>(pub &'a str);
+/// UCSC chain text of the record. The normal form has single spaces in the header,
+/// `block_separator` in the `size dt dq` lines and a blank line after each chain, so
+/// a chain file in this form with integer (or shortest-form) scores is written back
+/// byte for byte.
+impl fmt::Display for ChainRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        ChainText(&self.header, &self.alignments, self.block_separator).fmt(f)
+    }
+}
 
-impl<'a> Iterator for ChainRecords<'a> {
-    type Item = Result<ChainRecord, String>;
+struct ChainText<'a>(&'a Header, &'a [Alignment], char);
+
+impl fmt::Display for ChainText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ChainText(header, alignments, sep) = self;
+        writeln!(f, "{}", header)?;
+        for (i, alignment) in alignments.iter().enumerate() {
+            if i + 1 == alignments.len() {
+                writeln!(f, "{}", alignment.size)?;
+            } else {
+                writeln!(
+                    f,
+                    "{}{sep}{}{sep}{}",
+                    alignment.size, alignment.target_diff, alignment.query_diff
+                )?;
+            }
+        }
+        writeln!(f)
+    }
+}
+
+/// Turn gapless blocks `(target_start, query_start, size)`, sorted and in chain
+/// coordinates, into alignment lines; touching blocks are joined.
+pub fn alignments_from_blocks(blocks: &[(usize, usize, usize)]) -> Vec<Alignment> {
+    let mut alignments: Vec<Alignment> = Vec::new();
+    let mut blocks = blocks.iter().filter(|x| x.2 > 0).peekable();
+    while let Some(&(t, q, size)) = blocks.next() {
+        let mut size = size;
+        while let Some(&&(next_t, next_q, next_size)) = blocks.peek() {
+            if next_t == t + size && next_q == q + size {
+                size += next_size;
+                blocks.next();
+            } else {
+                break;
+            }
+        }
+        let (target_diff, query_diff) = match blocks.peek() {
+            Some(&&(next_t, next_q, _)) => (next_t - (t + size), next_q - (q + size)),
+            None => (0, 0),
+        };
+        alignments.push(Alignment {
+            size,
+            target_diff,
+            query_diff,
+        });
+    }
+    alignments
+}
+
+/// Write one chain in UCSC format: header, tab separated `size dt dq` lines, a last
+/// `size` line and a blank line.
+pub fn write_chain<W: Write + ?Sized>(
+    writer: &mut W,
+    header: &Header,
+    alignments: &[Alignment],
+) -> io::Result<()> {
+    write!(writer, "{}", ChainText(header, alignments, '\t'))
+}
+
+/// Streaming reader of the chains of a chain file, one line at a time, so memory
+/// stays bounded by the largest chain. Blank and `#` comment lines between chains are
+/// skipped. A malformed chain is returned as an error and reading resumes at the next
+/// `chain` header; iteration stops after an I/O error.
+pub struct ChainReader<R: BufRead> {
+    reader: R,
+    /// current line without its line ending
+    buf: String,
+    /// line number of `buf`, 1-based
+    line: usize,
+    /// `buf` holds the header of the next chain, read while looking for the end
+    /// of the previous one
+    pending_header: bool,
+    /// I/O error met while skipping a malformed chain, returned on the next call
+    pending_error: Option<CrussmapError>,
+    done: bool,
+}
+
+impl<R: BufRead> ChainReader<R> {
+    pub fn new(reader: R) -> Self {
+        ChainReader {
+            reader,
+            buf: String::new(),
+            line: 0,
+            pending_header: false,
+            pending_error: None,
+            done: false,
+        }
+    }
+
+    /// Chains of the reader, malformed ones are logged and skipped while I/O errors
+    /// are kept for the caller.
+    pub fn skip_malformed(self) -> impl Iterator<Item = Result<ChainRecord>> {
+        self.filter(|x| match x {
+            Err(e @ CrussmapError::ChainParse { .. }) => {
+                warn!("SKIP: Error parsing chain: {}", e);
+                false
+            }
+            _ => true,
+        })
+    }
+
+    /// Skip the rest of a malformed chain, up to the next header or the end of input.
+    fn skip_to_next_header(&mut self) {
+        while !self.pending_header {
+            match self.read_line() {
+                Ok(true) => self.pending_header = self.buf.starts_with("chain"),
+                Ok(false) => {
+                    self.done = true;
+                    return;
+                }
+                Err(e) => {
+                    self.pending_error = Some(e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Read the next line into `buf`, returns false at the end of input.
+    fn read_line(&mut self) -> Result<bool> {
+        self.buf.clear();
+        if self.reader.read_line(&mut self.buf)? == 0 {
+            return Ok(false);
+        }
+        self.line += 1;
+        if self.buf.ends_with('\n') {
+            self.buf.pop();
+            if self.buf.ends_with('\r') {
+                self.buf.pop();
+            }
+        }
+        Ok(true)
+    }
+
+    fn read_record(&mut self) -> Result<Option<ChainRecord>> {
+        if !self.pending_header {
+            loop {
+                if !self.read_line()? {
+                    return Ok(None);
+                }
+                let text = self.buf.trim();
+                if !text.is_empty() && !text.starts_with('#') {
+                    break;
+                }
+            }
+        }
+        self.pending_header = false;
+        let header_line = self.line;
+        let header = parse_header(header_line, &self.buf)?;
+        let mut alignments: Vec<Alignment> = Vec::new();
+        let mut block_separator = None;
+        let mut target_cursor = header.target.start;
+        let mut query_cursor = header.query.start;
+        while self.read_line()? {
+            if self.buf.trim().is_empty() {
+                break;
+            }
+            if self.buf.starts_with("chain") {
+                self.pending_header = true;
+                break;
+            }
+            let alignment = parse_block_line(self.line, &self.buf)?;
+            if target_cursor.saturating_add(alignment.size) > header.target.end
+                || query_cursor.saturating_add(alignment.size) > header.query.end
+            {
+                return Err(CrussmapError::chain_parse(
+                    self.line,
+                    1,
+                    "block runs past the end of the chain",
+                ));
+            }
+            target_cursor = target_cursor.saturating_add(alignment.size + alignment.target_diff);
+            query_cursor = query_cursor.saturating_add(alignment.size + alignment.query_diff);
+            alignments.push(alignment);
+            if block_separator.is_none() {
+                block_separator = self.buf.trim().chars().find(|x| x.is_whitespace());
+            }
+        }
+        if alignments.is_empty() {
+            return Err(CrussmapError::chain_parse(
+                header_line + 1,
+                1,
+                "no block line after header",
+            ));
+        }
+        let mut record = ChainRecord::new(header, alignments);
+        record.block_separator = block_separator.unwrap_or('\t');
+        Ok(Some(record))
+    }
+}
+
+impl<R: BufRead> Iterator for ChainReader<R> {
+    type Item = Result<ChainRecord>;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.0.is_empty() {
+        if let Some(e) = self.pending_error.take() {
+            self.done = true;
+            return Some(Err(e));
+        }
+        if self.done {
             return None;
         }
-        match chain_parser(self.0) {
-            Ok((i, r)) => {
-                self.0 = i;
-                Some(Ok(r))
+        match self.read_record() {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e @ CrussmapError::ChainParse { .. }) => {
+                self.skip_to_next_header();
+                Some(Err(e))
             }
             Err(e) => {
-                let mut msg = format!("{:?}", e);
-                msg.push_str(self.0);
-                Some(Err(msg))
+                self.done = true;
+                Some(Err(e))
             }
         }
     }
 }
 
-fn parse_header(header_line: &str) -> Result<Header, io::Error> {
-    // actually: it's few times to parse the header line
-    let header_vec: Vec<&str> = header_line.split_whitespace().collect();
-    if header_vec.len() != 12 {
-        error!("invalid header: {}", header_line);
-        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid header"));
-    }
-    let score = header_vec[0].parse::<f64>().unwrap();
-    let target = SeqInfo {
-        name: header_vec[1].to_string(),
-        size: header_vec[2].parse::<usize>().unwrap(),
-        strand: if header_vec[3] == "+" {
-            Strand::Positive
-        } else {
-            Strand::Negative
-        },
-        start: header_vec[4].parse::<usize>().unwrap(),
-        end: header_vec[5].parse::<usize>().unwrap(),
-    };
-    let query = SeqInfo {
-        name: header_vec[6].to_string(),
-        size: header_vec[7].parse::<usize>().unwrap(),
-        strand: if header_vec[8] == "+" {
-            Strand::Positive
-        } else {
-            Strand::Negative
-        },
-        start: header_vec[9].parse::<usize>().unwrap(),
-        end: header_vec[10].parse::<usize>().unwrap(),
-    };
-    let chain_id = header_vec[11].parse::<usize>().unwrap();
-    let header = Header {
-        score,
-        target,
-        query,
-        chain_id,
-    };
-    Ok(header)
+/// Iterator over the chains of a chain file held in memory, see [`ChainReader`].
+pub struct ChainRecords<'a> {
+    inner: ChainReader<&'a [u8]>,
 }
 
-fn line_not_chain(i: &str) -> IResult<&str, &str> {
-    terminated(is_not("chain\n"), line_ending)(i)
+impl<'a> ChainRecords<'a> {
+    pub fn new(data: &'a str) -> Self {
+        ChainRecords {
+            inner: ChainReader::new(data.as_bytes()),
+        }
+    }
 }
 
-fn blocks(i: &str, header: Header) -> IResult<&str, Vec<BlockIvl>> {
-    let target_name = &header.target.name;
-    let target_start = header.target.start;
-    let target_strand = &header.target.strand;
-    let query_name = &header.query.name;
-    let query_start = header.query.start;
-    let query_strand = &header.query.strand;
-    let mut target_current_cursor = target_start;
-    let mut query_current_cursor = query_start;
-    let query_size = header.query.size;
-    let x = fold_many1(line_not_chain, Vec::new, |mut acc: Vec<_>, x| {
-        let mut block_vec: Vec<&str> = x.split_whitespace().collect();
-        block_vec.push("0");
-        block_vec.push("0");
-        let size = block_vec[0].parse::<usize>().unwrap();
-        let target_diff = block_vec[1].parse::<usize>().unwrap();
-        let query_diff = block_vec[2].parse::<usize>().unwrap();
-        let alignment = Alignment {
-            size,
-            target_diff,
-            query_diff,
+impl<'a> Iterator for ChainRecords<'a> {
+    type Item = Result<ChainRecord>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+/// 1-based column of `field` in `line`, `field` must be a slice of `line`.
+fn column(line: &str, field: &str) -> usize {
+    field.as_ptr() as usize - line.as_ptr() as usize + 1
+}
+
+fn parse_field<T: FromStr>(line_no: usize, line: &str, field: &str, what: &str) -> Result<T> {
+    field.parse::<T>().map_err(|_| {
+        CrussmapError::chain_parse(
+            line_no,
+            column(line, field),
+            format!("invalid {}: {}", what, field),
+        )
+    })
+}
+
+fn parse_strand(line_no: usize, line: &str, field: &str) -> Result<Strand> {
+    match field {
+        "+" => Ok(Strand::Positive),
+        "-" => Ok(Strand::Negative),
+        _ => Err(CrussmapError::chain_parse(
+            line_no,
+            column(line, field),
+            format!("invalid strand: {}", field),
+        )),
+    }
+}
+
+/// Parse a `chain ...` header line, errors are reported on `line_no`.
+fn parse_header(line_no: usize, header_line: &str) -> Result<Header> {
+    let header_vec: Vec<&str> = header_line.split_whitespace().collect();
+    if header_vec.len() != 13 || header_vec[0] != "chain" {
+        return Err(CrussmapError::chain_parse(
+            line_no,
+            1,
+            format!(
+                "invalid header, expected `chain` and 12 fields: {}",
+                header_line
+            ),
+        ));
+    }
+    let seq_info = |side: &str, fields: &[&str]| -> Result<SeqInfo> {
+        let seq_info = SeqInfo {
+            name: fields[0].to_string(),
+            size: parse_field(line_no, header_line, fields[1], "size")?,
+            strand: parse_strand(line_no, header_line, fields[2])?,
+            start: parse_field(line_no, header_line, fields[3], "start")?,
+            end: parse_field(line_no, header_line, fields[4], "end")?,
         };
+        if seq_info.start > seq_info.end || seq_info.end > seq_info.size {
+            return Err(CrussmapError::chain_parse(
+                line_no,
+                column(header_line, fields[3]),
+                format!(
+                    "{} coordinates out of range: start {} end {} size {}",
+                    side, seq_info.start, seq_info.end, seq_info.size
+                ),
+            ));
+        }
+        Ok(seq_info)
+    };
+    Ok(Header {
+        score: parse_field(line_no, header_line, header_vec[1], "score")?,
+        target: seq_info("target", &header_vec[2..7])?,
+        query: seq_info("query", &header_vec[7..12])?,
+        chain_id: parse_field(line_no, header_line, header_vec[12], "chain id")?,
+    })
+}
 
+/// Blocks of both assemblies covered by the alignment lines of a chain.
+pub fn get_block_ivls(header: &Header, alignments: &[Alignment]) -> Vec<BlockIvl> {
+    let mut target_current_cursor = header.target.start;
+    let mut query_current_cursor = header.query.start;
+    let query_size = header.query.size;
+    let mut block_ivls = Vec::with_capacity(alignments.len());
+    for (block_index, alignment) in alignments.iter().enumerate() {
         let t2 = target_current_cursor;
         let t3 = target_current_cursor + alignment.size;
-        let (q2, q3) = match query_strand {
+        let (q2, q3) = match header.query.strand {
             Strand::Positive => (
                 (query_current_cursor),
                 (query_current_cursor + alignment.size),
@@ -178,34 +467,142 @@ fn blocks(i: &str, header: Header) -> IResult<&str, Vec<BlockIvl>> {
         target_current_cursor += alignment.size + alignment.target_diff;
         query_current_cursor += alignment.size + alignment.query_diff;
         let block_target = Block {
-            name: target_name.to_string(),
+            name: header.target.name.to_string(),
             start: t2,
             end: t3,
-            strand: *target_strand,
+            strand: header.target.strand,
+            chain_id: header.chain_id,
+            score: header.score,
+            block_index,
         };
         let block_query = Block {
-            name: query_name.to_string(),
+            name: header.query.name.to_string(),
             start: q2,
             end: q3,
-            strand: *query_strand,
+            strand: header.query.strand,
+            chain_id: header.chain_id,
+            score: header.score,
+            block_index,
         };
-        let block_ivl = get_block_ivl(block_target, block_query);
-        acc.push(block_ivl);
-        acc
-    })(i);
-    x
-}
-
-pub fn chain_parser(input: &str) -> IResult<&str, ChainRecord> {
-    let (input, _) = tag("chain")(input)?;
-    let (input, header_line) = not_line_ending(input)?;
-    let header = parse_header(header_line).unwrap();
-    let (input, _) = line_ending(input)?;
-    let (input, blocks) = blocks(input, header.clone())?;
-    let (input, _) = take_while(|x| x != 'c')(input)?; // should better
-    let chainrecord = ChainRecord {
-        block_ivls: blocks,
-        header,
+        block_ivls.push(get_block_ivl(block_target, block_query));
+    }
+    block_ivls
+}
+
+/// Parse a `size [dt dq]` block line.
+fn parse_block_line(line_no: usize, line: &str) -> Result<Alignment> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 1 && fields.len() != 3 {
+        return Err(CrussmapError::chain_parse(
+            line_no,
+            1,
+            format!("block line has {} fields, expected 1 or 3", fields.len()),
+        ));
+    }
+    let size = parse_field(line_no, line, fields[0], "block size")?;
+    let (target_diff, query_diff) = match fields.len() {
+        3 => (
+            parse_field(line_no, line, fields[1], "target gap")?,
+            parse_field(line_no, line, fields[2], "query gap")?,
+        ),
+        _ => (0, 0),
     };
-    Ok((input, chainrecord))
+    Ok(Alignment {
+        size,
+        target_diff,
+        query_diff,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::{fs::File, io::BufReader, io::Read};
+
+    #[test]
+    fn chain_files_round_trip() {
+        for name in [
+            "v2_to_v3.chain.gz",
+            "v3_to_v2.chain.gz",
+            "v4_to_v5.chain.gz",
+            "v5_to_v4.chain.gz",
+        ] {
+            let path = format!("{}/data/{}", env!("CARGO_MANIFEST_DIR"), name);
+            let mut text = String::new();
+            MultiGzDecoder::new(File::open(&path).unwrap())
+                .read_to_string(&mut text)
+                .unwrap();
+            let mut written: Vec<u8> = Vec::new();
+            for record in ChainReader::new(BufReader::new(text.as_bytes())) {
+                record.unwrap().write(&mut written).unwrap();
+            }
+            assert!(written == text.as_bytes(), "{} changed on round trip", name);
+        }
+    }
+
+    #[test]
+    fn tab_separated_blocks_are_kept() {
+        let text = "chain 5 chrA 100 + 0 30 chrB 90 - 10 45 7\n10\t5\t15\n10\n\n";
+        let record = ChainRecords::new(text).next().unwrap().unwrap();
+        assert_eq!(record.block_separator, '\t');
+        assert_eq!(record.to_string(), text);
+    }
+
+    #[test]
+    fn malformed_chain_is_skipped() {
+        let text = "chain 1 chrA 100 + 0 10 chrB 100 + 0 10 1\n10\n\n\
+                    chain 1 chrA 100 + 20 30 chrB 100 + 20 x 2\n10\n\n\
+                    chain 1 chrA 100 + 40 50 chrB 100 + 40 50 3\n5 q 0\n5\n\n\
+                    chain 1 chrA 100 + 60 70 chrB 100 + 60 70 4\n10\n";
+        let results: Vec<Result<ChainRecord>> = ChainRecords::new(text).collect();
+        let ids: Vec<Option<usize>> = results
+            .iter()
+            .map(|x| x.as_ref().ok().map(|x| x.header.chain_id))
+            .collect();
+        assert_eq!(ids, [Some(1), None, None, Some(4)]);
+        match &results[2] {
+            Err(CrussmapError::ChainParse { line, .. }) => assert_eq!(*line, 8),
+            _ => panic!("expected a parse error"),
+        }
+    }
+
+    #[test]
+    fn out_of_range_chains_are_errors() {
+        let text = "chain 1 a 100 + 0 10 b 5 - 0 10 1\n10\n\n\
+                    chain 1 a 100 + 20 10 b 100 + 0 10 2\n10\n\n\
+                    chain 1 a 100 + 0 10 b 100 - 0 10 3\n5\t0\t1\n5\n\n\
+                    chain 1 a 100 + 0 10 b 100 - 90 100 4\n10\n";
+        let results: Vec<Result<ChainRecord>> = ChainRecords::new(text).collect();
+        let errors: Vec<(usize, usize, String)> = results[..3]
+            .iter()
+            .map(|x| match x {
+                Err(CrussmapError::ChainParse {
+                    line,
+                    column,
+                    message,
+                }) => (*line, *column, message.clone()),
+                _ => panic!("expected a parse error"),
+            })
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                (
+                    1,
+                    28,
+                    "query coordinates out of range: start 0 end 10 size 5".to_string()
+                ),
+                (
+                    4,
+                    17,
+                    "target coordinates out of range: start 20 end 10 size 100".to_string()
+                ),
+                (9, 1, "block runs past the end of the chain".to_string()),
+            ]
+        );
+        let record = results[3].as_ref().unwrap();
+        assert_eq!(record.block_ivls[0].val.start, 0);
+        assert_eq!(record.block_ivls[0].val.end, 10);
+    }
 }
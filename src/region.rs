@@ -0,0 +1,178 @@
+use crate::{
+    error::Result,
+    interval::{find_in_lapper, get_lapper_hashmap, Region},
+    parser::Strand,
+    utils::{get_file_reader, get_output_writer},
+};
+use log::info;
+use std::io::{BufRead, BufReader, Write};
+
+/// Parse a `chr1:1,000-2,000[:+]` region string, 1-based and inclusive like the
+/// genome browsers, into chrom, 0-based half-open start/end and strand. A bare
+/// `chr1` covers the whole chromosome, its end is `usize::MAX`.
+pub fn parse_region_str(region: &str) -> Option<(String, usize, usize, Strand)> {
+    let region = region.trim().replace(',', "");
+    let (region, strand) = if let Some(x) = region.strip_suffix(":+") {
+        (x, Strand::Positive)
+    } else if let Some(x) = region.strip_suffix(":-") {
+        (x, Strand::Negative)
+    } else {
+        (region.as_str(), Strand::Positive)
+    };
+    let (chrom, range) = match region.rsplit_once(':') {
+        Some(x) => x,
+        None if !region.is_empty() => return Some((region.to_string(), 0, usize::MAX, strand)),
+        None => return None,
+    };
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (start.parse::<usize>().ok()?, end.parse::<usize>().ok()?),
+        None => {
+            let pos = range.parse::<usize>().ok()?;
+            (pos, pos)
+        }
+    };
+    if chrom.is_empty() || start == 0 || start > end {
+        return None;
+    }
+    Some((chrom.to_string(), start - 1, end, strand))
+}
+
+fn format_region(region: &Region) -> String {
+    format!(
+        "{}:{}-{}:{}",
+        region.chrom,
+        region.start + 1,
+        region.end,
+        region.strand
+    )
+}
+
+pub fn cross_region(
+    regions: &[String],
+    region_file: &Option<String>,
+    input_chain: &Option<String>,
+    output: &Option<String>,
+    rewrite: bool,
+) -> Result<()> {
+    let lapper_hashmap = get_lapper_hashmap(input_chain)?;
+    info!("get lapper hashmap done!");
+    let (mut output_file, _) = get_output_writer(output, rewrite)?;
+    let mut all_regions: Vec<String> = regions.to_vec();
+    if let Some(region_file) = region_file {
+        let reader = BufReader::new(get_file_reader(region_file)?);
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                all_regions.push(line.to_string());
+            }
+        }
+    }
+
+    for region_str in all_regions {
+        let (chrom, start, end, strand) = match parse_region_str(&region_str) {
+            Some(x) => x,
+            None => {
+                output_file
+                    .write_all(format!("{}\tUNMAP\tinvalid region\n", region_str).as_bytes())?;
+                continue;
+            }
+        };
+        let q_region = Region {
+            chrom: &chrom,
+            start,
+            end,
+            strand,
+        };
+        let matches = match find_in_lapper(&lapper_hashmap, &q_region) {
+            Some(matches) => matches,
+            None => {
+                let reason = match lapper_hashmap.contains_key(&chrom) {
+                    true => "no overlapping chain block",
+                    false => "chrom not in chain file",
+                };
+                output_file.write_all(format!("{}\tUNMAP\t{}\n", region_str, reason).as_bytes())?;
+                continue;
+            }
+        };
+        let hit_multi = matches.len() > 2;
+        for (count, pair) in matches.chunks(2).enumerate() {
+            let hit_info = match hit_multi {
+                true => format!("(split.{}:{})", count + 1, format_region(&pair[0])),
+                false => "->".to_string(),
+            };
+            output_file.write_all(
+                format!(
+                    "{}\t{}\t{}\n",
+                    region_str,
+                    hit_info,
+                    format_region(&pair[1])
+                )
+                .as_bytes(),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(
+        chrom: &str,
+        start: usize,
+        end: usize,
+        strand: Strand,
+    ) -> Option<(String, usize, usize, Strand)> {
+        Some((chrom.to_string(), start, end, strand))
+    }
+
+    #[test]
+    fn accepted_regions() {
+        assert_eq!(
+            parse_region_str("chr1:1-10"),
+            region("chr1", 0, 10, Strand::Positive)
+        );
+        assert_eq!(
+            parse_region_str(" chr1:1,000-2,000 "),
+            region("chr1", 999, 2000, Strand::Positive)
+        );
+        assert_eq!(
+            parse_region_str("chr1:5-6:-"),
+            region("chr1", 4, 6, Strand::Negative)
+        );
+        assert_eq!(
+            parse_region_str("chr1:5:+"),
+            region("chr1", 4, 5, Strand::Positive)
+        );
+        assert_eq!(
+            parse_region_str("HLA-A*01:01:1-3"),
+            region("HLA-A*01:01", 0, 3, Strand::Positive)
+        );
+        assert_eq!(
+            parse_region_str("chr1"),
+            region("chr1", 0, usize::MAX, Strand::Positive)
+        );
+        assert_eq!(
+            parse_region_str("chr1:-"),
+            region("chr1", 0, usize::MAX, Strand::Negative)
+        );
+    }
+
+    #[test]
+    fn rejected_regions() {
+        for x in [
+            "chr1:10-5",
+            "chr1:a-10",
+            "chr1:1-1e3",
+            "chr1:0-10",
+            "chr1:",
+            ":1-10",
+            "",
+            "chr1:1-",
+        ] {
+            assert_eq!(parse_region_str(x), None, "{}", x);
+        }
+    }
+}
@@ -0,0 +1,723 @@
+use crate::{bgzf::BgzfWriter, utils::reg2bin};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+};
+
+const BAM_MAGIC: &[u8; 4] = b"BAM\x01";
+const CIGAR_OPS: &[u8; 9] = b"MIDNSHP=X";
+const SEQ_CODES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+fn invalid_data<E: fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CigarOp {
+    pub len: u32,
+    /// one of `MIDNSHP=X`
+    pub op: u8,
+}
+
+impl CigarOp {
+    pub fn consumes_ref(&self) -> bool {
+        matches!(self.op, b'M' | b'D' | b'N' | b'=' | b'X')
+    }
+
+    pub fn consumes_read(&self) -> bool {
+        matches!(self.op, b'M' | b'I' | b'S' | b'=' | b'X')
+    }
+}
+
+pub fn parse_cigar(cigar: &str) -> io::Result<Vec<CigarOp>> {
+    let mut ops = Vec::new();
+    if cigar == "*" {
+        return Ok(ops);
+    }
+    let mut len: u32 = 0;
+    for b in cigar.bytes() {
+        if b.is_ascii_digit() {
+            len = len * 10 + (b - b'0') as u32;
+        } else if CIGAR_OPS.contains(&b) {
+            ops.push(CigarOp { len, op: b });
+            len = 0;
+        } else {
+            return Err(invalid_data(format!("invalid CIGAR: {}", cigar)));
+        }
+    }
+    Ok(ops)
+}
+
+pub fn cigar_to_string(cigar: &[CigarOp]) -> String {
+    if cigar.is_empty() {
+        return "*".to_string();
+    }
+    cigar
+        .iter()
+        .map(|x| format!("{}{}", x.len, x.op as char))
+        .collect()
+}
+
+/// One alignment line in SAM text representation, BAM records are decoded into it.
+#[derive(Debug, Clone)]
+pub struct SamRecord {
+    pub qname: String,
+    pub flag: u16,
+    pub rname: String,
+    /// 1-based, 0 when unavailable
+    pub pos: usize,
+    pub mapq: u8,
+    pub cigar: Vec<CigarOp>,
+    pub rnext: String,
+    /// 1-based, 0 when unavailable
+    pub pnext: usize,
+    pub tlen: i64,
+    pub seq: String,
+    pub qual: String,
+    /// optional fields in `TAG:TYPE:VALUE` form
+    pub tags: Vec<String>,
+}
+
+impl SamRecord {
+    pub fn from_line(line: &str) -> io::Result<Self> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 11 {
+            return Err(invalid_data(format!(
+                "SAM record with {} fields",
+                fields.len()
+            )));
+        }
+        Ok(SamRecord {
+            qname: fields[0].to_string(),
+            flag: fields[1].parse().map_err(invalid_data)?,
+            rname: fields[2].to_string(),
+            pos: fields[3].parse().map_err(invalid_data)?,
+            mapq: fields[4].parse().map_err(invalid_data)?,
+            cigar: parse_cigar(fields[5])?,
+            rnext: fields[6].to_string(),
+            pnext: fields[7].parse().map_err(invalid_data)?,
+            tlen: fields[8].parse().map_err(invalid_data)?,
+            seq: fields[9].to_string(),
+            qual: fields[10].to_string(),
+            tags: fields[11..].iter().map(|x| x.to_string()).collect(),
+        })
+    }
+
+    pub fn is_unmapped(&self) -> bool {
+        self.flag & 0x4 != 0
+    }
+
+    /// Length of reference covered by the CIGAR.
+    pub fn ref_len(&self) -> usize {
+        self.cigar
+            .iter()
+            .filter(|x| x.consumes_ref())
+            .map(|x| x.len as usize)
+            .sum()
+    }
+
+    pub fn remove_tag(&mut self, tag: &str) {
+        self.tags.retain(|x| !x.starts_with(&format!("{}:", tag)));
+    }
+
+    pub fn set_tag(&mut self, tag: &str, typ: char, value: &str) {
+        self.remove_tag(tag);
+        self.tags.push(format!("{}:{}:{}", tag, typ, value));
+    }
+}
+
+impl fmt::Display for SamRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.qname,
+            self.flag,
+            self.rname,
+            self.pos,
+            self.mapq,
+            cigar_to_string(&self.cigar),
+            self.rnext,
+            self.pnext,
+            self.tlen,
+            self.seq,
+            self.qual
+        )?;
+        for tag in &self.tags {
+            write!(f, "\t{}", tag)?;
+        }
+        Ok(())
+    }
+}
+
+/// Header text and the reference sequence dictionary.
+#[derive(Debug, Clone, Default)]
+pub struct SamHeader {
+    /// header lines without the `@SQ` lines
+    pub lines: Vec<String>,
+    pub refs: Vec<(String, usize)>,
+}
+
+impl SamHeader {
+    fn push_line(&mut self, line: &str) {
+        if let Some(sq) = line.strip_prefix("@SQ\t") {
+            let mut name = None;
+            let mut len = 0;
+            for field in sq.split('\t') {
+                if let Some(x) = field.strip_prefix("SN:") {
+                    name = Some(x.to_string());
+                } else if let Some(x) = field.strip_prefix("LN:") {
+                    len = x.parse().unwrap_or(0);
+                }
+            }
+            if let Some(name) = name {
+                self.refs.push((name, len));
+            }
+        } else if !line.is_empty() {
+            self.lines.push(line.to_string());
+        }
+    }
+
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        let mut lines = self.lines.iter().peekable();
+        // @HD must stay first, @SQ lines go right after it
+        if let Some(hd) = lines.next_if(|x| x.starts_with("@HD")) {
+            text.push_str(hd);
+            text.push('\n');
+        }
+        for (name, len) in &self.refs {
+            text.push_str(&format!("@SQ\tSN:{}\tLN:{}\n", name, len));
+        }
+        for line in lines {
+            text.push_str(line);
+            text.push('\n');
+        }
+        text
+    }
+}
+
+/// Reader of SAM text or BAM, picked from the leading bytes of the input. BAM must
+/// already be decompressed, as [`crate::utils::get_file_reader`] does.
+pub enum SamReader {
+    Sam(Box<dyn BufRead>, Option<String>),
+    Bam(Box<dyn BufRead>, Vec<(String, usize)>),
+}
+
+impl SamReader {
+    pub fn new<R: Read + 'static>(reader: R) -> io::Result<(Self, SamHeader)> {
+        let mut reader: Box<dyn BufRead> = Box::new(BufReader::new(reader));
+        let mut header = SamHeader::default();
+        if reader.fill_buf()?.starts_with(BAM_MAGIC) {
+            reader.consume(4);
+            let l_text = read_i32(&mut reader)? as usize;
+            let mut text = vec![0u8; l_text];
+            reader.read_exact(&mut text)?;
+            let text = String::from_utf8_lossy(&text);
+            for line in text.trim_end_matches('\0').lines() {
+                header.push_line(line);
+            }
+            let n_ref = read_i32(&mut reader)?;
+            let mut refs = Vec::new();
+            for _ in 0..n_ref {
+                let l_name = read_i32(&mut reader)? as usize;
+                if l_name == 0 {
+                    return Err(invalid_data("empty BAM reference name"));
+                }
+                let mut name = vec![0u8; l_name];
+                reader.read_exact(&mut name)?;
+                let name = String::from_utf8_lossy(&name[..l_name - 1]).to_string();
+                let l_ref = read_i32(&mut reader)? as usize;
+                refs.push((name, l_ref));
+            }
+            // the binary dictionary is authoritative
+            header.refs = refs.clone();
+            return Ok((SamReader::Bam(reader, refs), header));
+        }
+        let mut first_record = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.starts_with('@') {
+                header.push_line(line);
+            } else {
+                first_record = Some(line.to_string());
+                break;
+            }
+        }
+        Ok((SamReader::Sam(reader, first_record), header))
+    }
+
+    pub fn read_record(&mut self) -> io::Result<Option<SamRecord>> {
+        match self {
+            SamReader::Sam(reader, pending) => loop {
+                let line = match pending.take() {
+                    Some(line) => line,
+                    None => {
+                        let mut line = String::new();
+                        if reader.read_line(&mut line)? == 0 {
+                            return Ok(None);
+                        }
+                        line.trim_end_matches(['\n', '\r']).to_string()
+                    }
+                };
+                if !line.is_empty() {
+                    return SamRecord::from_line(&line).map(Some);
+                }
+            },
+            SamReader::Bam(reader, refs) => {
+                let mut size = [0u8; 4];
+                match reader.read_exact(&mut size) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+                    Err(e) => return Err(e),
+                }
+                let mut data = vec![0u8; u32::from_le_bytes(size) as usize];
+                reader.read_exact(&mut data)?;
+                decode_bam_record(&data, refs).map(Some)
+            }
+        }
+    }
+}
+
+impl Iterator for SamReader {
+    type Item = io::Result<SamRecord>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+fn read_i32<R: Read>(reader: &mut R) -> io::Result<i32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+/// Little endian cursor over the bytes of one BAM record.
+struct ByteCursor<'a>(&'a [u8]);
+
+impl<'a> ByteCursor<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid_data("truncated BAM record"));
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn take_until_nul(&mut self) -> io::Result<&'a [u8]> {
+        let end = self
+            .0
+            .iter()
+            .position(|&x| x == 0)
+            .ok_or_else(|| invalid_data("unterminated BAM string"))?;
+        let s = self.take(end)?;
+        self.take(1)?;
+        Ok(s)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(self.u32()? as i32)
+    }
+}
+
+fn ref_name(refs: &[(String, usize)], ref_id: i32) -> String {
+    match usize::try_from(ref_id).ok().and_then(|i| refs.get(i)) {
+        Some((name, _)) => name.clone(),
+        None => "*".to_string(),
+    }
+}
+
+fn decode_bam_record(data: &[u8], refs: &[(String, usize)]) -> io::Result<SamRecord> {
+    let mut cur = ByteCursor(data);
+    let ref_id = cur.i32()?;
+    let pos = cur.i32()?;
+    let l_read_name = cur.u8()? as usize;
+    let mapq = cur.u8()?;
+    let _bin = cur.u16()?;
+    let n_cigar_op = cur.u16()? as usize;
+    let flag = cur.u16()?;
+    let l_seq = cur.u32()? as usize;
+    let next_ref_id = cur.i32()?;
+    let next_pos = cur.i32()?;
+    let tlen = cur.i32()?;
+    if l_read_name == 0 {
+        return Err(invalid_data("empty BAM read name"));
+    }
+    let read_name = cur.take(l_read_name)?;
+    let qname = String::from_utf8_lossy(&read_name[..l_read_name - 1]).to_string();
+    let mut cigar = Vec::with_capacity(n_cigar_op);
+    for _ in 0..n_cigar_op {
+        let x = cur.u32()?;
+        cigar.push(CigarOp {
+            len: x >> 4,
+            op: *CIGAR_OPS
+                .get((x & 0xf) as usize)
+                .ok_or_else(|| invalid_data("invalid BAM CIGAR op"))?,
+        });
+    }
+    let packed = cur.take(l_seq.div_ceil(2))?;
+    let seq: String = match l_seq {
+        0 => "*".to_string(),
+        _ => (0..l_seq)
+            .map(|i| {
+                let code = match i % 2 {
+                    0 => packed[i / 2] >> 4,
+                    _ => packed[i / 2] & 0xf,
+                };
+                SEQ_CODES[code as usize] as char
+            })
+            .collect(),
+    };
+    let qual_bytes = cur.take(l_seq)?;
+    let qual: String = match qual_bytes.first() {
+        None | Some(0xff) => "*".to_string(),
+        _ => qual_bytes
+            .iter()
+            .map(|&q| q.saturating_add(33) as char)
+            .collect(),
+    };
+    let mut tags = Vec::new();
+    while !cur.0.is_empty() {
+        tags.push(decode_bam_tag(&mut cur)?);
+    }
+    let rname = ref_name(refs, ref_id);
+    let rnext = match next_ref_id {
+        -1 => "*".to_string(),
+        x if x == ref_id => "=".to_string(),
+        x => ref_name(refs, x),
+    };
+    Ok(SamRecord {
+        qname,
+        flag,
+        rname,
+        pos: (pos + 1) as usize,
+        mapq,
+        cigar,
+        rnext,
+        pnext: (next_pos + 1) as usize,
+        tlen: tlen as i64,
+        seq,
+        qual,
+        tags,
+    })
+}
+
+fn decode_bam_value(cur: &mut ByteCursor, typ: u8) -> io::Result<String> {
+    let value = match typ {
+        b'c' => (cur.u8()? as i8).to_string(),
+        b'C' => cur.u8()?.to_string(),
+        b's' => (cur.u16()? as i16).to_string(),
+        b'S' => cur.u16()?.to_string(),
+        b'i' => cur.i32()?.to_string(),
+        b'I' => cur.u32()?.to_string(),
+        b'f' => f32::from_bits(cur.u32()?).to_string(),
+        _ => {
+            return Err(invalid_data(format!(
+                "invalid BAM tag type {}",
+                typ as char
+            )))
+        }
+    };
+    Ok(value)
+}
+
+fn decode_bam_tag(cur: &mut ByteCursor) -> io::Result<String> {
+    let tag = String::from_utf8_lossy(cur.take(2)?).to_string();
+    let typ = cur.u8()?;
+    let text = match typ {
+        b'A' => format!("{}:A:{}", tag, cur.u8()? as char),
+        b'Z' | b'H' => format!(
+            "{}:{}:{}",
+            tag,
+            typ as char,
+            String::from_utf8_lossy(cur.take_until_nul()?)
+        ),
+        b'B' => {
+            let sub = cur.u8()?;
+            let count = cur.u32()?;
+            let mut text = format!("{}:B:{}", tag, sub as char);
+            for _ in 0..count {
+                text.push(',');
+                text.push_str(&decode_bam_value(cur, sub)?);
+            }
+            text
+        }
+        b'c' | b'C' | b's' | b'S' | b'i' | b'I' => {
+            format!("{}:i:{}", tag, decode_bam_value(cur, typ)?)
+        }
+        _ => format!("{}:f:{}", tag, decode_bam_value(cur, typ)?),
+    };
+    Ok(text)
+}
+
+/// Smallest BAM integer type holding `value`, as samtools does when converting SAM.
+fn encode_bam_int(value: i64, out: &mut Vec<u8>) {
+    if value < 0 {
+        if value >= i8::MIN as i64 {
+            out.push(b'c');
+            out.push(value as i8 as u8);
+        } else if value >= i16::MIN as i64 {
+            out.push(b's');
+            out.extend_from_slice(&(value as i16).to_le_bytes());
+        } else {
+            out.push(b'i');
+            out.extend_from_slice(&(value as i32).to_le_bytes());
+        }
+    } else if value <= u8::MAX as i64 {
+        out.push(b'C');
+        out.push(value as u8);
+    } else if value <= u16::MAX as i64 {
+        out.push(b'S');
+        out.extend_from_slice(&(value as u16).to_le_bytes());
+    } else {
+        out.push(b'I');
+        out.extend_from_slice(&(value as u32).to_le_bytes());
+    }
+}
+
+fn encode_bam_array_value(sub: u8, value: &str, out: &mut Vec<u8>) -> io::Result<()> {
+    match sub {
+        b'c' => out.push(value.parse::<i8>().map_err(invalid_data)? as u8),
+        b'C' => out.push(value.parse::<u8>().map_err(invalid_data)?),
+        b's' => out.extend_from_slice(&value.parse::<i16>().map_err(invalid_data)?.to_le_bytes()),
+        b'S' => out.extend_from_slice(&value.parse::<u16>().map_err(invalid_data)?.to_le_bytes()),
+        b'i' => out.extend_from_slice(&value.parse::<i32>().map_err(invalid_data)?.to_le_bytes()),
+        b'I' => out.extend_from_slice(&value.parse::<u32>().map_err(invalid_data)?.to_le_bytes()),
+        b'f' => out.extend_from_slice(&value.parse::<f32>().map_err(invalid_data)?.to_le_bytes()),
+        _ => {
+            return Err(invalid_data(format!(
+                "invalid B array type {}",
+                sub as char
+            )))
+        }
+    }
+    Ok(())
+}
+
+fn encode_bam_tag(tag: &str, out: &mut Vec<u8>) -> io::Result<()> {
+    let mut parts = tag.splitn(3, ':');
+    let (name, typ, value) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(name), Some(typ), Some(value)) if name.len() == 2 && typ.len() == 1 => {
+            (name, typ.as_bytes()[0], value)
+        }
+        _ => return Err(invalid_data(format!("invalid SAM tag: {}", tag))),
+    };
+    out.extend_from_slice(name.as_bytes());
+    match typ {
+        b'A' => {
+            out.push(b'A');
+            out.push(*value.as_bytes().first().unwrap_or(&b' '));
+        }
+        b'i' => encode_bam_int(value.parse::<i64>().map_err(invalid_data)?, out),
+        b'f' => {
+            out.push(b'f');
+            out.extend_from_slice(&value.parse::<f32>().map_err(invalid_data)?.to_le_bytes());
+        }
+        b'Z' | b'H' => {
+            out.push(typ);
+            out.extend_from_slice(value.as_bytes());
+            out.push(0);
+        }
+        b'B' => {
+            let mut values = value.split(',');
+            let sub = values.next().unwrap_or("").as_bytes().first().copied();
+            let sub = sub.ok_or_else(|| invalid_data(format!("invalid SAM tag: {}", tag)))?;
+            let values: Vec<&str> = values.collect();
+            out.push(b'B');
+            out.push(sub);
+            out.extend_from_slice(&(values.len() as u32).to_le_bytes());
+            for v in values {
+                encode_bam_array_value(sub, v, out)?;
+            }
+        }
+        _ => return Err(invalid_data(format!("invalid SAM tag: {}", tag))),
+    }
+    Ok(())
+}
+
+fn encode_bam_record(record: &SamRecord, ref_ids: &HashMap<String, i32>) -> io::Result<Vec<u8>> {
+    let ref_id = match record.rname.as_str() {
+        "*" => -1,
+        name => *ref_ids
+            .get(name)
+            .ok_or_else(|| invalid_data(format!("{} not found in BAM header", name)))?,
+    };
+    let next_ref_id = match record.rnext.as_str() {
+        "*" => -1,
+        "=" => ref_id,
+        name => *ref_ids
+            .get(name)
+            .ok_or_else(|| invalid_data(format!("{} not found in BAM header", name)))?,
+    };
+    let pos = record.pos as i64 - 1;
+    let end = match record.ref_len() {
+        0 => pos + 1,
+        x => pos + x as i64,
+    };
+    let bin = match pos {
+        -1 => 4680,
+        // BAI binning scheme: 16 kb smallest bins, 5 levels
+        _ => reg2bin(pos as u64, end as u64, 14, 5) as u16,
+    };
+    let seq = match record.seq.as_str() {
+        "*" => "",
+        x => x,
+    };
+    let mut data = Vec::new();
+    data.extend_from_slice(&ref_id.to_le_bytes());
+    data.extend_from_slice(&(pos as i32).to_le_bytes());
+    data.push((record.qname.len() + 1) as u8);
+    data.push(record.mapq);
+    data.extend_from_slice(&bin.to_le_bytes());
+    data.extend_from_slice(&(record.cigar.len() as u16).to_le_bytes());
+    data.extend_from_slice(&record.flag.to_le_bytes());
+    data.extend_from_slice(&(seq.len() as u32).to_le_bytes());
+    data.extend_from_slice(&next_ref_id.to_le_bytes());
+    data.extend_from_slice(&(record.pnext as i32 - 1).to_le_bytes());
+    data.extend_from_slice(&(record.tlen as i32).to_le_bytes());
+    data.extend_from_slice(record.qname.as_bytes());
+    data.push(0);
+    for op in &record.cigar {
+        let code = CIGAR_OPS.iter().position(|&x| x == op.op).unwrap() as u32;
+        data.extend_from_slice(&(op.len << 4 | code).to_le_bytes());
+    }
+    let codes: Vec<u8> = seq
+        .bytes()
+        .map(|b| {
+            SEQ_CODES
+                .iter()
+                .position(|&x| x == b.to_ascii_uppercase())
+                .unwrap_or(15) as u8
+        })
+        .collect();
+    for pair in codes.chunks(2) {
+        data.push(pair[0] << 4 | pair.get(1).copied().unwrap_or(0));
+    }
+    match record.qual.as_str() {
+        "*" => data.extend(std::iter::repeat_n(0xff, seq.len())),
+        qual => data.extend(qual.bytes().map(|q| q.saturating_sub(33))),
+    }
+    for tag in &record.tags {
+        encode_bam_tag(tag, &mut data)?;
+    }
+    Ok(data)
+}
+
+/// Writer of SAM text or BAM.
+pub enum SamWriter {
+    Sam(Box<dyn Write>),
+    Bam(BgzfWriter<Box<dyn Write>>, HashMap<String, i32>),
+}
+
+impl SamWriter {
+    pub fn new(writer: Box<dyn Write>, bam: bool) -> Self {
+        match bam {
+            true => SamWriter::Bam(BgzfWriter::new(writer), HashMap::new()),
+            false => SamWriter::Sam(writer),
+        }
+    }
+
+    pub fn write_header(&mut self, header: &SamHeader) -> io::Result<()> {
+        let text = header.text();
+        match self {
+            SamWriter::Sam(writer) => writer.write_all(text.as_bytes()),
+            SamWriter::Bam(writer, ref_ids) => {
+                writer.write_all(BAM_MAGIC)?;
+                writer.write_all(&(text.len() as i32).to_le_bytes())?;
+                writer.write_all(text.as_bytes())?;
+                writer.write_all(&(header.refs.len() as i32).to_le_bytes())?;
+                for (i, (name, len)) in header.refs.iter().enumerate() {
+                    writer.write_all(&(name.len() as i32 + 1).to_le_bytes())?;
+                    writer.write_all(name.as_bytes())?;
+                    writer.write_all(&[0])?;
+                    writer.write_all(&(*len as i32).to_le_bytes())?;
+                    ref_ids.insert(name.clone(), i as i32);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn write_record(&mut self, record: &SamRecord) -> io::Result<()> {
+        match self {
+            SamWriter::Sam(writer) => writer.write_all(format!("{}\n", record).as_bytes()),
+            SamWriter::Bam(writer, ref_ids) => {
+                let data = encode_bam_record(record, ref_ids)?;
+                writer.write_all(&(data.len() as u32).to_le_bytes())?;
+                writer.write_all(&data)
+            }
+        }
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self {
+            SamWriter::Sam(writer) => writer.flush(),
+            SamWriter::Bam(writer, _) => writer.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bam_record_round_trip() {
+        let refs = vec![("chr1".to_string(), 1000), ("chr2".to_string(), 2000)];
+        let ref_ids: HashMap<String, i32> = refs
+            .iter()
+            .enumerate()
+            .map(|(i, (name, _))| (name.clone(), i as i32))
+            .collect();
+        for line in [
+            "r1\t99\tchr1\t100\t60\t5M1I2D3M\t=\t300\t210\tACGTAcGTN\tIIIIIIII#\tNM:i:3\tXA:A:x\tRG:Z:grp 1",
+            "r2\t147\tchr2\t1\t0\t2S7M\tchr1\t100\t-210\tACGTACGTA\t*\tXi:i:-200\tXj:i:70000\tXf:f:1.5\tXb:B:c,-1,2\tXh:H:1AE3",
+            "r3\t4\t*\t0\t0\t*\t*\t0\t0\tACG\t!!+",
+            "r4\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*",
+        ] {
+            let record = SamRecord::from_line(line).unwrap();
+            let data = encode_bam_record(&record, &ref_ids).unwrap();
+            let decoded = decode_bam_record(&data, &refs).unwrap();
+            assert_eq!(decoded.to_string(), line.replace("ACGTAcGTN", "ACGTACGTN"));
+        }
+    }
+
+    #[test]
+    fn empty_read_name_is_an_error() {
+        let ref_ids = HashMap::new();
+        let record = SamRecord::from_line("r\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*").unwrap();
+        let mut data = encode_bam_record(&record, &ref_ids).unwrap();
+        data[8] = 0;
+        assert!(decode_bam_record(&data, &[]).is_err());
+    }
+
+    #[test]
+    fn bam_record_bin() {
+        let ref_ids = HashMap::from([("chr1".to_string(), 0)]);
+        let bin = |line: &str| {
+            let data = encode_bam_record(&SamRecord::from_line(line).unwrap(), &ref_ids).unwrap();
+            u16::from_le_bytes([data[10], data[11]])
+        };
+        assert_eq!(bin("r\t0\tchr1\t100\t60\t10M\t*\t0\t0\t*\t*"), 4681);
+        assert_eq!(bin("r\t0\tchr1\t16380\t60\t10M\t*\t0\t0\t*\t*"), 585);
+        assert_eq!(bin("r\t4\t*\t0\t0\t*\t*\t0\t0\t*\t*"), 4680);
+    }
+}
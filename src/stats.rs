@@ -0,0 +1,391 @@
+use crate::{
+    error::Result,
+    parser::{ChainReader, ChainRecord},
+    utils::{get_input_reader, get_output_writer},
+};
+use log::info;
+use std::{
+    borrow::Borrow,
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainStats {
+    pub chain_id: usize,
+    pub score: f64,
+    pub target_name: String,
+    pub target_start: usize,
+    pub target_end: usize,
+    pub query_name: String,
+    pub query_start: usize,
+    pub query_end: usize,
+    pub query_strand: String,
+    pub blocks: usize,
+    pub aligned_bases: usize,
+    pub target_gap: usize,
+    pub query_gap: usize,
+}
+
+impl ChainStats {
+    pub fn from_record(record: &ChainRecord) -> Self {
+        let header = &record.header;
+        ChainStats {
+            chain_id: header.chain_id,
+            score: header.score,
+            target_name: header.target.name.clone(),
+            target_start: header.target.start,
+            target_end: header.target.end,
+            query_name: header.query.name.clone(),
+            query_start: header.query.start,
+            query_end: header.query.end,
+            query_strand: header.query.strand.to_string(),
+            blocks: record.alignments.len(),
+            aligned_bases: record.alignments.iter().map(|x| x.size).sum(),
+            target_gap: record.alignments.iter().map(|x| x.target_diff).sum(),
+            query_gap: record.alignments.iter().map(|x| x.query_diff).sum(),
+        }
+    }
+}
+
+/// Target (old assembly) chromosome metrics.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChromStats {
+    pub name: String,
+    pub size: usize,
+    pub chains: usize,
+    pub blocks: usize,
+    pub aligned_bases: usize,
+    /// bases in at least one block
+    pub covered_bases: usize,
+    pub coverage: f64,
+    /// bases in blocks of more than one chain
+    pub multi_covered_bases: usize,
+    pub multi_coverage: f64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ScoreStats {
+    pub min: f64,
+    pub q1: f64,
+    pub median: f64,
+    pub q3: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+impl ScoreStats {
+    fn from_scores(mut scores: Vec<f64>) -> Self {
+        if scores.is_empty() {
+            return ScoreStats::default();
+        }
+        scores.sort_by(|a, b| a.total_cmp(b));
+        // nearest rank
+        let quantile = |q: f64| scores[((scores.len() - 1) as f64 * q).round() as usize];
+        ScoreStats {
+            min: scores[0],
+            q1: quantile(0.25),
+            median: quantile(0.5),
+            q3: quantile(0.75),
+            max: scores[scores.len() - 1],
+            mean: scores.iter().sum::<f64>() / scores.len() as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Summary {
+    pub chains: usize,
+    pub blocks: usize,
+    pub aligned_bases: usize,
+    pub target_gap: usize,
+    pub query_gap: usize,
+    pub target_size: usize,
+    pub covered_bases: usize,
+    pub coverage: f64,
+    pub multi_covered_bases: usize,
+    pub multi_coverage: f64,
+    pub score: ScoreStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChainFileStats {
+    pub summary: Summary,
+    pub chromosomes: Vec<ChromStats>,
+    pub chains: Vec<ChainStats>,
+}
+
+/// Bases covered by at least one and by at least two of `ivls`, with blocks of one
+/// chain never overlapping each other.
+fn coverage(ivls: &[(usize, usize)]) -> (usize, usize) {
+    let mut events: Vec<(usize, i64)> = Vec::with_capacity(ivls.len() * 2);
+    for &(start, end) in ivls {
+        events.push((start, 1));
+        events.push((end, -1));
+    }
+    events.sort_unstable();
+    let (mut covered, mut multi_covered) = (0, 0);
+    let mut depth = 0;
+    let mut last = 0;
+    for (pos, delta) in events {
+        if depth >= 1 {
+            covered += pos - last;
+        }
+        if depth >= 2 {
+            multi_covered += pos - last;
+        }
+        depth += delta;
+        last = pos;
+    }
+    (covered, multi_covered)
+}
+
+pub fn get_chain_stats<T: Borrow<ChainRecord>>(
+    records: impl IntoIterator<Item = T>,
+) -> ChainFileStats {
+    let mut chains: Vec<ChainStats> = Vec::new();
+    let mut chroms: BTreeMap<String, (ChromStats, Vec<(usize, usize)>)> = BTreeMap::new();
+    for record in records {
+        let record = record.borrow();
+        let chain_stats = ChainStats::from_record(record);
+        let (chrom, ivls) = chroms
+            .entry(chain_stats.target_name.clone())
+            .or_insert_with(|| {
+                (
+                    ChromStats {
+                        name: chain_stats.target_name.clone(),
+                        size: record.header.target.size,
+                        ..Default::default()
+                    },
+                    Vec::new(),
+                )
+            });
+        chrom.chains += 1;
+        chrom.blocks += chain_stats.blocks;
+        chrom.aligned_bases += chain_stats.aligned_bases;
+        ivls.extend(record.block_ivls.iter().map(|x| (x.start, x.stop)));
+        chains.push(chain_stats);
+    }
+    let mut summary = Summary {
+        chains: chains.len(),
+        blocks: chains.iter().map(|x| x.blocks).sum(),
+        aligned_bases: chains.iter().map(|x| x.aligned_bases).sum(),
+        target_gap: chains.iter().map(|x| x.target_gap).sum(),
+        query_gap: chains.iter().map(|x| x.query_gap).sum(),
+        score: ScoreStats::from_scores(chains.iter().map(|x| x.score).collect()),
+        ..Default::default()
+    };
+    let chromosomes: Vec<ChromStats> = chroms
+        .into_values()
+        .map(|(mut chrom, ivls)| {
+            (chrom.covered_bases, chrom.multi_covered_bases) = coverage(&ivls);
+            if chrom.size > 0 {
+                chrom.coverage = chrom.covered_bases as f64 / chrom.size as f64;
+                chrom.multi_coverage = chrom.multi_covered_bases as f64 / chrom.size as f64;
+            }
+            summary.target_size += chrom.size;
+            summary.covered_bases += chrom.covered_bases;
+            summary.multi_covered_bases += chrom.multi_covered_bases;
+            chrom
+        })
+        .collect();
+    if summary.target_size > 0 {
+        summary.coverage = summary.covered_bases as f64 / summary.target_size as f64;
+        summary.multi_coverage = summary.multi_covered_bases as f64 / summary.target_size as f64;
+    }
+    ChainFileStats {
+        summary,
+        chromosomes,
+        chains,
+    }
+}
+
+/// Write `SN` summary lines, then `CHR` per target chromosome and `CHAIN` per chain
+/// rows, each table with its own `#` header line; grep a prefix to get one table.
+fn write_tsv<W: Write + ?Sized>(writer: &mut W, stats: &ChainFileStats) -> std::io::Result<()> {
+    let summary = &stats.summary;
+    let score = &summary.score;
+    let sn: [(&str, String); 17] = [
+        ("chains", summary.chains.to_string()),
+        ("blocks", summary.blocks.to_string()),
+        ("aligned_bases", summary.aligned_bases.to_string()),
+        ("target_gap", summary.target_gap.to_string()),
+        ("query_gap", summary.query_gap.to_string()),
+        ("target_size", summary.target_size.to_string()),
+        ("covered_bases", summary.covered_bases.to_string()),
+        ("coverage", format!("{:.6}", summary.coverage)),
+        (
+            "multi_covered_bases",
+            summary.multi_covered_bases.to_string(),
+        ),
+        ("multi_coverage", format!("{:.6}", summary.multi_coverage)),
+        ("score_min", score.min.to_string()),
+        ("score_q1", score.q1.to_string()),
+        ("score_median", score.median.to_string()),
+        ("score_q3", score.q3.to_string()),
+        ("score_max", score.max.to_string()),
+        ("score_mean", format!("{:.2}", score.mean)),
+        ("target_chroms", stats.chromosomes.len().to_string()),
+    ];
+    for (key, value) in sn {
+        writeln!(writer, "SN\t{}\t{}", key, value)?;
+    }
+    writeln!(writer, "#CHR\tname\tsize\tchains\tblocks\taligned_bases\tcovered_bases\tcoverage\tmulti_covered_bases\tmulti_coverage")?;
+    for x in &stats.chromosomes {
+        writeln!(
+            writer,
+            "CHR\t{}\t{}\t{}\t{}\t{}\t{}\t{:.6}\t{}\t{:.6}",
+            x.name,
+            x.size,
+            x.chains,
+            x.blocks,
+            x.aligned_bases,
+            x.covered_bases,
+            x.coverage,
+            x.multi_covered_bases,
+            x.multi_coverage
+        )?;
+    }
+    writeln!(writer, "#CHAIN\tchain_id\tscore\ttarget_name\ttarget_start\ttarget_end\tquery_name\tquery_start\tquery_end\tquery_strand\tblocks\taligned_bases\ttarget_gap\tquery_gap")?;
+    for x in &stats.chains {
+        writeln!(
+            writer,
+            "CHAIN\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            x.chain_id,
+            x.score,
+            x.target_name,
+            x.target_start,
+            x.target_end,
+            x.query_name,
+            x.query_start,
+            x.query_end,
+            x.query_strand,
+            x.blocks,
+            x.aligned_bases,
+            x.target_gap,
+            x.query_gap
+        )?;
+    }
+    Ok(())
+}
+
+pub fn chain_stats(
+    input: &Option<String>,
+    output: &Option<String>,
+    json: bool,
+    rewrite: bool,
+) -> Result<()> {
+    let mut read_error = None;
+    let records = ChainReader::new(get_input_reader(input)?)
+        .skip_malformed()
+        .map_while(|x| match x {
+            Ok(x) => Some(x),
+            Err(e) => {
+                read_error = Some(e);
+                None
+            }
+        });
+    let stats = get_chain_stats(records);
+    if let Some(e) = read_error {
+        return Err(e);
+    }
+    info!("get stats of {} chains done!", stats.summary.chains);
+    let (mut output_file, _) = get_output_writer(output, rewrite)?;
+    if json {
+        serde_json::to_writer_pretty(&mut output_file, &stats).map_err(io::Error::from)?;
+        writeln!(output_file)?;
+    } else {
+        write_tsv(&mut output_file, &stats)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::ChainRecords;
+
+    /// Two chr1 chains with overlapping blocks, and one chr2 chain.
+    const CHAINS: &str = "chain 1000 chr1 1000 + 0 35 chrA 100 + 0 30 1
+10\t5\t0
+20
+
+chain 100 chr1 1000 + 5 40 chrB 100 - 0 25 2
+15\t10\t0
+10
+
+chain 300 chr2 500 + 100 150 chrA 100 + 40 90 3
+50
+";
+
+    #[test]
+    fn overlapping_coverage() {
+        assert_eq!(coverage(&[]), (0, 0));
+        assert_eq!(coverage(&[(0, 10), (10, 20)]), (20, 0));
+        // bases under three intervals count once as multi covered
+        assert_eq!(coverage(&[(8, 12), (0, 10), (5, 20)]), (20, 7));
+    }
+
+    #[test]
+    fn score_quantiles() {
+        let stats = ScoreStats::from_scores(vec![5.0, 1.0, 4.0, 2.0, 3.0]);
+        assert_eq!(
+            (
+                stats.min,
+                stats.q1,
+                stats.median,
+                stats.q3,
+                stats.max,
+                stats.mean
+            ),
+            (1.0, 2.0, 3.0, 4.0, 5.0, 3.0)
+        );
+        // nearest rank, halves round up
+        let stats = ScoreStats::from_scores(vec![40.0, 10.0, 30.0, 20.0]);
+        assert_eq!((stats.q1, stats.median, stats.q3), (20.0, 30.0, 30.0));
+        let stats = ScoreStats::from_scores(Vec::new());
+        assert_eq!((stats.min, stats.max, stats.mean), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn two_chains_on_one_chromosome() {
+        let records: Vec<ChainRecord> = ChainRecords::new(CHAINS).map(|x| x.unwrap()).collect();
+        let stats = get_chain_stats(&records);
+
+        // chr1 blocks 0-10, 15-35 and 5-20, 30-40
+        let chr1 = &stats.chromosomes[0];
+        assert_eq!(chr1.name, "chr1");
+        assert_eq!((chr1.size, chr1.chains, chr1.blocks), (1000, 2, 4));
+        assert_eq!(chr1.aligned_bases, 55);
+        assert_eq!((chr1.covered_bases, chr1.multi_covered_bases), (40, 15));
+        assert_eq!((chr1.coverage, chr1.multi_coverage), (0.04, 0.015));
+        let chr2 = &stats.chromosomes[1];
+        assert_eq!((chr2.covered_bases, chr2.multi_covered_bases), (50, 0));
+
+        let summary = &stats.summary;
+        assert_eq!((summary.chains, summary.blocks), (3, 5));
+        assert_eq!(
+            (summary.aligned_bases, summary.target_gap, summary.query_gap),
+            (105, 15, 0)
+        );
+        assert_eq!(summary.target_size, 1500);
+        assert_eq!(
+            (summary.covered_bases, summary.multi_covered_bases),
+            (90, 15)
+        );
+        assert_eq!((summary.coverage, summary.multi_coverage), (0.06, 0.01));
+        let score = &summary.score;
+        assert_eq!(
+            (score.min, score.q1, score.median, score.q3, score.max),
+            (100.0, 300.0, 300.0, 1000.0, 1000.0)
+        );
+        assert!((score.mean - 1400.0 / 3.0).abs() < 1e-9);
+
+        let chain = &stats.chains[1];
+        assert_eq!((chain.chain_id, chain.query_strand.as_str()), (2, "-"));
+        assert_eq!(
+            (chain.blocks, chain.aligned_bases, chain.target_gap),
+            (2, 25, 10)
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use crate::{
+    error::Result,
+    parser::{Alignment, ChainReader, ChainRecord, Header, SeqInfo, Strand},
+    utils::{get_input_reader, get_output_writer},
+};
+use log::info;
+
+/// Coordinates of `seq_info` counted on its positive strand.
+fn positive_range(seq_info: &SeqInfo) -> (usize, usize) {
+    match seq_info.strand {
+        Strand::Positive => (seq_info.start, seq_info.end),
+        Strand::Negative => (seq_info.size - seq_info.end, seq_info.size - seq_info.start),
+    }
+}
+
+/// Invert a chain so its query becomes the target, like UCSC `chainSwap`.
+/// A chain to the negative strand of the query is turned around to keep the new
+/// target on the positive strand: alignment lines are reversed and the old target
+/// becomes a negative strand query.
+pub fn swap_record(record: &ChainRecord) -> ChainRecord {
+    let header = &record.header;
+    let strand = header.query.strand;
+    let (target_start, target_end) = positive_range(&header.query);
+    let (query_start, query_end) = match strand {
+        Strand::Positive => (header.target.start, header.target.end),
+        Strand::Negative => (
+            header.target.size - header.target.end,
+            header.target.size - header.target.start,
+        ),
+    };
+    let target = SeqInfo {
+        name: header.query.name.clone(),
+        size: header.query.size,
+        strand: Strand::Positive,
+        start: target_start,
+        end: target_end,
+    };
+    let query = SeqInfo {
+        name: header.target.name.clone(),
+        size: header.target.size,
+        strand,
+        start: query_start,
+        end: query_end,
+    };
+    let old = &record.alignments;
+    let n = old.len();
+    let alignments: Vec<Alignment> = match strand {
+        Strand::Positive => old
+            .iter()
+            .map(|x| Alignment {
+                size: x.size,
+                target_diff: x.query_diff,
+                query_diff: x.target_diff,
+            })
+            .collect(),
+        // gap after new block i is the gap before old block n-1-i
+        Strand::Negative => (0..n)
+            .map(|i| {
+                let (target_diff, query_diff) = match i + 1 < n {
+                    true => (old[n - 2 - i].query_diff, old[n - 2 - i].target_diff),
+                    false => (0, 0),
+                };
+                Alignment {
+                    size: old[n - 1 - i].size,
+                    target_diff,
+                    query_diff,
+                }
+            })
+            .collect(),
+    };
+    let mut swapped = ChainRecord::new(
+        Header::new(header.score, target, query, header.chain_id),
+        alignments,
+    );
+    swapped.block_separator = record.block_separator;
+    swapped
+}
+
+/// Write every chain of `input` with target and query swapped.
+pub fn swap_chain(input: &Option<String>, output: &Option<String>, rewrite: bool) -> Result<()> {
+    let reader = get_input_reader(input)?;
+    let (mut output_file, _) = get_output_writer(output, rewrite)?;
+    let mut count = 0;
+    for chain_record in ChainReader::new(reader).skip_malformed() {
+        swap_record(&chain_record?).write(&mut output_file)?;
+        count += 1;
+    }
+    info!("swap {} chains", count);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::{fs::File, io::BufReader};
+
+    fn chain_text(record: &ChainRecord) -> Vec<u8> {
+        let mut text: Vec<u8> = Vec::new();
+        record.write(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn swap_twice_is_identity() {
+        for name in ["v2_to_v3.chain.gz", "v4_to_v5.chain.gz"] {
+            let path = format!("{}/data/{}", env!("CARGO_MANIFEST_DIR"), name);
+            let reader = MultiGzDecoder::new(File::open(&path).unwrap());
+            for record in ChainReader::new(BufReader::new(reader)) {
+                let record = record.unwrap();
+                let swapped = swap_record(&record);
+                assert_eq!(swapped.header.target.name, record.header.query.name);
+                assert_eq!(swapped.header.target.strand, Strand::Positive);
+                assert_eq!(
+                    chain_text(&swap_record(&swapped)),
+                    chain_text(&record),
+                    "chain {} of {}",
+                    record.header.chain_id,
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn negative_strand_blocks_are_reversed() {
+        let text = "chain 100 chrT 100 + 10 60 chrQ 80 - 5 55 7\n10\t5\t0\n20\t0\t5\n15\n\n";
+        let record = ChainReader::new(text.as_bytes()).next().unwrap().unwrap();
+        let swapped = swap_record(&record);
+        let mut written: Vec<u8> = Vec::new();
+        swapped.write(&mut written).unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            "chain 100 chrQ 80 + 25 75 chrT 100 - 40 90 7\n15\t5\t0\n20\t0\t5\n10\n\n"
+        );
+    }
+}
@@ -0,0 +1,524 @@
+use crate::{
+    bgzf::BgzfWriter,
+    error::{CrussmapError, Result},
+    utils::{outfile_exist, read_file_to_string, reg2bin, OutputCompression},
+};
+use clap::ValueEnum;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{self, Write},
+};
+
+/// Width of a linear index window, and the smallest bin, 16 kb like tabix.
+const MIN_SHIFT: u32 = 14;
+const TBI_DEPTH: u32 = 5;
+/// deep enough to cover positions up to 2^32, same as `tabix --csi`
+const CSI_DEPTH: u32 = 6;
+
+/// Index written next to a BGZF compressed output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IndexFormat {
+    /// tabix index, positions up to 2^29
+    Tbi,
+    /// coordinate sorted index, positions up to 2^32
+    Csi,
+}
+
+impl IndexFormat {
+    fn depth(&self) -> u32 {
+        match self {
+            IndexFormat::Tbi => TBI_DEPTH,
+            IndexFormat::Csi => CSI_DEPTH,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            IndexFormat::Tbi => "tbi",
+            IndexFormat::Csi => "csi",
+        }
+    }
+}
+
+/// Layout of a tab separated format, the same as the tabix presets. Columns are
+/// 1-based, `col_end` 0 means the end comes from the REF allele (VCF).
+#[derive(Debug, Clone, Copy)]
+pub struct TabixConf {
+    preset: i32,
+    zero_based: bool,
+    col_seq: usize,
+    col_beg: usize,
+    col_end: usize,
+    meta: u8,
+}
+
+impl TabixConf {
+    pub const BED: TabixConf = TabixConf {
+        preset: 0,
+        zero_based: true,
+        col_seq: 1,
+        col_beg: 2,
+        col_end: 3,
+        meta: b'#',
+    };
+    pub const GFF: TabixConf = TabixConf {
+        preset: 0,
+        zero_based: false,
+        col_seq: 1,
+        col_beg: 4,
+        col_end: 5,
+        meta: b'#',
+    };
+    pub const VCF: TabixConf = TabixConf {
+        preset: 2,
+        zero_based: false,
+        col_seq: 1,
+        col_beg: 2,
+        col_end: 0,
+        meta: b'#',
+    };
+
+    /// Format field of the index header, `0x10000` flags 0-based positions.
+    fn format(&self) -> i32 {
+        match self.zero_based {
+            true => self.preset | 0x10000,
+            false => self.preset,
+        }
+    }
+
+    /// Chromosome and 0-based half-open interval of a record line.
+    fn locate<'a>(&self, line: &'a str) -> Option<(&'a str, u64, u64)> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let chrom = *fields.get(self.col_seq - 1)?;
+        let mut beg: u64 = fields.get(self.col_beg - 1)?.parse().ok()?;
+        if !self.zero_based {
+            beg = beg.saturating_sub(1);
+        }
+        // 1-based inclusive ends equal 0-based exclusive ones
+        let end = match self.col_end {
+            0 => beg + fields.get(3)?.len() as u64,
+            col => fields.get(col - 1)?.parse().ok()?,
+        };
+        Some((chrom, beg, end.max(beg + 1)))
+    }
+}
+
+/// Compression and index of the converted output file.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutputOptions {
+    /// if not set, picked from the output file name
+    pub compress: Option<OutputCompression>,
+    pub index: Option<IndexFormat>,
+}
+
+impl OutputOptions {
+    /// Check the options and return the compression of `output`, forced to BGZF when
+    /// an index is asked for. Both need an output file, as unmapped records share
+    /// stdout with the converted ones.
+    pub fn compression(&self, output: &Option<String>) -> Result<Option<OutputCompression>> {
+        if output.is_none() && (self.compress.is_some() || self.index.is_some()) {
+            return Err(CrussmapError::Index(
+                "an output file is needed to compress or index the output".to_string(),
+            ));
+        }
+        if self.index.is_none() {
+            return Ok(self.compress);
+        }
+        match self.compress {
+            None | Some(OutputCompression::Bgzf) => Ok(Some(OutputCompression::Bgzf)),
+            Some(compression) => Err(CrussmapError::Index(format!(
+                "only BGZF output can be indexed, not {:?}",
+                compression
+            ))),
+        }
+    }
+
+    /// Sort and index the written `output` if an index is asked for.
+    pub fn finish(&self, output: &Option<String>, conf: &TabixConf, rewrite: bool) -> Result<()> {
+        match (self.index, output) {
+            (Some(format), Some(output)) => sort_and_index(output, conf, format, rewrite),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Sort the records of a written output by position, rewrite it as BGZF and write
+/// `<output>.tbi` or `<output>.csi` next to it. Header lines are kept first, records
+/// of a chromosome stay together in order of first appearance.
+pub fn sort_and_index(
+    output: &String,
+    conf: &TabixConf,
+    format: IndexFormat,
+    rewrite: bool,
+) -> Result<()> {
+    let index_path = format!("{}.{}", output, format.extension());
+    outfile_exist(&index_path, rewrite)?;
+    let data = read_file_to_string(output)?;
+
+    let mut meta_lines: Vec<&str> = Vec::new();
+    let mut records: Vec<(usize, u64, u64, &str)> = Vec::new();
+    let mut chroms: Vec<&str> = Vec::new();
+    let mut chrom_ranks: HashMap<&str, usize> = HashMap::new();
+    for (i, line) in data.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        if line.as_bytes()[0] == conf.meta {
+            meta_lines.push(line);
+            continue;
+        }
+        let (chrom, beg, end) = conf.locate(line).ok_or_else(|| {
+            CrussmapError::Index(format!("line {} of {} has no position", i + 1, output))
+        })?;
+        let rank = *chrom_ranks.entry(chrom).or_insert_with(|| {
+            chroms.push(chrom);
+            chroms.len() - 1
+        });
+        records.push((rank, beg, end, line));
+    }
+    records.sort_by_key(|x| (x.0, x.1));
+
+    let max_pos = 1u64 << (MIN_SHIFT + 3 * format.depth());
+    let mut writer = BgzfWriter::new(File::create(output)?);
+    for line in meta_lines {
+        writeln!(writer, "{}", line)?;
+    }
+    let mut ref_indexes: Vec<RefIndex> = chroms.iter().map(|_| RefIndex::default()).collect();
+    for (rank, beg, end, line) in records {
+        if end > max_pos {
+            return Err(CrussmapError::Index(format!(
+                "position {} of {} is too large for a {:?} index",
+                end, chroms[rank], format
+            )));
+        }
+        let vstart = writer.virtual_offset();
+        writeln!(writer, "{}", line)?;
+        let vend = writer.virtual_offset();
+        ref_indexes[rank].push(beg, end, vstart, vend, format.depth());
+    }
+    writer.finish()?;
+
+    let mut index = BgzfWriter::new(File::create(&index_path)?);
+    write_index(&mut index, conf, format, &chroms, ref_indexes)?;
+    index.finish()?;
+    Ok(())
+}
+
+/// First position covered by a bin.
+fn bin_start(bin: u32, depth: u32) -> u64 {
+    let bin = bin as u64;
+    let mut level = 0;
+    while ((1u64 << (3 * (level + 1))) - 1) / 7 <= bin {
+        level += 1;
+    }
+    let first_bin = ((1u64 << (3 * level)) - 1) / 7;
+    (bin - first_bin) << (MIN_SHIFT + 3 * (depth - level as u32))
+}
+
+/// Chunks and linear index of one chromosome.
+#[derive(Default)]
+struct RefIndex {
+    bins: BTreeMap<u32, Vec<(u64, u64)>>,
+    linear: Vec<Option<u64>>,
+    off_beg: Option<u64>,
+    off_end: u64,
+    n_mapped: u64,
+}
+
+impl RefIndex {
+    fn push(&mut self, beg: u64, end: u64, vstart: u64, vend: u64, depth: u32) {
+        let chunks = self
+            .bins
+            .entry(reg2bin(beg, end, MIN_SHIFT, depth))
+            .or_default();
+        // like htslib, a chunk is extended over records of other bins in between as
+        // long as it stays in the same BGZF block, readers filter by position anyway
+        match chunks.last_mut() {
+            Some(chunk) if chunk.1 >> 16 == vstart >> 16 => chunk.1 = vend,
+            _ => chunks.push((vstart, vend)),
+        }
+        let last_window = ((end - 1) >> MIN_SHIFT) as usize;
+        if self.linear.len() <= last_window {
+            self.linear.resize(last_window + 1, None);
+        }
+        // records come sorted by start, the first one in a window is the leftmost
+        for window in &mut self.linear[(beg >> MIN_SHIFT) as usize..=last_window] {
+            window.get_or_insert(vstart);
+        }
+        self.off_beg.get_or_insert(vstart);
+        self.off_end = vend;
+        self.n_mapped += 1;
+    }
+
+    /// Linear index with empty windows filled from the window before them.
+    fn linear_offsets(&self) -> Vec<u64> {
+        let mut last = 0;
+        self.linear
+            .iter()
+            .map(|x| {
+                last = x.unwrap_or(last);
+                last
+            })
+            .collect()
+    }
+}
+
+fn write_index<W: Write>(
+    writer: &mut W,
+    conf: &TabixConf,
+    format: IndexFormat,
+    chroms: &[&str],
+    ref_indexes: Vec<RefIndex>,
+) -> io::Result<()> {
+    let mut names: Vec<u8> = Vec::new();
+    for chrom in chroms {
+        names.extend_from_slice(chrom.as_bytes());
+        names.push(0);
+    }
+    let mut header: Vec<u8> = Vec::new();
+    for x in [
+        conf.format(),
+        conf.col_seq as i32,
+        conf.col_beg as i32,
+        conf.col_end as i32,
+        conf.meta as i32,
+        0,
+        names.len() as i32,
+    ] {
+        header.extend_from_slice(&x.to_le_bytes());
+    }
+    header.extend_from_slice(&names);
+
+    let depth = format.depth();
+    match format {
+        IndexFormat::Tbi => writer.write_all(b"TBI\x01")?,
+        IndexFormat::Csi => {
+            writer.write_all(b"CSI\x01")?;
+            writer.write_all(&(MIN_SHIFT as i32).to_le_bytes())?;
+            writer.write_all(&(depth as i32).to_le_bytes())?;
+            writer.write_all(&(header.len() as i32).to_le_bytes())?;
+        }
+    }
+    if format == IndexFormat::Tbi {
+        writer.write_all(&(chroms.len() as i32).to_le_bytes())?;
+    }
+    writer.write_all(&header)?;
+    if format == IndexFormat::Csi {
+        writer.write_all(&(chroms.len() as i32).to_le_bytes())?;
+    }
+
+    let pseudo_bin = (((1u64 << (3 * (depth + 1))) - 1) / 7 + 1) as u32;
+    for ref_index in ref_indexes {
+        let linear = ref_index.linear_offsets();
+        writer.write_all(&(ref_index.bins.len() as i32 + 1).to_le_bytes())?;
+        for (bin, chunks) in &ref_index.bins {
+            writer.write_all(&bin.to_le_bytes())?;
+            if format == IndexFormat::Csi {
+                let window = (bin_start(*bin, depth) >> MIN_SHIFT) as usize;
+                writer.write_all(&linear[window].to_le_bytes())?;
+            }
+            writer.write_all(&(chunks.len() as i32).to_le_bytes())?;
+            for (vstart, vend) in chunks {
+                writer.write_all(&vstart.to_le_bytes())?;
+                writer.write_all(&vend.to_le_bytes())?;
+            }
+        }
+        // summary of the chromosome kept by htslib in a bin past the last one
+        writer.write_all(&pseudo_bin.to_le_bytes())?;
+        if format == IndexFormat::Csi {
+            writer.write_all(&0u64.to_le_bytes())?;
+        }
+        writer.write_all(&2i32.to_le_bytes())?;
+        for x in [
+            ref_index.off_beg.unwrap_or(0),
+            ref_index.off_end,
+            ref_index.n_mapped,
+            0,
+        ] {
+            writer.write_all(&x.to_le_bytes())?;
+        }
+        if format == IndexFormat::Tbi {
+            writer.write_all(&(linear.len() as i32).to_le_bytes())?;
+            for offset in linear {
+                writer.write_all(&offset.to_le_bytes())?;
+            }
+        }
+    }
+    // no record without coordinates
+    writer.write_all(&0u64.to_le_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    /// Little endian reader of a decompressed index.
+    struct Fields<'a>(&'a [u8]);
+
+    impl Fields<'_> {
+        fn take(&mut self, n: usize) -> &[u8] {
+            let (head, tail) = self.0.split_at(n);
+            self.0 = tail;
+            head
+        }
+
+        fn i32(&mut self) -> i32 {
+            i32::from_le_bytes(self.take(4).try_into().unwrap())
+        }
+
+        fn u64(&mut self) -> u64 {
+            u64::from_le_bytes(self.take(8).try_into().unwrap())
+        }
+    }
+
+    /// Bins of one chromosome with their CSI linear offset and chunks, and the TBI
+    /// linear index.
+    type DecodedRef = (BTreeMap<u32, (u64, Vec<(u64, u64)>)>, Vec<u64>);
+
+    /// Sequence names and per chromosome index of a TBI or CSI file.
+    fn decode_index(path: &str, format: IndexFormat) -> (Vec<String>, Vec<DecodedRef>) {
+        let mut data: Vec<u8> = Vec::new();
+        MultiGzDecoder::new(File::open(path).unwrap())
+            .read_to_end(&mut data)
+            .unwrap();
+        let mut fields = Fields(&data);
+        let n_ref = match format {
+            IndexFormat::Tbi => {
+                assert_eq!(fields.take(4), b"TBI\x01");
+                fields.i32()
+            }
+            IndexFormat::Csi => {
+                assert_eq!(fields.take(4), b"CSI\x01");
+                assert_eq!((fields.i32(), fields.i32()), (14, 6));
+                assert!(fields.i32() > 28);
+                0
+            }
+        };
+        let _conf: Vec<i32> = (0..6).map(|_| fields.i32()).collect();
+        let l_nm = fields.i32() as usize;
+        let names: Vec<String> = String::from_utf8(fields.take(l_nm).to_vec())
+            .unwrap()
+            .split_terminator('\0')
+            .map(|x| x.to_string())
+            .collect();
+        let n_ref = match format {
+            IndexFormat::Tbi => n_ref,
+            IndexFormat::Csi => fields.i32(),
+        };
+        let mut refs = Vec::new();
+        for _ in 0..n_ref {
+            let mut bins = BTreeMap::new();
+            for _ in 0..fields.i32() {
+                let bin = fields.i32() as u32;
+                let loffset = match format {
+                    IndexFormat::Tbi => 0,
+                    IndexFormat::Csi => fields.u64(),
+                };
+                let chunks = (0..fields.i32())
+                    .map(|_| (fields.u64(), fields.u64()))
+                    .collect();
+                bins.insert(bin, (loffset, chunks));
+            }
+            let linear = match format {
+                IndexFormat::Tbi => (0..fields.i32()).map(|_| fields.u64()).collect(),
+                IndexFormat::Csi => Vec::new(),
+            };
+            refs.push((bins, linear));
+        }
+        assert_eq!(fields.u64(), 0);
+        assert!(fields.0.is_empty());
+        (names, refs)
+    }
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("crussmap_tabix_{}_{}", std::process::id(), name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn bed_tbi() {
+        let path = temp_path("a.bed.gz");
+        std::fs::write(
+            &path,
+            "#header\nchr2\t100\t200\tb1\nchr1\t20000\t40000\ta3\nchr1\t5\t10\ta1\nchr1\t16000\t17000\ta2\n",
+        )
+        .unwrap();
+        sort_and_index(&path, &TabixConf::BED, IndexFormat::Tbi, true).unwrap();
+
+        let mut text = String::new();
+        MultiGzDecoder::new(File::open(&path).unwrap())
+            .read_to_string(&mut text)
+            .unwrap();
+        let sorted = "#header\nchr2\t100\t200\tb1\nchr1\t5\t10\ta1\nchr1\t16000\t17000\ta2\nchr1\t20000\t40000\ta3\n";
+        assert_eq!(text, sorted);
+        // everything is in the first block, virtual offsets are offsets in the text
+        let offset = |x: &str| sorted.find(x).unwrap() as u64;
+        let end = sorted.len() as u64;
+
+        let (names, refs) = decode_index(&format!("{}.tbi", path), IndexFormat::Tbi);
+        assert_eq!(names, ["chr2", "chr1"]);
+        let (bins, linear) = &refs[1];
+        assert_eq!(reg2bin(5, 10, 14, 5), 4681);
+        assert_eq!(reg2bin(16000, 17000, 14, 5), 585);
+        assert_eq!(reg2bin(20000, 40000, 14, 5), 585);
+        assert_eq!(bins[&4681].1, [(offset("chr1\t5"), offset("chr1\t16000"))]);
+        // a2 and a3 share bin 585 and the block, so they share a chunk
+        assert_eq!(bins[&585].1, [(offset("chr1\t16000"), end)]);
+        assert_eq!(
+            bins[&37450].1,
+            [(offset("chr1\t5"), end), (3, 0)],
+            "pseudo-bin: offsets and number of records"
+        );
+        assert_eq!(
+            *linear,
+            [
+                offset("chr1\t5"),
+                offset("chr1\t16000"),
+                offset("chr1\t20000")
+            ]
+        );
+
+        let (bins, linear) = &refs[0];
+        assert_eq!(bins.keys().collect::<Vec<_>>(), [&4681, &37450]);
+        assert_eq!(bins[&37450].1[1], (1, 0));
+        assert_eq!(*linear, [offset("chr2")]);
+        for file in [path.clone(), format!("{}.tbi", path)] {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn vcf_csi() {
+        let path = temp_path("a.vcf.gz");
+        let sorted = "##fileformat=VCFv4.2\n1\t100\t.\tACGT\tA\n1\t40000\t.\tA\tT\n";
+        std::fs::write(&path, sorted).unwrap();
+        sort_and_index(&path, &TabixConf::VCF, IndexFormat::Csi, true).unwrap();
+        let offset = |x: &str| sorted.find(x).unwrap() as u64;
+
+        let (names, refs) = decode_index(&format!("{}.csi", path), IndexFormat::Csi);
+        assert_eq!(names, ["1"]);
+        let (bins, _) = &refs[0];
+        // the deletion ends with its REF allele, at 103
+        let first = reg2bin(99, 103, 14, 6);
+        let second = reg2bin(39999, 40000, 14, 6);
+        assert_eq!((first, second), (37449, 37451));
+        assert_eq!(
+            bins[&first],
+            (
+                offset("1\t100"),
+                vec![(offset("1\t100"), offset("1\t40000"))]
+            )
+        );
+        assert_eq!(bins[&second].0, offset("1\t40000"));
+        assert_eq!(bins[&299594].1[1], (2, 0));
+        for file in [path.clone(), format!("{}.csi", path)] {
+            std::fs::remove_file(file).unwrap();
+        }
+    }
+}
@@ -1,44 +1,117 @@
-use log::{error, warn};
+use crate::{
+    bgzf::BgzfWriter,
+    error::{CrussmapError, Result},
+};
+use bzip2::read::MultiBzDecoder;
+use clap::ValueEnum;
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+use log::warn;
 use std::{
     fs::File,
-    io::Error,
-    io::{self, Read, Write},
+    io::{self, BufRead, BufReader, Cursor, Read, Write},
     path::Path,
 };
 
-pub fn get_output_writer(output: &Option<String>, rewrite: bool) -> (Box<dyn Write>, bool) {
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const BZIP2_MAGIC: &[u8] = b"BZh";
+
+/// Compression of written output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputCompression {
+    Plain,
+    Gzip,
+    /// blocked gzip, readable by any gzip reader and indexable by tabix
+    Bgzf,
+}
+
+impl OutputCompression {
+    /// Compression implied by a file name, `.gz` and `.bgz` give BGZF like bgzip.
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".gz") || path.ends_with(".bgz") {
+            OutputCompression::Bgzf
+        } else {
+            OutputCompression::Plain
+        }
+    }
+
+    fn wrap(&self, writer: Box<dyn Write>) -> Box<dyn Write> {
+        match self {
+            OutputCompression::Plain => writer,
+            OutputCompression::Gzip => Box::new(GzEncoder::new(writer, Default::default())),
+            OutputCompression::Bgzf => Box::new(BgzfWriter::new(writer)),
+        }
+    }
+}
+
+pub fn get_output_writer(output: &Option<String>, rewrite: bool) -> Result<(Box<dyn Write>, bool)> {
+    get_compressed_output_writer(output, None, rewrite)
+}
+
+/// Same as [`get_output_writer`], `compression` overrides the one implied by the
+/// output file name and also applies to stdout.
+pub fn get_compressed_output_writer(
+    output: &Option<String>,
+    compression: Option<OutputCompression>,
+    rewrite: bool,
+) -> Result<(Box<dyn Write>, bool)> {
     let (output_file, stdout): (Box<dyn Write>, bool) = match output {
         Some(output_file) => {
-            outfile_exist(output_file, rewrite);
-            (Box::new(File::create(output_file).unwrap()), false)
+            outfile_exist(output_file, rewrite)?;
+            let compression =
+                compression.unwrap_or_else(|| OutputCompression::from_path(output_file));
+            let file = Box::new(File::create(output_file)?);
+            (compression.wrap(file), false)
+        }
+        None => {
+            let compression = compression.unwrap_or(OutputCompression::Plain);
+            (compression.wrap(Box::new(io::stdout())), true)
         }
-        None => (Box::new(io::stdout()), true),
     };
-    (output_file, stdout)
+    Ok((output_file, stdout))
 }
 
-pub fn get_file_reader(input_file: &String) -> Result<File, Error> {
-    input_files_exist(input_file);
-    File::open(input_file)
+/// Wrap `reader` in a decoder picked from its leading bytes, so plain, gzip (BGZF
+/// included), zstd and bzip2 inputs all read as plain text.
+pub fn decompress_reader<'a, R: Read + 'a>(mut reader: R) -> Result<Box<dyn Read + 'a>> {
+    let mut magic = Vec::with_capacity(4);
+    reader.by_ref().take(4).read_to_end(&mut magic)?;
+    let reader = Cursor::new(magic.clone()).chain(reader);
+    let reader: Box<dyn Read + 'a> = if magic.starts_with(GZIP_MAGIC) {
+        // BGZF is a series of gzip members
+        Box::new(MultiGzDecoder::new(reader))
+    } else if magic.starts_with(ZSTD_MAGIC) {
+        Box::new(zstd::Decoder::with_buffer(BufReader::new(reader))?)
+    } else if magic.starts_with(BZIP2_MAGIC) {
+        Box::new(MultiBzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+    Ok(reader)
 }
 
-pub fn read_file_to_string(file_path: &String) -> Result<String, String> {
-    let mut f = File::open(file_path).map_err(|e| e.to_string())?;
+pub fn get_file_reader(input_file: &String) -> Result<Box<dyn Read>> {
+    input_files_exist(input_file)?;
+    decompress_reader(File::open(input_file)?)
+}
+
+pub fn read_file_to_string(file_path: &String) -> Result<String> {
+    let mut f = decompress_reader(File::open(file_path)?)?;
     let mut data = String::with_capacity(512);
-    f.read_to_string(&mut data).map_err(|e| e.to_string())?;
+    f.read_to_string(&mut data)?;
     Ok(data)
 }
 
-pub fn input_files_exist(input_file: &String) {
+pub fn input_files_exist(input_file: &String) -> Result<()> {
     // check if input files exist
     let path = Path::new(input_file);
     if !path.exists() {
-        error!("file {} does not exist", input_file);
-        std::process::exit(1);
+        return Err(CrussmapError::InputNotFound(input_file.to_string()));
     }
+    Ok(())
 }
 
-pub fn outfile_exist(outputname: &String, rewrite: bool) {
+pub fn outfile_exist(outputname: &String, rewrite: bool) -> Result<()> {
     // check if output file exists
     let path = Path::new(outputname);
     if path.exists() {
@@ -46,28 +119,51 @@ pub fn outfile_exist(outputname: &String, rewrite: bool) {
             // rewrite the file
             warn!("file {} exist, will rewrite it", outputname);
         } else {
-            // exit
-            error!("file {} exist, use -r to rewrite it", outputname);
-            std::process::exit(1);
+            return Err(CrussmapError::OutputExists(outputname.to_string()));
         }
     }
+    Ok(())
 }
 
-pub fn get_data_from_input(input: &Option<String>) -> String {
+pub fn get_data_from_input(input: &Option<String>) -> Result<String> {
     match input {
         // input file
         Some(input_file) => {
-            input_files_exist(input_file);
-            read_file_to_string(input_file).unwrap()
+            input_files_exist(input_file)?;
+            read_file_to_string(input_file)
         }
         // stdin
         None => {
             let mut data = String::with_capacity(512);
-            io::stdin()
-                .read_to_string(&mut data)
-                .expect("failed to read from stdin");
-            data
+            decompress_reader(io::stdin())?.read_to_string(&mut data)?;
+            Ok(data)
+        }
+    }
+}
+
+/// Buffered reader of `input`, or stdin if not set, decompressed as
+/// [`decompress_reader`].
+pub fn get_input_reader(input: &Option<String>) -> Result<Box<dyn BufRead>> {
+    let reader = match input {
+        Some(input_file) => get_file_reader(input_file)?,
+        None => decompress_reader(io::stdin())?,
+    };
+    Ok(Box::new(BufReader::new(reader)))
+}
+
+/// Bin of the smallest node of the UCSC binning scheme of BAM, tabix and CSI which
+/// holds the 0-based, half-open region `[beg, end)`, as `hts_reg2bin`. Bins of the
+/// deepest level span `1 << min_shift` bases.
+pub fn reg2bin(beg: u64, end: u64, min_shift: u32, depth: u32) -> u32 {
+    let end = end - 1;
+    let mut shift = min_shift;
+    let mut first_bin = ((1u64 << (3 * depth)) - 1) / 7;
+    for level in (1..=depth).rev() {
+        if beg >> shift == end >> shift {
+            return (first_bin + (beg >> shift)) as u32;
         }
+        shift += 3;
+        first_bin -= 1 << (3 * (level - 1));
     }
-    // data
+    0
 }
@@ -0,0 +1,544 @@
+use crate::{
+    error::Result,
+    utils::{get_input_reader, get_output_writer},
+};
+use log::info;
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{BufRead, Write},
+};
+
+/// A problem found in a chain file, `line` is 1-based.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub chain_id: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.chain_id {
+            Some(chain_id) => write!(
+                f,
+                "line {}: chain {}: {}",
+                self.line, chain_id, self.message
+            ),
+            None => write!(f, "line {}: {}", self.line, self.message),
+        }
+    }
+}
+
+/// Header fields of the chain being checked and what its blocks added up to.
+struct OpenChain {
+    line: usize,
+    chain_id: Option<usize>,
+    target: Option<(usize, usize, usize)>,
+    query: Option<(usize, usize, usize)>,
+    target_cursor: usize,
+    query_cursor: usize,
+    blocks: usize,
+    last_block_line: usize,
+    /// a block line could not be read, so sizes and gaps do not add up anyway
+    malformed: bool,
+    /// line number of the last block line if it had only the size field
+    closed_at: Option<usize>,
+}
+
+struct Validator {
+    diagnostics: Vec<Diagnostic>,
+    chain_ids: HashMap<usize, usize>,
+    chains: usize,
+    chain: Option<OpenChain>,
+}
+
+impl Validator {
+    fn new() -> Self {
+        Validator {
+            diagnostics: Vec::new(),
+            chain_ids: HashMap::new(),
+            chains: 0,
+            chain: None,
+        }
+    }
+
+    fn error(&mut self, line: usize, chain_id: Option<usize>, message: String) {
+        self.diagnostics.push(Diagnostic {
+            line,
+            chain_id,
+            message,
+        });
+    }
+
+    /// Check `name size strand start end` fields of one side of the header, strand aside.
+    fn seq_info(
+        &mut self,
+        line: usize,
+        chain_id: Option<usize>,
+        side: &str,
+        fields: &[&str],
+    ) -> Option<(usize, usize, usize)> {
+        let mut numbers = [0usize; 3];
+        let mut valid = true;
+        for (number, (field, what)) in numbers.iter_mut().zip([
+            (fields[1], "size"),
+            (fields[3], "start"),
+            (fields[4], "end"),
+        ]) {
+            match field.parse::<usize>() {
+                Ok(x) => *number = x,
+                Err(_) => {
+                    self.error(
+                        line,
+                        chain_id,
+                        format!("{} {} is not a non-negative integer: {}", side, what, field),
+                    );
+                    valid = false;
+                }
+            }
+        }
+        if !valid {
+            return None;
+        }
+        let [size, start, end] = numbers;
+        if start > end || end > size {
+            self.error(
+                line,
+                chain_id,
+                format!(
+                    "{} coordinates out of range: start {} end {} size {}",
+                    side, start, end, size
+                ),
+            );
+        }
+        Some((size, start, end))
+    }
+
+    fn open_chain(&mut self, line: usize, text: &str) -> OpenChain {
+        self.chains += 1;
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() != 13 {
+            self.error(
+                line,
+                None,
+                format!("header has {} fields, expected 13", fields.len()),
+            );
+        }
+        let chain_id = fields.get(12).and_then(|x| x.parse::<usize>().ok());
+        match (fields.get(12), chain_id) {
+            (Some(field), None) => self.error(
+                line,
+                None,
+                format!("chain id is not a non-negative integer: {}", field),
+            ),
+            (_, Some(id)) => {
+                if let Some(first_line) = self.chain_ids.insert(id, line) {
+                    self.error(
+                        line,
+                        chain_id,
+                        format!("duplicate chain id, first used on line {}", first_line),
+                    );
+                    self.chain_ids.insert(id, first_line);
+                }
+            }
+            _ => {}
+        }
+        let mut chain = OpenChain {
+            line,
+            chain_id,
+            target: None,
+            query: None,
+            target_cursor: 0,
+            query_cursor: 0,
+            blocks: 0,
+            last_block_line: line,
+            malformed: false,
+            closed_at: None,
+        };
+        if fields.len() != 13 {
+            return chain;
+        }
+        if fields[1].parse::<f64>().is_err() {
+            self.error(
+                line,
+                chain_id,
+                format!("score is not a number: {}", fields[1]),
+            );
+        }
+        if fields[4] != "+" {
+            self.error(line, chain_id, "target strand must be +".to_string());
+        }
+        if fields[9] != "+" && fields[9] != "-" {
+            self.error(
+                line,
+                chain_id,
+                format!("query strand must be + or -: {}", fields[9]),
+            );
+        }
+        chain.target = self.seq_info(line, chain_id, "target", &fields[2..7]);
+        chain.query = self.seq_info(line, chain_id, "query", &fields[7..12]);
+        chain.target_cursor = chain.target.map_or(0, |x| x.1);
+        chain.query_cursor = chain.query.map_or(0, |x| x.1);
+        chain
+    }
+
+    fn block_line(&mut self, line: usize, text: &str, chain: &mut OpenChain) {
+        let chain_id = chain.chain_id;
+        if let Some(closed_at) = chain.closed_at {
+            self.error(
+                closed_at,
+                chain_id,
+                "block line with only the size field must be the last of a chain".to_string(),
+            );
+        }
+        chain.blocks += 1;
+        chain.last_block_line = line;
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        if fields.len() != 1 && fields.len() != 3 {
+            self.error(
+                line,
+                chain_id,
+                format!("block line has {} fields, expected 1 or 3", fields.len()),
+            );
+            chain.closed_at = None;
+            chain.malformed = true;
+            return;
+        }
+        let mut numbers = [0usize; 3];
+        for (i, field) in fields.iter().enumerate() {
+            match field.parse::<usize>() {
+                Ok(x) => numbers[i] = x,
+                Err(_) => {
+                    self.error(
+                        line,
+                        chain_id,
+                        format!("block field is not a non-negative integer: {}", field),
+                    );
+                    chain.closed_at = None;
+                    chain.malformed = true;
+                    return;
+                }
+            }
+        }
+        let [size, target_diff, query_diff] = numbers;
+        if size == 0 {
+            self.error(line, chain_id, "block size is 0".to_string());
+        }
+        let target_cursor = chain.target_cursor;
+        let query_cursor = chain.query_cursor;
+        chain.target_cursor = target_cursor.saturating_add(size.saturating_add(target_diff));
+        chain.query_cursor = query_cursor.saturating_add(size.saturating_add(query_diff));
+        chain.closed_at = match fields.len() {
+            1 => Some(line),
+            _ => None,
+        };
+        if let (Some((target_size, _, _)), Some((query_size, _, _))) = (chain.target, chain.query) {
+            // only report the block crossing the end
+            if (target_cursor <= target_size && chain.target_cursor > target_size)
+                || (query_cursor <= query_size && chain.query_cursor > query_size)
+            {
+                self.error(
+                    line,
+                    chain_id,
+                    "block runs past the end of the sequence".to_string(),
+                );
+            }
+        }
+    }
+
+    /// Check the sums of a chain, `ended` tells whether a blank line followed it.
+    fn close_chain(&mut self, chain: OpenChain, ended: bool) {
+        let chain_id = chain.chain_id;
+        if chain.blocks == 0 {
+            self.error(chain.line, chain_id, "no block line".to_string());
+            return;
+        }
+        if !ended {
+            self.error(
+                chain.last_block_line,
+                chain_id,
+                "chain is not followed by a blank line".to_string(),
+            );
+        }
+        if chain.closed_at.is_none() {
+            self.error(
+                chain.last_block_line,
+                chain_id,
+                "last block line must have only the size field".to_string(),
+            );
+        }
+        if chain.malformed {
+            return;
+        }
+        if let Some((_, start, end)) = chain.target {
+            if chain.target_cursor != end {
+                self.error(
+                    chain.line,
+                    chain_id,
+                    format!(
+                        "target end {} != start {} + sizes and gaps {}",
+                        end,
+                        start,
+                        chain.target_cursor - start
+                    ),
+                );
+            }
+        }
+        if let Some((_, start, end)) = chain.query {
+            if chain.query_cursor != end {
+                self.error(
+                    chain.line,
+                    chain_id,
+                    format!(
+                        "query end {} != start {} + sizes and gaps {}",
+                        end,
+                        start,
+                        chain.query_cursor - start
+                    ),
+                );
+            }
+        }
+    }
+
+    fn line(&mut self, line: usize, text: &str) {
+        let text = text.trim();
+        if text.is_empty() {
+            if let Some(chain) = self.chain.take() {
+                self.close_chain(chain, true);
+            }
+            return;
+        }
+        if text.starts_with('#') {
+            return;
+        }
+        if text.starts_with("chain") {
+            if let Some(chain) = self.chain.take() {
+                self.close_chain(chain, false);
+            }
+            self.chain = Some(self.open_chain(line, text));
+            return;
+        }
+        match self.chain.take() {
+            Some(mut chain) => {
+                self.block_line(line, text, &mut chain);
+                self.chain = Some(chain);
+            }
+            None if self.chains == 0 => {
+                self.error(line, None, "block line before any chain header".to_string())
+            }
+            None => self.error(
+                line,
+                None,
+                "block line after the blank line ending a chain".to_string(),
+            ),
+        }
+    }
+
+    /// Number of chains and the problems found, in line order.
+    fn finish(mut self) -> (usize, Vec<Diagnostic>) {
+        if let Some(chain) = self.chain.take() {
+            self.close_chain(chain, false);
+        }
+        self.diagnostics.sort_by_key(|x| x.line);
+        (self.chains, self.diagnostics)
+    }
+}
+
+/// Check every chain of `data`: header fields, coordinates within the sequence size,
+/// block lines, ends agreeing with the sum of sizes and gaps, unique chain ids and the
+/// blank line after each chain.
+/// Returns the number of chains and the problems found, in line order.
+pub fn validate_chain_text(data: &str) -> (usize, Vec<Diagnostic>) {
+    let mut validator = Validator::new();
+    for (i, text) in data.lines().enumerate() {
+        validator.line(i + 1, text);
+    }
+    validator.finish()
+}
+
+/// Same as [`validate_chain_text`], reading `reader` one line at a time.
+pub fn validate_chain_reader<R: BufRead>(reader: R) -> Result<(usize, Vec<Diagnostic>)> {
+    let mut validator = Validator::new();
+    for (i, text) in reader.lines().enumerate() {
+        validator.line(i + 1, &text?);
+    }
+    Ok(validator.finish())
+}
+
+/// Write diagnostics of `input`, returns the number of problems found.
+pub fn validate_chain(
+    input: &Option<String>,
+    output: &Option<String>,
+    rewrite: bool,
+) -> Result<usize> {
+    let (chains, diagnostics) = validate_chain_reader(get_input_reader(input)?)?;
+    let (mut output_file, _) = get_output_writer(output, rewrite)?;
+    for diagnostic in &diagnostics {
+        writeln!(output_file, "{}", diagnostic)?;
+    }
+    info!(
+        "validate {} chains, {} problems found",
+        chains,
+        diagnostics.len()
+    );
+    Ok(diagnostics.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOOD: &str = "chain 100 chrT 100 + 10 40 chrQ 90 - 0 25 1\n10\t5\t0\n15\n\n";
+
+    /// Line and message of every diagnostic of `text`.
+    fn problems(text: &str) -> Vec<(usize, String)> {
+        let (_, diagnostics) = validate_chain_text(text);
+        diagnostics
+            .into_iter()
+            .map(|x| (x.line, x.message))
+            .collect()
+    }
+
+    fn problem(line: usize, message: &str) -> Vec<(usize, String)> {
+        vec![(line, message.to_string())]
+    }
+
+    #[test]
+    fn valid_chain() {
+        assert_eq!(problems(GOOD), []);
+        let (chains, diagnostics) = validate_chain_reader(GOOD.as_bytes()).unwrap();
+        assert_eq!((chains, diagnostics.len()), (1, 0));
+    }
+
+    #[test]
+    fn header_fields() {
+        assert_eq!(
+            problems("chain 100 chrT 100 + 10 40 chrQ 90 - 0 25\n25\n\n"),
+            problem(1, "header has 12 fields, expected 13")
+        );
+        assert_eq!(
+            problems("chain x chrT 100 + 10 40 chrQ 90 - 0 25 1\n10\t5\t0\n15\n\n"),
+            problem(1, "score is not a number: x")
+        );
+        assert_eq!(
+            problems("chain 100 chrT 100 + 10 40 chrQ 90 - 0 25 a\n10\t5\t0\n15\n\n"),
+            problem(1, "chain id is not a non-negative integer: a")
+        );
+        assert_eq!(
+            problems("chain 100 chrT 100 - 10 40 chrQ 90 . 0 25 1\n10\t5\t0\n15\n\n"),
+            [
+                (1, "target strand must be +".to_string()),
+                (1, "query strand must be + or -: .".to_string()),
+            ]
+        );
+        assert_eq!(
+            problems("chain 100 chrT 100 + -1 40 chrQ 90 - 0 25 1\n10\t5\t0\n15\n\n"),
+            problem(1, "target start is not a non-negative integer: -1")
+        );
+    }
+
+    #[test]
+    fn coordinates_out_of_range() {
+        assert_eq!(
+            problems("chain 100 chrT 100 + 10 40 chrQ 20 - 0 25 1\n10\t5\t0\n15\n\n"),
+            [
+                (
+                    1,
+                    "query coordinates out of range: start 0 end 25 size 20".to_string()
+                ),
+                (3, "block runs past the end of the sequence".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn block_lines() {
+        assert_eq!(
+            problems("chain 100 chrT 100 + 10 40 chrQ 90 - 0 25 1\n10\t5\n15\n\n"),
+            problem(2, "block line has 2 fields, expected 1 or 3")
+        );
+        assert_eq!(
+            problems("chain 100 chrT 100 + 10 40 chrQ 90 - 0 25 1\n10\t5\ty\n15\n\n"),
+            problem(2, "block field is not a non-negative integer: y")
+        );
+        assert_eq!(
+            problems("chain 100 chrT 100 + 10 40 chrQ 90 - 0 25 1\n10\t5\t0\n0\t0\t0\n15\n\n"),
+            problem(3, "block size is 0")
+        );
+        assert_eq!(
+            problems("chain 100 chrT 100 + 10 40 chrQ 90 - 0 25 1\n10\n5\t0\n\n"),
+            [
+                (
+                    2,
+                    "block line with only the size field must be the last of a chain".to_string()
+                ),
+                (3, "block line has 2 fields, expected 1 or 3".to_string()),
+                (
+                    3,
+                    "last block line must have only the size field".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            problems("chain 100 chrT 100 + 10 40 chrQ 90 - 0 25 1\n\n"),
+            problem(1, "no block line")
+        );
+        assert_eq!(
+            problems("10\t5\t0\n"),
+            problem(1, "block line before any chain header")
+        );
+        assert_eq!(
+            problems(&format!("{}15\n", GOOD)),
+            problem(5, "block line after the blank line ending a chain")
+        );
+    }
+
+    #[test]
+    fn block_overrun() {
+        assert_eq!(
+            problems("chain 100 chrT 100 + 80 100 chrQ 90 - 0 25 1\n10\t5\t0\n15\n\n"),
+            [
+                (
+                    1,
+                    "target end 100 != start 80 + sizes and gaps 30".to_string()
+                ),
+                (3, "block runs past the end of the sequence".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn sums_disagree_with_ends() {
+        assert_eq!(
+            problems("chain 100 chrT 100 + 10 40 chrQ 90 - 0 30 1\n10\t5\t0\n15\n\n"),
+            problem(1, "query end 30 != start 0 + sizes and gaps 25")
+        );
+    }
+
+    #[test]
+    fn duplicate_chain_id() {
+        let (chains, diagnostics) = validate_chain_text(&format!("{}{}", GOOD, GOOD));
+        assert_eq!(chains, 2);
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>(),
+            ["line 5: chain 1: duplicate chain id, first used on line 1"]
+        );
+    }
+
+    #[test]
+    fn missing_blank_line() {
+        assert_eq!(
+            problems(GOOD.trim_end()),
+            problem(3, "chain is not followed by a blank line")
+        );
+        let text = GOOD.replace("15\n\n", "15\n");
+        assert_eq!(
+            problems(&format!("{}{}", text, GOOD.replace(" 1\n", " 2\n"))),
+            problem(3, "chain is not followed by a blank line")
+        );
+    }
+}
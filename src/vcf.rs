@@ -0,0 +1,352 @@
+use crate::{
+    error::CrussmapError,
+    fasta::{revcomp, FastaReader},
+    interval::{find_in_lapper, get_lapper_hashmap_with_sizes, LapperHashMap, Region},
+    parser::Strand,
+    tabix::{OutputOptions, TabixConf},
+    utils::{get_compressed_output_writer, get_file_reader},
+};
+use log::{info, warn};
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+};
+
+const UNMAP_INFO_HEADER: &str = "##INFO=<ID=UNMAP_REASON,Number=1,Type=String,Description=\"Reason why the record failed to be converted by crussmap\">";
+
+/// Why a VCF record could not be lifted to the new assembly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VcfUnmapReason {
+    /// REF allele does not overlap any chain block
+    Unmap,
+    /// REF allele is split across several chain blocks
+    MultipleHits,
+    /// only part of the REF allele is covered by a chain block
+    PartialMap,
+    /// lifted REF allele differs from the new reference sequence
+    RefMismatch,
+    /// lifted chromosome or position is absent from the new reference
+    NoRefSeq,
+    /// indel on the negative strand can not be re-anchored without a reference
+    NoFastaForIndel,
+}
+
+impl fmt::Display for VcfUnmapReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            VcfUnmapReason::Unmap => "Unmap",
+            VcfUnmapReason::MultipleHits => "MultipleHits",
+            VcfUnmapReason::PartialMap => "PartialMap",
+            VcfUnmapReason::RefMismatch => "RefMismatch",
+            VcfUnmapReason::NoRefSeq => "NoRefSeq",
+            VcfUnmapReason::NoFastaForIndel => "NoFastaForIndel",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VcfRecord {
+    pub chrom: String,
+    /// 1-based position
+    pub pos: usize,
+    pub id: String,
+    pub reference: String,
+    pub alt: Vec<String>,
+    pub qual: String,
+    pub filter: String,
+    pub info: String,
+    /// FORMAT and sample columns
+    pub rest: Vec<String>,
+}
+
+impl VcfRecord {
+    pub fn from_line(line: &str) -> Option<Self> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 8 {
+            return None;
+        }
+        Some(VcfRecord {
+            chrom: fields[0].to_string(),
+            pos: fields[1].parse::<usize>().ok()?,
+            id: fields[2].to_string(),
+            reference: fields[3].to_string(),
+            alt: fields[4].split(',').map(|x| x.to_string()).collect(),
+            qual: fields[5].to_string(),
+            filter: fields[6].to_string(),
+            info: fields[7].to_string(),
+            rest: fields[8..].iter().map(|x| x.to_string()).collect(),
+        })
+    }
+
+    fn with_unmap_reason(&self, reason: VcfUnmapReason) -> Self {
+        let mut record = self.clone();
+        record.info = match record.info.as_str() {
+            "." | "" => format!("UNMAP_REASON={}", reason),
+            info => format!("{};UNMAP_REASON={}", info, reason),
+        };
+        record
+    }
+}
+
+impl fmt::Display for VcfRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.chrom,
+            self.pos,
+            self.id,
+            self.reference,
+            self.alt.join(","),
+            self.qual,
+            self.filter,
+            self.info
+        )?;
+        for x in &self.rest {
+            write!(f, "\t{}", x)?;
+        }
+        Ok(())
+    }
+}
+
+/// Plain nucleotide allele which can be reverse complemented,
+/// symbolic (`<DEL>`), breakend, missing (`.`) and `*` alleles are not.
+fn is_sequence_allele(allele: &str) -> bool {
+    !allele.is_empty()
+        && allele
+            .bytes()
+            .all(|b| b"ACGTNacgtnRYKMSWBDHVrykmswbdhv".contains(&b))
+}
+
+/// Put `anchor`, the base before the lifted position, in front of reverse complemented
+/// alleles. The old padding base, now trailing, is dropped when every allele ends with
+/// it; alleles of mixed records such as a SNP next to an indel keep it.
+fn reanchor(anchor: u8, reference: &str, alt: &[String]) -> (String, Vec<String>) {
+    let last = reference.as_bytes()[reference.len() - 1];
+    let drop_padding = alt.iter().all(|x| x.as_bytes()[x.len() - 1] == last);
+    let anchored = |x: &str| {
+        let end = x.len() - drop_padding as usize;
+        format!("{}{}", anchor as char, &x[..end])
+    };
+    (
+        anchored(reference),
+        alt.iter().map(|x| anchored(x)).collect(),
+    )
+}
+
+/// Lift a single VCF record, returning the converted record or the reason it failed.
+fn lift_vcf_record(
+    record: &VcfRecord,
+    lapper_hashmap: &LapperHashMap,
+    fasta: &mut Option<FastaReader>,
+) -> Result<VcfRecord, VcfUnmapReason> {
+    let ref_len = record.reference.len().max(1);
+    let start = record.pos.saturating_sub(1);
+    let end = start + ref_len;
+    let q_region = Region {
+        chrom: &record.chrom,
+        start,
+        end,
+        strand: Strand::Positive,
+    };
+    let matches = match find_in_lapper(lapper_hashmap, &q_region) {
+        Some(matches) => matches,
+        None => return Err(VcfUnmapReason::Unmap),
+    };
+    if matches.len() > 2 {
+        return Err(VcfUnmapReason::MultipleHits);
+    }
+    if matches[0].start != start || matches[0].end != end {
+        return Err(VcfUnmapReason::PartialMap);
+    }
+    let hit = &matches[1];
+    let mut lifted = record.clone();
+    lifted.chrom = hit.chrom.to_string();
+    lifted.pos = hit.start + 1;
+    if hit.strand == Strand::Negative {
+        let alleles_are_seq = is_sequence_allele(&record.reference)
+            && record.alt.iter().all(|x| is_sequence_allele(x));
+        if is_sequence_allele(&record.reference) {
+            lifted.reference = revcomp(&record.reference);
+        }
+        lifted.alt = record
+            .alt
+            .iter()
+            .map(|x| match is_sequence_allele(x) {
+                true => revcomp(x),
+                false => x.to_string(),
+            })
+            .collect();
+        // VCF indels carry a leading padding base, which ends up trailing after
+        // reverse complement: anchor on the base before the new position instead.
+        let is_indel =
+            alleles_are_seq && record.alt.iter().any(|x| x.len() != record.reference.len());
+        if is_indel {
+            let fasta = match fasta {
+                Some(fasta) => fasta,
+                None => return Err(VcfUnmapReason::NoFastaForIndel),
+            };
+            if hit.start == 0 {
+                return Err(VcfUnmapReason::NoRefSeq);
+            }
+            let anchor = match fasta.fetch(hit.chrom, hit.start - 1, hit.start) {
+                Some(anchor) => anchor[0],
+                None => return Err(VcfUnmapReason::NoRefSeq),
+            };
+            (lifted.reference, lifted.alt) = reanchor(anchor, &lifted.reference, &lifted.alt);
+            lifted.pos = hit.start;
+        }
+    }
+    if let Some(fasta) = fasta {
+        if is_sequence_allele(&lifted.reference) {
+            let ref_start = lifted.pos - 1;
+            let ref_end = ref_start + lifted.reference.len();
+            match fasta.fetch(&lifted.chrom, ref_start, ref_end) {
+                Some(seq) => {
+                    if !seq.eq_ignore_ascii_case(lifted.reference.as_bytes()) {
+                        return Err(VcfUnmapReason::RefMismatch);
+                    }
+                }
+                None => return Err(VcfUnmapReason::NoRefSeq),
+            }
+        }
+    }
+    Ok(lifted)
+}
+
+pub fn cross_vcf(
+    vcf_file: &String,
+    input_chain: &Option<String>,
+    ref_fasta: &Option<String>,
+    output_vcf: &Option<String>,
+    unmaped_vcf: &Option<String>,
+    output_options: &OutputOptions,
+    rewrite: bool,
+) -> Result<(), CrussmapError> {
+    let (lapper_hashmap, query_sizes) = get_lapper_hashmap_with_sizes(input_chain)?;
+    info!("get lapper hashmap done!");
+    let mut fasta = ref_fasta.as_ref().map(FastaReader::from_path).transpose()?;
+    let vcf_file = BufReader::new(get_file_reader(vcf_file)?);
+    let compression = output_options.compression(output_vcf)?;
+    let (mut output_file, stdout_mode) =
+        get_compressed_output_writer(output_vcf, compression, rewrite)?;
+    let mut unmaped_file = match stdout_mode {
+        true => Box::new(io::stdout()),
+        false => get_compressed_output_writer(unmaped_vcf, output_options.compress, rewrite)?.0,
+    };
+
+    for line in vcf_file.lines() {
+        let line = line?;
+        if line.starts_with("##contig=") {
+            if !stdout_mode {
+                unmaped_file.write_all(format!("{}\n", line).as_bytes())?;
+            }
+            continue;
+        }
+        if line.starts_with("#CHROM") {
+            for (name, size) in &query_sizes {
+                output_file
+                    .write_all(format!("##contig=<ID={},length={}>\n", name, size).as_bytes())?;
+            }
+            if stdout_mode {
+                output_file.write_all(format!("{}\n", UNMAP_INFO_HEADER).as_bytes())?;
+            } else {
+                unmaped_file.write_all(format!("{}\n{}\n", UNMAP_INFO_HEADER, line).as_bytes())?;
+            }
+            output_file.write_all(format!("{}\n", line).as_bytes())?;
+            continue;
+        }
+        if line.starts_with('#') {
+            output_file.write_all(format!("{}\n", line).as_bytes())?;
+            if !stdout_mode {
+                unmaped_file.write_all(format!("{}\n", line).as_bytes())?;
+            }
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let vcf_rcd = match VcfRecord::from_line(&line) {
+            Some(vcf_rcd) => vcf_rcd,
+            None => {
+                warn!("SKIP: Error parsing VCF record: {}", line);
+                continue;
+            }
+        };
+        match lift_vcf_record(&vcf_rcd, &lapper_hashmap, &mut fasta) {
+            Ok(lifted) => output_file.write_all(format!("{}\n", lifted).as_bytes())?,
+            Err(reason) => unmaped_file
+                .write_all(format!("{}\n", vcf_rcd.with_unmap_reason(reason)).as_bytes())?,
+        }
+    }
+    // finish the compressed stream before it is read back
+    output_file.flush()?;
+    drop(output_file);
+    output_options.finish(output_vcf, &TabixConf::VCF, rewrite)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interval::lapper_hashmap_from_str;
+
+    fn strings(x: &[&str]) -> Vec<String> {
+        x.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn reanchor_drops_shared_padding_base() {
+        // ATA>A and A>AT, reverse complemented
+        assert_eq!(
+            reanchor(b'G', "TAT", &strings(&["T"])),
+            ("GTA".to_string(), strings(&["G"]))
+        );
+        assert_eq!(
+            reanchor(b'G', "T", &strings(&["AT"])),
+            ("G".to_string(), strings(&["GA"]))
+        );
+    }
+
+    #[test]
+    fn reanchor_keeps_padding_base_of_mixed_records() {
+        // GA>G,CA reverse complemented: the SNP changes the old padding base
+        assert_eq!(
+            reanchor(b'T', "TC", &strings(&["C", "TG"])),
+            ("TTC".to_string(), strings(&["TC", "TTG"]))
+        );
+    }
+
+    #[test]
+    fn indels_are_reanchored_on_negative_strand() {
+        let (lapper_hashmap, _) =
+            lapper_hashmap_from_str("chain 100 chrT 20 + 0 20 chrQ 20 - 0 20 1\n20\n\n").unwrap();
+        // chrT is the reverse complement of chrQ
+        let path = std::env::temp_dir().join(format!("crussmap_vcf_{}.fa", std::process::id()));
+        std::fs::write(&path, ">chrQ\nACGTTTTCGGCATTGACCAG\n").unwrap();
+        let mut fasta = Some(FastaReader::from_path(&path.to_string_lossy().to_string()).unwrap());
+        let lift = |line: &str, fasta: &mut Option<FastaReader>| {
+            let record = VcfRecord::from_line(line).unwrap();
+            lift_vcf_record(&record, &lapper_hashmap, fasta).map(|x| x.to_string())
+        };
+        // chrT 0-based 12..14 is GA, chrQ 6..8 is TC after the T anchor at 5
+        assert_eq!(
+            lift("chrT\t13\tdel\tGA\tG\t.\t.\t.", &mut fasta),
+            Ok("chrQ\t6\tdel\tTT\tT\t.\t.\t.".to_string())
+        );
+        assert_eq!(
+            lift("chrT\t13\tmix\tGA\tG,CA\t.\t.\t.", &mut fasta),
+            Ok("chrQ\t6\tmix\tTTC\tTC,TTG\t.\t.\t.".to_string())
+        );
+        assert_eq!(
+            lift("chrT\t13\tsnp\tG\tC\t.\t.\t.", &mut fasta),
+            Ok("chrQ\t8\tsnp\tC\tG\t.\t.\t.".to_string())
+        );
+        assert_eq!(
+            lift("chrT\t13\tdel\tGA\tG\t.\t.\t.", &mut None),
+            Err(VcfUnmapReason::NoFastaForIndel)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
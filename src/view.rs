@@ -1,11 +1,17 @@
 use crate::{
-    parser::{ChainRecords, Strand},
-    utils::{get_data_from_input, get_output_writer},
+    error::Result,
+    parser::{ChainReader, Strand},
+    utils::{get_input_reader, get_output_writer},
 };
 use std::io::Write;
 
-pub fn view_chain(input: &Option<String>, output: &Option<String>, csv: bool, rewrite: bool) {
-    let data = get_data_from_input(input);
+pub fn view_chain(
+    input: &Option<String>,
+    output: &Option<String>,
+    csv: bool,
+    rewrite: bool,
+) -> Result<()> {
+    let reader = get_input_reader(input)?;
     // let mut output_file: Box<dyn Write> = match output {
     //     Some(output_file) => {
     //         outfile_exist(output_file, rewrite);
@@ -13,17 +19,16 @@ pub fn view_chain(input: &Option<String>, output: &Option<String>, csv: bool, re
     //     }
     //     None => Box::new(io::stdout()),
     // };
-    let (mut output_file, _) = get_output_writer(output, rewrite);
+    let (mut output_file, _) = get_output_writer(output, rewrite)?;
     // info!("start parse");
-    let chain_record_iter = ChainRecords(&data);
+    let chain_record_iter = ChainReader::new(reader);
     // info!("get iteror");
 
     if csv {
         output_file
-            .write_all(b"target_name,target_start,target_end,target_strand,query_name,query_start,query_end,query_strand\n")
-            .unwrap();
+            .write_all(b"target_name,target_start,target_end,target_strand,query_name,query_start,query_end,query_strand,chain_id,score,block_index\n")?;
         for chain_record in chain_record_iter {
-            let chain_record = chain_record.unwrap();
+            let chain_record = chain_record?;
             let target_chrom = chain_record.header.target.name;
             for block in chain_record.block_ivls {
                 let query_strand = match block.val.strand {
@@ -31,7 +36,7 @@ pub fn view_chain(input: &Option<String>, output: &Option<String>, csv: bool, re
                     Strand::Negative => "-",
                 };
                 let line = format!(
-                    "{},{},{},{},{},{},{},{}\n",
+                    "{},{},{},{},{},{},{},{},{},{},{}\n",
                     target_chrom,
                     block.start,
                     block.stop,
@@ -39,17 +44,19 @@ pub fn view_chain(input: &Option<String>, output: &Option<String>, csv: bool, re
                     block.val.name,
                     block.val.start,
                     block.val.end,
-                    query_strand
+                    query_strand,
+                    block.val.chain_id,
+                    block.val.score,
+                    block.val.block_index
                 );
-                output_file.write_all(line.as_bytes()).unwrap();
+                output_file.write_all(line.as_bytes())?;
             }
         }
     } else {
         output_file
-                .write_all(b"target_name\ttarget_start\ttarget_end\ttarget_strand\tquery_name\tquery_start\tquery_end\tquery_strand\n")
-                .unwrap();
+                .write_all(b"target_name\ttarget_start\ttarget_end\ttarget_strand\tquery_name\tquery_start\tquery_end\tquery_strand\tchain_id\tscore\tblock_index\n")?;
         for chain_record in chain_record_iter {
-            let chain_record = chain_record.unwrap();
+            let chain_record = chain_record?;
             let target_chrom = chain_record.header.target.name;
             for block in chain_record.block_ivls {
                 let query_strand = match block.val.strand {
@@ -57,7 +64,7 @@ pub fn view_chain(input: &Option<String>, output: &Option<String>, csv: bool, re
                     Strand::Negative => "-",
                 };
                 let line = format!(
-                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
                     target_chrom,
                     block.start,
                     block.stop,
@@ -65,11 +72,15 @@ pub fn view_chain(input: &Option<String>, output: &Option<String>, csv: bool, re
                     block.val.name,
                     block.val.start,
                     block.val.end,
-                    query_strand
+                    query_strand,
+                    block.val.chain_id,
+                    block.val.score,
+                    block.val.block_index
                 );
-                output_file.write_all(line.as_bytes()).unwrap();
+                output_file.write_all(line.as_bytes())?;
             }
         }
     }
     // info!("write done");
+    Ok(())
 }